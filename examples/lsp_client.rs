@@ -0,0 +1,83 @@
+use rslox::json::Json;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{exit, Command, Stdio};
+use std::thread;
+
+/**
+ * A scripted client for trying out a language server without an editor. Each non-blank line of the script is a JSON-RPC
+ * message, sent in order; every message the server sends back is printed on its own line.
+ *
+ *     cargo build && cargo run --example lsp_client -- target/debug/rslox-lsp test/lsp/session.jsonl
+ *
+ * The client exits with the server's status, so a session that ends with `shutdown` and `exit` exits with 0.
+ */
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("Usage: lsp_client <server> <script>");
+        exit(64);
+    }
+    let script = match fs::read_to_string(&args[1]) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", args[1], e);
+            exit(66);
+        }
+    };
+    let mut messages = vec![];
+    for (n, line) in script.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        match Json::parse(line) {
+            Ok(message) => messages.push(message.to_string()),
+            Err(e) => {
+                eprintln!("{}:{}: {}", args[1], n + 1, e);
+                exit(65);
+            }
+        }
+    }
+
+    let mut server = match Command::new(&args[0]).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Couldn't start {}: {}", args[0], e);
+            exit(66);
+        }
+    };
+    // Read on another thread so a chatty server can't fill its pipe and block while we're still sending
+    let output = server.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = BufReader::new(output);
+        loop {
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                if output.read_line(&mut header).unwrap_or(0) == 0 {
+                    return;
+                }
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(value) = header.trim().strip_prefix("Content-Length:") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+            }
+            let mut body = vec![0; length];
+            if output.read_exact(&mut body).is_err() {
+                return;
+            }
+            println!("{}", String::from_utf8_lossy(&body));
+        }
+    });
+
+    let mut input = server.stdin.take().unwrap();
+    for message in messages {
+        if write!(input, "Content-Length: {}\r\n\r\n{}", message.len(), message).is_err() {
+            break;
+        }
+    }
+    drop(input);
+    let status = server.wait().map(|s| s.code().unwrap_or(1)).unwrap_or(1);
+    reader.join().unwrap();
+    exit(status);
+}
@@ -0,0 +1,8 @@
+use std::process::exit;
+
+/**
+ * Debug adapter for editors, speaking the Debug Adapter Protocol over stdin and stdout.
+ */
+fn main() {
+    exit(rslox::with_large_stack(rslox::run_debug_adapter));
+}
@@ -0,0 +1,8 @@
+use std::process::exit;
+
+/**
+ * Language server for editors, speaking LSP over stdin and stdout.
+ */
+fn main() {
+    exit(rslox::run_language_server());
+}
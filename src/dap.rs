@@ -0,0 +1,417 @@
+use crate::debugger::{describe, evaluate, scopes, Step, StopReason, Stepper};
+use crate::environment::Environment;
+use crate::interpreter::{execute, type_name, Hook, Interpreter};
+use crate::json::Json;
+use crate::lsp::{read_message, write_message};
+use crate::parser::{program, Parser};
+use crate::resolver::resolve;
+use crate::scanner::Scanner;
+use crate::syntax_tree::Stmt;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+// There's only ever one thread of execution
+const THREAD_ID: i64 = 1;
+
+/**
+ * A Debug Adapter Protocol server for rslox, talking over a pair of streams (stdin and stdout in the `rslox-dap`
+ * binary). It debugs a single program given by the `launch` request.
+ *
+ * The program runs on the same thread as the server: requests are only read while the program is stopped, through an
+ * interpreter `Hook` that runs before every statement. So a running program can't be paused, only stopped at
+ * breakpoints or by stepping. Anything the program prints is sent to the client as `output` events.
+ */
+pub struct Session {
+    input: Box<dyn BufRead>,
+    out: Box<dyn Write>,
+    seq: u32,
+    // Path, source and parsed statements of the program to debug, once launched
+    program: Option<(String, String, Vec<Stmt>)>,
+    stepper: Stepper,
+    configured: bool,
+    started: bool,
+    disconnected: bool,
+    // What the program has printed since it was last sent to the client
+    printed: Rc<RefCell<Vec<u8>>>,
+    // Scopes handed out by `scopes` while stopped, referred to by index + 1
+    variables: Vec<Rc<RefCell<Environment>>>,
+}
+
+enum Action {
+    None,
+    Run,
+    Resume(Step),
+    Disconnect,
+}
+
+/**
+ * Serve debug requests until the client disconnects.
+ */
+pub fn serve<R: BufRead + 'static, W: Write + 'static>(input: R, out: W) -> io::Result<i32> {
+    let session = Rc::new(RefCell::new(Session {
+        input: Box::new(input),
+        out: Box::new(out),
+        seq: 0,
+        program: None,
+        stepper: Stepper::new(false),
+        configured: false,
+        started: false,
+        disconnected: false,
+        printed: Rc::new(RefCell::new(vec![])),
+        variables: vec![],
+    }));
+    loop {
+        let request = read_request(&mut session.borrow_mut())?;
+        let request = match request {
+            Some(request) => request,
+            None => return Ok(0),
+        };
+        let action = handle(&mut session.borrow_mut(), &request, None)?;
+        match action {
+            Action::Run => run(&session)?,
+            Action::Disconnect => return Ok(0),
+            _ => (),
+        }
+        if session.borrow().disconnected {
+            return Ok(0);
+        }
+    }
+}
+
+fn read_request(s: &mut Session) -> io::Result<Option<Json>> {
+    loop {
+        match read_message(&mut s.input)? {
+            None => return Ok(None),
+            Some(body) => match Json::parse(&body) {
+                Ok(request) => return Ok(Some(request)),
+                Err(e) => output(s, "stderr", &format!("rslox-dap: invalid message: {}\n", e))?,
+            },
+        }
+    }
+}
+
+fn send(s: &mut Session, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+    s.seq += 1;
+    fields.insert(0, ("seq", Json::Int(s.seq as i64)));
+    write_message(&mut s.out, &Json::object(fields))
+}
+
+fn respond(s: &mut Session, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+    let mut fields = vec![
+        ("type", Json::string("response")),
+        ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+        ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+    ];
+    match result {
+        Ok(body) => {
+            fields.push(("success", Json::Bool(true)));
+            fields.push(("body", body));
+        }
+        Err(message) => {
+            fields.push(("success", Json::Bool(false)));
+            fields.push(("message", Json::String(message)));
+        }
+    }
+    send(s, fields)
+}
+
+fn event(s: &mut Session, event: &str, body: Json) -> io::Result<()> {
+    send(s, vec![("type", Json::string("event")), ("event", Json::string(event)), ("body", body)])
+}
+
+fn output(s: &mut Session, category: &str, text: &str) -> io::Result<()> {
+    event(s, "output", Json::object(vec![("category", Json::string(category)), ("output", Json::string(text))]))
+}
+
+// Send on anything the program has printed
+fn flush_printed(s: &mut Session) -> io::Result<()> {
+    let printed = std::mem::take(&mut *s.printed.borrow_mut());
+    if printed.is_empty() {
+        Ok(())
+    } else {
+        output(s, "stdout", &String::from_utf8_lossy(&printed))
+    }
+}
+
+/**
+ * Handle a request. `stopped` is the interpreter while the program is stopped, and `None` before it starts and after
+ * it finishes.
+ */
+fn handle(s: &mut Session, request: &Json, stopped: Option<&mut Interpreter>) -> io::Result<Action> {
+    let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+    let arguments = request.get("arguments").cloned().unwrap_or(Json::Null);
+    let mut action = Action::None;
+    let result = match command {
+        "initialize" => {
+            respond(s, request, Ok(capabilities()))?;
+            return event(s, "initialized", Json::object(vec![])).map(|_| Action::None);
+        }
+        "launch" => launch(s, &arguments),
+        "setBreakpoints" => Ok(set_breakpoints(s, &arguments)),
+        "setExceptionBreakpoints" => Ok(Json::object(vec![])),
+        "configurationDone" => {
+            s.configured = true;
+            Ok(Json::object(vec![]))
+        }
+        "threads" => {
+            let thread = Json::object(vec![("id", Json::Int(THREAD_ID)), ("name", Json::string("main"))]);
+            Ok(Json::object(vec![("threads", Json::Array(vec![thread]))]))
+        }
+        "stackTrace" => Ok(stack_trace(s, stopped.as_deref())),
+        "scopes" => frame_environment(stopped.as_deref(), &arguments).map(|env| scopes_body(s, env)),
+        "variables" => variables(s, &arguments),
+        "evaluate" => match stopped {
+            Some(i) => frame_environment(Some(i), &arguments).and_then(|env| {
+                let expression = arguments.get("expression").and_then(Json::as_str).unwrap_or("");
+                let value = evaluate(i, expression, env)?;
+                Ok(Json::object(vec![
+                    ("result", Json::String(describe(&value))),
+                    ("type", Json::string(type_name(&value))),
+                    ("variablesReference", Json::Int(0)),
+                ]))
+            }),
+            None => Err("the program isn't stopped".to_string()),
+        },
+        "continue" | "next" | "stepIn" | "stepOut" if stopped.is_some() => {
+            action = Action::Resume(match command {
+                "continue" => Step::Continue,
+                "next" => Step::Over,
+                "stepIn" => Step::In,
+                _ => Step::Out,
+            });
+            Ok(Json::object(vec![("allThreadsContinued", Json::Bool(true))]))
+        }
+        "continue" | "next" | "stepIn" | "stepOut" => Err("the program isn't stopped".to_string()),
+        "pause" => Err("pause isn't supported while the program is running".to_string()),
+        "disconnect" | "terminate" => {
+            action = Action::Disconnect;
+            s.disconnected = true;
+            Ok(Json::object(vec![]))
+        }
+        c => Err(format!("unsupported command `{}`", c)),
+    };
+    respond(s, request, result)?;
+    if s.program.is_some() && s.configured && !s.started {
+        s.started = true;
+        action = Action::Run;
+    }
+    Ok(action)
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        ("supportsConfigurationDoneRequest", Json::Bool(true)),
+        ("supportsEvaluateForHovers", Json::Bool(true)),
+    ])
+}
+
+fn launch(s: &mut Session, arguments: &Json) -> Result<Json, String> {
+    let path = arguments.get("program").and_then(Json::as_str).ok_or("launch needs a `program`")?;
+    let source = fs::read_to_string(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    let statements = program(&mut Parser::new(Scanner::new(source.clone())))?;
+    resolve(&statements).map_err(|errors| errors.join("\n"))?;
+    let stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+    // Breakpoints may already have been set
+    let breakpoints = std::mem::take(&mut s.stepper.breakpoints);
+    s.stepper = Stepper::new(stop_on_entry);
+    s.stepper.breakpoints = breakpoints;
+    s.program = Some((path.to_string(), source, statements));
+    Ok(Json::object(vec![]))
+}
+
+fn set_breakpoints(s: &mut Session, arguments: &Json) -> Json {
+    let requested = arguments.get("breakpoints").and_then(Json::as_array).cloned().unwrap_or_default();
+    let lines: Vec<u32> =
+        requested.iter().filter_map(|b| b.get("line").and_then(Json::as_f64)).map(|l| l as u32).collect();
+    s.stepper.breakpoints = lines.iter().copied().collect();
+    let breakpoints = lines
+        .iter()
+        .map(|line| Json::object(vec![("verified", Json::Bool(true)), ("line", Json::Int(*line as i64))]))
+        .collect();
+    Json::object(vec![("breakpoints", Json::Array(breakpoints))])
+}
+
+// Frame IDs are indexes into the interpreter's frames, so the outermost frame is 0
+fn stack_trace(s: &Session, stopped: Option<&Interpreter>) -> Json {
+    let path = s.program.as_ref().map(|(path, ..)| path.as_str()).unwrap_or("");
+    let frames: Vec<Json> = match stopped {
+        Some(i) => i
+            .frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(id, frame)| {
+                Json::object(vec![
+                    ("id", Json::Int(id as i64)),
+                    ("name", Json::string(&frame.name)),
+                    ("source", Json::object(vec![("path", Json::string(path))])),
+                    ("line", Json::Int(frame.line as i64)),
+                    ("column", Json::Int(1)),
+                ])
+            })
+            .collect(),
+        None => vec![],
+    };
+    let total = frames.len() as i64;
+    Json::object(vec![("stackFrames", Json::Array(frames)), ("totalFrames", Json::Int(total))])
+}
+
+// The innermost scope of the frame named by a `frameId` argument, or of the innermost frame if there isn't one
+fn frame_environment(stopped: Option<&Interpreter>, arguments: &Json) -> Result<Rc<RefCell<Environment>>, String> {
+    let i = stopped.ok_or("the program isn't stopped")?;
+    let id = match arguments.get("frameId").and_then(Json::as_f64) {
+        Some(id) => id as usize,
+        None => i.frames.len() - 1,
+    };
+    i.frames.get(id).map(|frame| Rc::clone(&frame.environment)).ok_or_else(|| format!("no frame {}", id))
+}
+
+fn scopes_body(s: &mut Session, env: Rc<RefCell<Environment>>) -> Json {
+    let mut chain = vec![];
+    let mut next = Some(env);
+    while let Some(env) = next {
+        next = env.borrow().enclosing();
+        chain.push(env);
+    }
+    let count = chain.len();
+    let mut scopes = vec![];
+    for (depth, env) in chain.into_iter().enumerate() {
+        let name = if depth + 1 == count {
+            "Globals"
+        } else if depth == 0 {
+            "Locals"
+        } else {
+            "Enclosing"
+        };
+        s.variables.push(env);
+        scopes.push(Json::object(vec![
+            ("name", Json::string(name)),
+            ("variablesReference", Json::Int(s.variables.len() as i64)),
+            ("expensive", Json::Bool(false)),
+        ]));
+    }
+    Json::object(vec![("scopes", Json::Array(scopes))])
+}
+
+fn variables(s: &Session, arguments: &Json) -> Result<Json, String> {
+    let reference = arguments.get("variablesReference").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+    let env = s.variables.get(reference.wrapping_sub(1)).ok_or_else(|| format!("no variables {}", reference))?;
+    // Only this scope's own variables; enclosing ones have scopes of their own
+    let variables = scopes(env)
+        .swap_remove(0)
+        .into_iter()
+        .map(|(name, value, ty)| {
+            Json::object(vec![
+                ("name", Json::String(name)),
+                ("value", Json::String(value)),
+                ("type", Json::string(ty)),
+                ("variablesReference", Json::Int(0)),
+            ])
+        })
+        .collect();
+    Ok(Json::object(vec![("variables", Json::Array(variables))]))
+}
+
+/**
+ * Run the launched program to completion, stopping wherever the client asks along the way.
+ */
+fn run(session: &Rc<RefCell<Session>>) -> io::Result<()> {
+    let (source, statements) = match &session.borrow().program {
+        Some((_, source, statements)) => (source.clone(), statements.clone()),
+        None => (String::new(), vec![]),
+    };
+    let mut i = Interpreter::new();
+    i.output = Box::new(Printed(Rc::clone(&session.borrow().printed)));
+    i.hook = Some(Box::new(DebugHook(Rc::clone(session))));
+    let mut error = None;
+    for statement in &statements {
+        if let Err(msg) = execute(&mut i, statement) {
+            error = Some(msg);
+            break;
+        }
+    }
+    i.hook = None;
+    let mut s = session.borrow_mut();
+    flush_printed(&mut s)?;
+    let mut status = 0;
+    if let Some(msg) = error {
+        if !s.disconnected {
+            output(&mut s, "stderr", &format!("{}\n", msg.report(Some(&source))))?;
+            status = crate::EX_SOFTWARE;
+        }
+    }
+    event(&mut s, "exited", Json::object(vec![("exitCode", Json::Int(status as i64))]))?;
+    event(&mut s, "terminated", Json::object(vec![]))
+}
+
+/**
+ * Collects what the program prints, to be sent on as `output` events.
+ */
+struct Printed(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Printed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct DebugHook(Rc<RefCell<Session>>);
+
+impl Hook for DebugHook {
+    fn before_statement(&mut self, i: &mut Interpreter, statement: &Stmt) -> Result<(), String> {
+        let mut s = self.0.borrow_mut();
+        stop(&mut s, i, statement).map_err(|e| format!("debug adapter: {}", e))?;
+        if s.disconnected {
+            Err("stopped by the debugger".to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// Stop before `statement` if the stepper says so, and handle requests until the client resumes or disconnects
+fn stop(s: &mut Session, i: &mut Interpreter, statement: &Stmt) -> io::Result<()> {
+    flush_printed(s)?;
+    let line = statement.span().line;
+    let depth = i.frames.len();
+    let reason = match s.stepper.check(line, depth) {
+        Some(reason) => reason,
+        None => return Ok(()),
+    };
+    let reason = match reason {
+        StopReason::Entry => "entry",
+        StopReason::Breakpoint => "breakpoint",
+        StopReason::Step => "step",
+    };
+    let body = Json::object(vec![
+        ("reason", Json::string(reason)),
+        ("threadId", Json::Int(THREAD_ID)),
+        ("allThreadsStopped", Json::Bool(true)),
+    ]);
+    event(s, "stopped", body)?;
+    loop {
+        let request = match read_request(s)? {
+            Some(request) => request,
+            None => {
+                s.disconnected = true;
+                return Ok(());
+            }
+        };
+        match handle(s, &request, Some(i))? {
+            Action::Resume(step) => {
+                s.stepper.resume(step, line, depth);
+                s.variables.clear();
+                return Ok(());
+            }
+            Action::Disconnect => return Ok(()),
+            _ => (),
+        }
+    }
+}
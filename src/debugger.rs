@@ -0,0 +1,395 @@
+use crate::environment::Environment;
+use crate::interpreter::{clone_value, execute, interpret_in, type_name, Hook, Interpreter};
+use crate::parser::{expression, is_at_end, Parser};
+use crate::scanner::Scanner;
+use crate::syntax_tree::{visit_any, Stmt};
+use core::any::Any;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io::{stdin, stdout, BufRead, BufReader, Write};
+use std::rc::Rc;
+
+/**
+ * How execution should carry on after a pause.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    /// Run until a breakpoint
+    Continue,
+    /// Stop at the next line run, including one inside a function being called
+    In,
+    /// Stop at the next line run in the same function or one that called it
+    Over,
+    /// Stop at the next statement in a function that called this one
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Entry,
+    Breakpoint,
+    Step,
+}
+
+/**
+ * Decides, statement by statement, whether a paused-and-resumed program should stop again. Positions are a line and
+ * a call depth (the number of frames on the interpreter's stack).
+ */
+pub struct Stepper {
+    pub breakpoints: HashSet<u32>,
+    step: Step,
+    // Whether the first statement hasn't been reached yet, and the program should stop there
+    stop_on_entry: bool,
+    // Line and call depth the program was last resumed from
+    from: (u32, usize),
+    // Position of the previous statement. Several statements can start on one line, but a breakpoint only stops
+    // execution once each time the line is reached.
+    last: (u32, usize),
+}
+
+impl Stepper {
+    pub fn new(stop_on_entry: bool) -> Self {
+        Stepper { breakpoints: HashSet::new(), step: Step::Continue, stop_on_entry, from: (0, 0), last: (0, 0) }
+    }
+
+    /**
+     * Carry on with `step` from a pause at `line` and call depth `depth`.
+     */
+    pub fn resume(&mut self, step: Step, line: u32, depth: usize) {
+        self.step = step;
+        self.from = (line, depth);
+    }
+
+    /**
+     * Called before each statement; returns why execution should stop there, if it should.
+     */
+    pub fn check(&mut self, line: u32, depth: usize) -> Option<StopReason> {
+        let last = std::mem::replace(&mut self.last, (line, depth));
+        if self.stop_on_entry {
+            self.stop_on_entry = false;
+            return Some(StopReason::Entry);
+        }
+        // Like gdb, stepping moves a line at a time, so other statements on the line stopped at are run through
+        let (from_line, from_depth) = self.from;
+        let stepped = match self.step {
+            Step::Continue => false,
+            Step::In => (line, depth) != self.from,
+            Step::Over => depth < from_depth || (depth == from_depth && line != from_line),
+            Step::Out => depth < from_depth,
+        };
+        if stepped {
+            Some(StopReason::Step)
+        } else if self.breakpoints.contains(&line) && (line, depth) != last {
+            Some(StopReason::Breakpoint)
+        } else {
+            None
+        }
+    }
+}
+
+/**
+ * Describe a runtime value for display: strings are quoted, everything else is printed as `print` would.
+ */
+pub fn describe(value: &Box<dyn Any>) -> String {
+    let ty = type_name(value);
+    if ty == "string" {
+        format!("{:?}", visit_any(clone_value(value)))
+    } else {
+        visit_any(clone_value(value))
+    }
+}
+
+/**
+ * The scopes visible from a frame, innermost first, each with its variables and their values and types.
+ */
+pub fn scopes(env: &Rc<RefCell<Environment>>) -> Vec<Vec<(String, String, &'static str)>> {
+    let mut scopes = vec![];
+    let mut next = Some(Rc::clone(env));
+    while let Some(env) = next {
+        let bindings = env.borrow().bindings();
+        scopes.push(bindings.iter().map(|(name, value)| (name.clone(), describe(value), type_name(value))).collect());
+        next = env.borrow().enclosing();
+    }
+    scopes
+}
+
+/**
+ * Parse the expression in `source` and evaluate it in the scope `env`.
+ */
+pub fn evaluate(i: &mut Interpreter, source: &str, env: Rc<RefCell<Environment>>) -> Result<Box<dyn Any>, String> {
+    let mut p = Parser::new(Scanner::new(source.to_string()));
+    let e = expression(&mut p);
+    if let Some(e) = p.scan_error() {
+        return Err(e.to_string());
+    }
+    let e = e?;
+    if !is_at_end(&mut p) {
+        return Err(format!("unexpected input after the expression in `{}`", source));
+    }
+    interpret_in(i, &e, env).map_err(|e| e.message)
+}
+
+const HELP: &str = "Commands:
+  break <line>     Stop before running line <line> (b)
+  delete [<line>]  Remove the breakpoint on <line>, or all of them (d)
+  watch <expr>     Stop whenever the value of <expr> changes
+  run              Start the program (r)
+  step             Run to the next statement, going into function calls (s)
+  next             Run to the next statement in this function, stepping over calls (n)
+  finish           Run until this function returns
+  continue         Run until a breakpoint or watch stops the program (c)
+  print <expr>     Evaluate <expr> where the program is stopped (p)
+  bt               Show the call stack (backtrace)
+  quit             Leave the debugger (q)
+An empty line repeats the previous command.";
+
+/**
+ * The interactive console behind `rslox debug`, with gdb-like commands. The console is shared between the prompt
+ * shown before and after the program runs and the interpreter hook that stops it, which shows the same prompt.
+ */
+pub struct Console {
+    input: Box<dyn BufRead>,
+    source: String,
+    lines: Vec<String>,
+    stepper: Stepper,
+    // Breakpoint lines, numbered from 1 in the order they were set
+    breakpoints: Vec<u32>,
+    watches: Vec<Watch>,
+    previous_command: String,
+    color: bool,
+    quit: bool,
+}
+
+struct Watch {
+    expression: String,
+    // The value when last checked, or `None` if it couldn't be evaluated (e.g. a variable that isn't in scope)
+    value: Option<String>,
+    previous: Option<String>,
+}
+
+enum Command {
+    None,
+    Run,
+    Resume(Step),
+    Quit,
+}
+
+/**
+ * Debug a program interactively, reading commands from stdin until the user quits.
+ */
+pub fn debug(statements: &[Stmt], source: &str, color: bool) {
+    let source = source.to_string();
+    let console = Rc::new(RefCell::new(Console {
+        input: Box::new(BufReader::new(stdin())),
+        lines: source.lines().map(|l| l.to_string()).collect(),
+        source,
+        stepper: Stepper::new(false),
+        breakpoints: vec![],
+        watches: vec![],
+        previous_command: String::new(),
+        color,
+        quit: false,
+    }));
+    println!("Type `help` for a list of commands.");
+    loop {
+        let command = prompt(&mut console.borrow_mut(), None);
+        match command {
+            Command::Run => {
+                run(&console, statements);
+                if console.borrow().quit {
+                    return;
+                }
+            }
+            Command::Quit => return,
+            _ => (),
+        }
+    }
+}
+
+fn run(console: &Rc<RefCell<Console>>, statements: &[Stmt]) {
+    {
+        let mut c = console.borrow_mut();
+        c.stepper = Stepper::new(false);
+        c.stepper.breakpoints = c.breakpoints.iter().copied().collect();
+        for watch in c.watches.iter_mut() {
+            watch.value = None;
+        }
+    }
+    let mut i = Interpreter::new();
+    i.hook = Some(Box::new(ConsoleHook(Rc::clone(console))));
+    for statement in statements {
+        if let Err(msg) = execute(&mut i, statement) {
+            let c = console.borrow();
+            if !c.quit {
+                error(&c, &msg.report(Some(&c.source)));
+                println!("Program stopped by a runtime error.");
+            }
+            return;
+        }
+    }
+    println!("Program exited normally.");
+}
+
+struct ConsoleHook(Rc<RefCell<Console>>);
+
+impl Hook for ConsoleHook {
+    fn before_statement(&mut self, i: &mut Interpreter, statement: &Stmt) -> Result<(), String> {
+        let mut c = self.0.borrow_mut();
+        let line = statement.span().line;
+        let depth = i.frames.len();
+        let reason = c.stepper.check(line, depth);
+        let changed = check_watches(&mut c, i);
+        match (changed, reason) {
+            (Some(n), _) => {
+                let watch = &c.watches[n];
+                println!("\nWatchpoint {}: {}", n + 1, watch.expression);
+                println!("Old value = {}", watch.previous.as_deref().unwrap_or("<not in scope>"));
+                println!("New value = {}", watch.value.as_deref().unwrap_or("<not in scope>"));
+            }
+            (None, Some(StopReason::Breakpoint)) => {
+                let n = c.breakpoints.iter().position(|l| *l == line).unwrap_or(0);
+                println!("\nBreakpoint {}, {} at line {}", n + 1, i.frames[depth - 1].name, line);
+            }
+            (None, Some(_)) => (),
+            (None, None) => return Ok(()),
+        }
+        show_line(&c, line);
+        match prompt(&mut c, Some(i)) {
+            Command::Resume(step) => {
+                c.stepper.resume(step, line, depth);
+                Ok(())
+            }
+            _ => {
+                c.quit = true;
+                Err("quit".to_string())
+            }
+        }
+    }
+}
+
+// Re-evaluate every watch expression, returning the first whose value has changed
+fn check_watches(c: &mut Console, i: &mut Interpreter) -> Option<usize> {
+    let env = Rc::clone(&i.frames.last()?.environment);
+    let mut changed = None;
+    for (n, watch) in c.watches.iter_mut().enumerate() {
+        let value = evaluate(i, &watch.expression, Rc::clone(&env)).ok().map(|v| describe(&v));
+        if value != watch.value {
+            watch.previous = std::mem::replace(&mut watch.value, value);
+            changed = changed.or(Some(n));
+        }
+    }
+    changed
+}
+
+fn show_line(c: &Console, line: u32) {
+    if let Some(text) = c.lines.get(line as usize - 1) {
+        println!("{}\t{}", line, text);
+    }
+}
+
+/**
+ * Read and run commands until one of them starts or resumes the program, or quits. `stopped` is the interpreter if
+ * the program is stopped.
+ */
+fn prompt(c: &mut Console, mut stopped: Option<&mut Interpreter>) -> Command {
+    loop {
+        print!("(rslox) ");
+        stdout().flush().ok();
+        let mut line = String::new();
+        match c.input.read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                println!();
+                return Command::Quit;
+            }
+            Ok(_) => (),
+        }
+        let mut line = line.trim().to_string();
+        if line.is_empty() {
+            line = c.previous_command.clone();
+        } else {
+            c.previous_command = line.clone();
+        }
+        match command(c, &line, stopped.as_deref_mut()) {
+            Command::None => (),
+            command => return command,
+        }
+    }
+}
+
+fn command(c: &mut Console, line: &str, stopped: Option<&mut Interpreter>) -> Command {
+    let (name, argument) = match line.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (line, ""),
+    };
+    match (name, stopped) {
+        ("", _) => (),
+        ("help" | "h", _) => println!("{}", HELP),
+        ("quit" | "q", _) => return Command::Quit,
+        ("break" | "b", _) => match argument.parse::<u32>() {
+            Ok(line) if line >= 1 && line as usize <= c.lines.len() => {
+                if !c.breakpoints.contains(&line) {
+                    c.breakpoints.push(line);
+                    c.stepper.breakpoints.insert(line);
+                }
+                let n = c.breakpoints.iter().position(|l| *l == line).unwrap() + 1;
+                println!("Breakpoint {} at line {}", n, line);
+            }
+            _ => error(c, &format!("`{}` isn't a line of the program", argument)),
+        },
+        ("delete" | "d", _) => {
+            if argument.is_empty() {
+                c.breakpoints.clear();
+                c.stepper.breakpoints.clear();
+            } else if let Ok(line) = argument.parse::<u32>() {
+                c.breakpoints.retain(|l| *l != line);
+                c.stepper.breakpoints.remove(&line);
+            } else {
+                error(c, &format!("`{}` isn't a line of the program", argument));
+            }
+        }
+        ("watch", stopped) => {
+            if argument.is_empty() {
+                error(c, "watch needs an expression");
+            } else {
+                // Watching starts from the current value, so only later changes stop the program
+                let value = stopped.and_then(|i| {
+                    let env = Rc::clone(&i.frames.last()?.environment);
+                    evaluate(i, argument, env).ok().map(|v| describe(&v))
+                });
+                c.watches.push(Watch { expression: argument.to_string(), value, previous: None });
+                println!("Watchpoint {}: {}", c.watches.len(), argument);
+            }
+        }
+        ("run" | "r", None) => return Command::Run,
+        ("run" | "r", Some(_)) => error(c, "the program is already running"),
+        ("step" | "s", Some(_)) => return Command::Resume(Step::In),
+        ("next" | "n", Some(_)) => return Command::Resume(Step::Over),
+        ("finish", Some(_)) => return Command::Resume(Step::Out),
+        ("continue" | "c", Some(_)) => return Command::Resume(Step::Continue),
+        ("print" | "p", Some(i)) => {
+            let env = Rc::clone(&i.frames.last().unwrap().environment);
+            match evaluate(i, argument, env) {
+                Ok(value) => println!("{}", describe(&value)),
+                Err(msg) => error(c, &msg),
+            }
+        }
+        ("bt" | "backtrace", Some(i)) => {
+            for (n, frame) in i.frames.iter().rev().enumerate() {
+                println!("#{}  {} at line {}", n, frame.name, frame.line);
+            }
+        }
+        ("step" | "s" | "next" | "n" | "finish" | "continue" | "c" | "print" | "p" | "bt" | "backtrace", None) => {
+            error(c, "the program isn't running")
+        }
+        (name, _) => error(c, &format!("unknown command `{}`; try `help`", name)),
+    }
+    Command::None
+}
+
+fn error(c: &Console, message: &str) {
+    if c.color {
+        eprintln!("\x1b[31m{}\x1b[0m", message);
+    } else {
+        eprintln!("{}", message);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use crate::syntax_tree::Stmt;
+
+/**
+ * The formats reference pages can be written in.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocFormat {
+    Html,
+    Markdown,
+}
+
+/**
+ * A top-level declaration worth documenting.
+ */
+pub struct Item {
+    pub name: String,
+    // Parameter names for a function, or `None` for a variable
+    pub params: Option<Vec<String>>,
+    pub doc: Option<String>,
+    pub line: u32,
+}
+
+/**
+ * The documentation for one script.
+ */
+pub struct Page {
+    /// Path of the script relative to what's being documented, with `/` separators
+    pub path: String,
+    pub source: String,
+    pub items: Vec<Item>,
+}
+
+/**
+ * Collect the top-level functions and variables of a program, in the order they're declared.
+ */
+pub fn items(statements: &[Stmt]) -> Vec<Item> {
+    statements
+        .iter()
+        .filter_map(|s| match s {
+            Stmt::Function(decl) => Some(Item {
+                name: decl.name.lexeme.clone(),
+                params: Some(decl.params.iter().map(|p| p.lexeme.clone()).collect()),
+                doc: decl.doc.clone(),
+                line: decl.span.line,
+            }),
+            Stmt::Var(name, _, doc, span) => {
+                Some(Item { name: name.lexeme.clone(), params: None, doc: doc.clone(), line: span.line })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/**
+ * Render a page for each script and an index of them all, returning the path and contents of each file to write,
+ * relative to the directory they go in. Doc comments are Markdown, and `[name]` in one links to the item called `name`,
+ * looking in the same script first.
+ *
+ * HTML pages link to a copy of each script with numbered lines that's written under `source`. Markdown pages link to
+ * the scripts themselves, so `source_dir` is the path to what's being documented from the directory the index goes in.
+ */
+pub fn render(pages: &[Page], format: DocFormat, source_dir: &str) -> Vec<(String, String)> {
+    let ext = if format == DocFormat::Html { "html" } else { "md" };
+    let mut targets: HashMap<&str, String> = HashMap::new();
+    for page in pages.iter().rev() {
+        for item in &page.items {
+            targets.insert(&item.name, format!("{}#{}", page_file(&page.path, ext), anchor(item)));
+        }
+    }
+    let mut files = vec![];
+    for page in pages {
+        let resolve = |name: &str| match page.items.iter().find(|item| item.name == name) {
+            Some(item) => Some(format!("#{}", anchor(item))),
+            None => targets.get(name).map(|target| format!("{}{}", root(&page.path), target)),
+        };
+        let contents = match format {
+            DocFormat::Html => html_page(page, &resolve),
+            DocFormat::Markdown => markdown_page(page, &resolve, source_dir),
+        };
+        files.push((page_file(&page.path, ext), contents));
+        if format == DocFormat::Html {
+            files.push((source_file(&page.path), html_source(page)));
+        }
+    }
+    let resolve = |name: &str| targets.get(name).cloned();
+    let index = match format {
+        DocFormat::Html => html_index(pages, &resolve),
+        DocFormat::Markdown => markdown_index(pages, &resolve),
+    };
+    files.push((format!("index.{}", ext), index));
+    files
+}
+
+// Pages mirror the scripts' directories, so `lib/math.rslox` is documented in `pages/lib/math.html`. Keeping them out
+// of the top directory leaves `index` free for the index whatever the scripts are called.
+fn page_file(path: &str, ext: &str) -> String {
+    format!("pages/{}.{}", path.trim_end_matches(".rslox"), ext)
+}
+
+fn source_file(path: &str) -> String {
+    format!("source/{}.html", path.trim_end_matches(".rslox"))
+}
+
+// The way back to the top directory from the page or copy of the script at `path`
+fn root(path: &str) -> String {
+    "../".repeat(path.matches('/').count() + 1)
+}
+
+fn anchor(item: &Item) -> String {
+    let kind = if item.params.is_some() { "fn" } else { "var" };
+    format!("{}.{}", kind, item.name)
+}
+
+fn signature(item: &Item) -> String {
+    match &item.params {
+        Some(params) => format!("func {}({})", item.name, params.join(", ")),
+        None => format!("var {}", item.name),
+    }
+}
+
+// The first paragraph of an item's documentation, on one line
+fn summary(item: &Item) -> String {
+    let doc = item.doc.as_deref().unwrap_or("");
+    doc.lines().map(str::trim).take_while(|line| !line.is_empty()).collect::<Vec<&str>>().join(" ")
+}
+
+/*
+ * Markdown
+ */
+
+fn markdown_page(page: &Page, resolve: &dyn Fn(&str) -> Option<String>, source_dir: &str) -> String {
+    let root = root(&page.path);
+    let mut out = format!("# {}\n\n[Index]({}index.md)\n", page.path, root);
+    for item in &page.items {
+        out += &format!("\n<a id=\"{}\"></a>\n## `{}`\n\n", anchor(item), signature(item));
+        let script = format!("{}{}/{}", root, source_dir, page.path);
+        out += &format!("Defined at [{}:{}]({}#L{})\n", page.path, item.line, script, item.line);
+        if let Some(doc) = &item.doc {
+            out += &format!("\n{}\n", markdown_links(doc, resolve));
+        }
+    }
+    out
+}
+
+fn markdown_index(pages: &[Page], resolve: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut out = "# Reference\n".to_string();
+    for page in pages {
+        out += &format!("\n## [{}]({})\n\n", page.path, page_file(&page.path, "md"));
+        for item in &page.items {
+            let link = format!("- [`{}`]({}#{})", item.name, page_file(&page.path, "md"), anchor(item));
+            match summary(item).as_str() {
+                "" => out += &format!("{}\n", link),
+                s => out += &format!("{}: {}\n", link, markdown_links(s, resolve)),
+            }
+        }
+    }
+    out
+}
+
+// Doc comments are Markdown already, so only the `[name]` links need filling in
+fn markdown_links(doc: &str, resolve: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut out = String::new();
+    let mut fenced = false;
+    for line in doc.lines() {
+        if line.trim_start().starts_with("```") {
+            fenced = !fenced;
+        }
+        if fenced || line.starts_with("    ") {
+            out += line;
+        } else {
+            out += &inline(line, resolve, DocFormat::Markdown);
+        }
+        out.push('\n');
+    }
+    out.pop();
+    out
+}
+
+/*
+ * HTML
+ */
+
+const STYLE: &str = "body { font-family: sans-serif; max-width: 50em; margin: auto; padding: 1em; }
+section { border-top: 1px solid #ccc; }
+.source { font-size: small; }
+pre { background: #f4f4f4; padding: 0.5em; overflow-x: auto; }
+pre a { color: #999; text-decoration: none; }";
+
+fn html_document(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n\
+         <body>\n{}</body>\n</html>\n",
+        escape(title),
+        STYLE,
+        body
+    )
+}
+
+fn html_page(page: &Page, resolve: &dyn Fn(&str) -> Option<String>) -> String {
+    let root = root(&page.path);
+    let mut body = format!("<nav><a href=\"{}index.html\">Index</a></nav>\n<h1>{}</h1>\n", root, escape(&page.path));
+    for item in &page.items {
+        body += &format!("<section id=\"{}\">\n<h2><code>{}</code></h2>\n", anchor(item), escape(&signature(item)));
+        body += &format!(
+            "<p class=\"source\">Defined at <a href=\"{}{}#L{}\">{}:{}</a></p>\n",
+            root,
+            source_file(&page.path),
+            item.line,
+            escape(&page.path),
+            item.line
+        );
+        if let Some(doc) = &item.doc {
+            body += &html_doc(doc, resolve);
+        }
+        body += "</section>\n";
+    }
+    html_document(&page.path, &body)
+}
+
+fn html_index(pages: &[Page], resolve: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut body = "<h1>Reference</h1>\n".to_string();
+    for page in pages {
+        let file = page_file(&page.path, "html");
+        body += &format!("<h2><a href=\"{}\">{}</a></h2>\n<ul>\n", file, escape(&page.path));
+        for item in &page.items {
+            let link = format!("<a href=\"{}#{}\"><code>{}</code></a>", file, anchor(item), escape(&item.name));
+            match summary(item).as_str() {
+                "" => body += &format!("<li>{}</li>\n", link),
+                s => body += &format!("<li>{}: {}</li>\n", link, inline(s, resolve, DocFormat::Html)),
+            }
+        }
+        body += "</ul>\n";
+    }
+    html_document("Reference", &body)
+}
+
+// A script with every line numbered, so pages can link to where things are declared
+fn html_source(page: &Page) -> String {
+    let page_file = page_file(&page.path, "html");
+    let page_link = format!("<a href=\"{}{}\">{}</a>", root(&page.path), page_file, escape(&page.path));
+    let mut body = format!("<nav>{}</nav>\n<pre>", page_link);
+    for (i, line) in page.source.lines().enumerate() {
+        body += &format!("<span id=\"L{0}\"><a href=\"#L{0}\">{0:>4}</a>  {1}</span>\n", i + 1, escape(line));
+    }
+    body += "</pre>\n";
+    html_document(&page.path, &body)
+}
+
+/**
+ * Turn the Markdown of a doc comment into HTML. Only the parts likely to turn up in a doc comment are understood:
+ * paragraphs, code blocks (indented or fenced with ```), inline code and links.
+ */
+fn html_doc(doc: &str, resolve: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut out = String::new();
+    let mut paragraph: Vec<&str> = vec![];
+    let mut code: Vec<&str> = vec![];
+    let mut fenced = false;
+    let mut lines = doc.lines().peekable();
+    while let Some(line) = lines.next() {
+        if line.trim_start().starts_with("```") {
+            if fenced {
+                out += &format!("<pre><code>{}</code></pre>\n", escape(&code.join("\n")));
+                code.clear();
+            }
+            fenced = !fenced;
+            continue;
+        }
+        if fenced {
+            code.push(line);
+        } else if (line.starts_with("    ") || !code.is_empty()) && paragraph.is_empty() {
+            code.push(line.get(4..).unwrap_or(""));
+            // Blank lines inside an indented code block belong to it
+            let continues = lines.peek().is_some_and(|next| next.starts_with("    ") || next.trim().is_empty());
+            if !continues {
+                while code.last().is_some_and(|l| l.trim().is_empty()) {
+                    code.pop();
+                }
+                out += &format!("<pre><code>{}</code></pre>\n", escape(&code.join("\n")));
+                code.clear();
+            }
+        } else if line.trim().is_empty() {
+            if !paragraph.is_empty() {
+                out += &format!("<p>{}</p>\n", inline(&paragraph.join("\n"), resolve, DocFormat::Html));
+                paragraph.clear();
+            }
+        } else {
+            paragraph.push(line);
+        }
+    }
+    if !code.is_empty() {
+        out += &format!("<pre><code>{}</code></pre>\n", escape(&code.join("\n")));
+    }
+    if !paragraph.is_empty() {
+        out += &format!("<p>{}</p>\n", inline(&paragraph.join("\n"), resolve, DocFormat::Html));
+    }
+    out
+}
+
+/**
+ * Render the inline parts of some Markdown text: `code`, [text](url) links and `[name]` links to other items, which
+ * are left alone if there's nothing called `name`. Markdown only needs the `[name]` links filled in.
+ */
+fn inline(text: &str, resolve: &dyn Fn(&str) -> Option<String>, format: DocFormat) -> String {
+    let html = format == DocFormat::Html;
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '`' {
+            if let Some(end) = rest[1..].find('`') {
+                let code = &rest[1..end + 1];
+                out += &if html { format!("<code>{}</code>", escape(code)) } else { format!("`{}`", code) };
+                rest = &rest[end + 2..];
+                continue;
+            }
+        } else if c == '[' {
+            if let Some(end) = rest.find(']') {
+                let label = &rest[1..end];
+                let after = &rest[end + 1..];
+                // An ordinary Markdown link
+                if let (true, Some(close)) = (after.starts_with('('), after.find(')')) {
+                    let url = &after[1..close];
+                    if html {
+                        out += &format!("<a href=\"{}\">{}</a>", escape(url), inline(label, resolve, format));
+                    } else {
+                        out += &rest[..end + 1 + close + 1];
+                    }
+                    rest = &after[close + 1..];
+                    continue;
+                }
+                if let Some(href) = resolve(label.trim_matches('`')) {
+                    if html {
+                        out += &format!("<a href=\"{}\">{}</a>", href, inline(label, resolve, format));
+                    } else {
+                        out += &format!("[{}]({})", label, href);
+                    }
+                    rest = after;
+                    continue;
+                }
+            }
+        }
+        if html {
+            out += &escape(&c.to_string());
+        } else {
+            out.push(c);
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
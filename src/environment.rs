@@ -0,0 +1,75 @@
+use crate::interpreter::clone_value;
+use crate::scanner::Token;
+use core::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/**
+ * A single scope of variable bindings. Scopes are chained together through `enclosing`, with the global scope at the
+ * root of the chain.
+ */
+#[derive(Default)]
+pub struct Environment {
+    values: HashMap<String, Box<dyn Any>>,
+    enclosing: Option<Rc<RefCell<Environment>>>,
+}
+impl Environment {
+    pub fn new() -> Self {
+        Environment { values: HashMap::new(), enclosing: None }
+    }
+
+    pub fn new_enclosed(enclosing: Rc<RefCell<Environment>>) -> Self {
+        Environment { values: HashMap::new(), enclosing: Some(enclosing) }
+    }
+
+    /**
+     * Bind `name` in this scope. Redefining an existing name is allowed and simply replaces the old value.
+     */
+    pub fn define(&mut self, name: String, value: Box<dyn Any>) {
+        self.values.insert(name, value);
+    }
+
+    /**
+     * Look up `name`, walking outwards through enclosing scopes. Returns a copy of the bound value.
+     */
+    pub fn get(&self, name: &Token) -> Result<Box<dyn Any>, String> {
+        match self.values.get(&name.lexeme) {
+            Some(v) => Ok(clone_value(v)),
+            None => match &self.enclosing {
+                Some(enclosing) => enclosing.borrow().get(name),
+                None => Err(format!("Undefined variable '{}'.", name.lexeme)),
+            },
+        }
+    }
+
+    /**
+     * Reassign an existing binding, walking outwards through enclosing scopes. Assigning to an undeclared name is an
+     * error.
+     */
+    pub fn assign(&mut self, name: &Token, value: Box<dyn Any>) -> Result<(), String> {
+        if self.values.contains_key(&name.lexeme) {
+            self.values.insert(name.lexeme.clone(), value);
+            Ok(())
+        } else {
+            match &self.enclosing {
+                Some(enclosing) => enclosing.borrow_mut().assign(name, value),
+                None => Err(format!("Undefined variable '{}'.", name.lexeme)),
+            }
+        }
+    }
+
+    /**
+     * Copies of the bindings in this scope alone (not enclosing ones), sorted by name.
+     */
+    pub fn bindings(&self) -> Vec<(String, Box<dyn Any>)> {
+        let mut bindings: Vec<(String, Box<dyn Any>)> =
+            self.values.iter().map(|(name, value)| (name.clone(), clone_value(value))).collect();
+        bindings.sort_by(|(a, _), (b, _)| a.cmp(b));
+        bindings
+    }
+
+    pub fn enclosing(&self) -> Option<Rc<RefCell<Environment>>> {
+        self.enclosing.clone()
+    }
+}
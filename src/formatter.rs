@@ -0,0 +1,343 @@
+use crate::scanner::Comment;
+use crate::syntax_tree::{Expr, Stmt};
+use std::collections::VecDeque;
+
+const INDENT: &str = "    ";
+
+/**
+ * Pretty-prints a program in the canonical rslox style: four-space indents, one statement per line, single spaces
+ * around binary operators and after commas, and opening braces on the same line as their statement.
+ *
+ * Comments aren't part of the syntax tree, so they're interleaved back in by source position: each comment is written
+ * just before the first statement that starts after it. A comment that shared a line with code stays at the end of
+ * that line. Single blank lines between statements are kept; runs of blank lines are collapsed into one.
+ */
+pub struct Formatter<'a> {
+    source: &'a str,
+    comments: VecDeque<Comment>,
+    lines: Vec<String>,
+    depth: usize,
+    // Offsets at which each source line starts, for turning offsets into line numbers
+    line_starts: Vec<usize>,
+    // The source line that the last thing written ended on
+    last_line: usize,
+    // Whether nothing has been written yet in the current block, in which case blank lines aren't kept
+    at_block_start: bool,
+}
+
+/**
+ * Format a parsed program. `comments` must be in source order.
+ */
+pub fn format(source: &str, comments: Vec<Comment>, statements: &[Stmt]) -> String {
+    let mut line_starts = vec![0];
+    line_starts.extend(source.char_indices().filter(|(_, c)| *c == '\n').map(|(i, _)| i + 1));
+    let mut f = Formatter {
+        source,
+        comments: VecDeque::from(comments),
+        lines: vec![],
+        depth: 0,
+        line_starts,
+        last_line: 0,
+        at_block_start: true,
+    };
+    for statement in statements {
+        stmt(&mut f, statement);
+    }
+    flush_comments(&mut f, usize::MAX);
+    if f.lines.is_empty() {
+        String::new()
+    } else {
+        f.lines.join("\n") + "\n"
+    }
+}
+
+fn stmt(f: &mut Formatter, s: &Stmt) {
+    let span = s.span();
+    flush_comments(f, span.start);
+    separate(f, span.start);
+    match s {
+        Stmt::Expression(e, _) => {
+            let text = format!("{};", expr(f, e));
+            line(f, text)
+        }
+        Stmt::Print(e, _) => {
+            let text = format!("print {};", expr(f, e));
+            line(f, text)
+        }
+        Stmt::Var(..) => {
+            let text = var_text(f, s);
+            line(f, text)
+        }
+        Stmt::Return(None, _) => line(f, "return;".to_string()),
+        Stmt::Return(Some(e), _) => {
+            let text = format!("return {};", expr(f, e));
+            line(f, text)
+        }
+        Stmt::Throw(e, _) => {
+            let text = format!("throw {};", expr(f, e));
+            line(f, text)
+        }
+        Stmt::Try(body, catch, finally, _) => {
+            line(f, "try".to_string());
+            joined(f, body);
+            if let Some((name, handler)) = catch {
+                append(f, &format!(" catch ({})", name.lexeme));
+                joined(f, handler);
+            }
+            if let Some(finally) = finally {
+                append(f, " finally");
+                joined(f, finally);
+            }
+        }
+        Stmt::Block(statements, span) => {
+            line(f, "{".to_string());
+            block_body(f, statements, span.end - 1);
+        }
+        Stmt::If(condition, then_branch, else_branch, _) => {
+            let head = format!("if ({})", expr(f, condition));
+            line(f, head);
+            branch(f, then_branch);
+            if let Some(else_branch) = else_branch {
+                if let Stmt::Block(..) = **then_branch {
+                    append(f, " else");
+                } else {
+                    line(f, "else".to_string());
+                }
+                // `else if` chains stay flat rather than nesting deeper and deeper
+                match **else_branch {
+                    Stmt::If(..) => joined(f, else_branch),
+                    _ => branch(f, else_branch),
+                }
+            }
+        }
+        Stmt::While(condition, body, _) => {
+            let head = format!("while ({})", expr(f, condition));
+            line(f, head);
+            branch(f, body);
+        }
+        Stmt::For(initializer, condition, increment, body, _) => {
+            let mut head = String::from("for (");
+            match initializer {
+                Some(initializer) => match &**initializer {
+                    Stmt::Expression(e, _) => head.push_str(&format!("{};", expr(f, e))),
+                    s => head.push_str(&var_text(f, s)),
+                },
+                None => head.push(';'),
+            }
+            if let Some(condition) = condition {
+                head.push_str(&format!(" {}", expr(f, condition)));
+            }
+            head.push(';');
+            if let Some(increment) = increment {
+                head.push_str(&format!(" {}", expr(f, increment)));
+            }
+            head.push(')');
+            line(f, head);
+            branch(f, body);
+        }
+        Stmt::Function(decl) => {
+            let params: Vec<&str> = decl.params.iter().map(|p| p.lexeme.as_str()).collect();
+            line(f, format!("func {}({}) {{", decl.name.lexeme, params.join(", ")));
+            block_body(f, &decl.body, decl.span.end - 1);
+        }
+    }
+    f.last_line = line_of(f, span.end.saturating_sub(1));
+    f.at_block_start = false;
+}
+
+fn var_text(f: &mut Formatter, s: &Stmt) -> String {
+    match s {
+        Stmt::Var(name, Some(e), ..) => format!("var {} = {};", name.lexeme, expr(f, e)),
+        Stmt::Var(name, None, ..) => format!("var {};", name.lexeme),
+        _ => unreachable!(),
+    }
+}
+
+/**
+ * Write the body of a loop or branch. Blocks open on the line of the statement that owns them; anything else goes on
+ * its own line, one level deeper.
+ */
+fn branch(f: &mut Formatter, body: &Stmt) {
+    if let Stmt::Block(..) = body {
+        joined(f, body);
+    } else {
+        f.depth += 1;
+        f.at_block_start = true;
+        stmt(f, body);
+        f.depth -= 1;
+    }
+}
+
+/**
+ * Write a statement so that it starts at the end of the last line written, rather than on a line of its own.
+ */
+fn joined(f: &mut Formatter, s: &Stmt) {
+    let before = (f.lines.len(), f.lines.last().map(|l| l.len()));
+    flush_comments(f, s.span().start);
+    f.at_block_start = true;
+    let first = f.lines.len();
+    stmt(f, s);
+    // A comment in between ends the line it's on, so the statement has to start a new one
+    if before == (first, f.lines.get(first.wrapping_sub(1)).map(|l| l.len())) && first > 0 {
+        let text = f.lines.remove(first);
+        f.lines[first - 1].push(' ');
+        f.lines[first - 1].push_str(text.trim_start());
+    }
+}
+
+/**
+ * Write the statements inside a block followed by the closing brace, which is at `close` in the source.
+ */
+fn block_body(f: &mut Formatter, statements: &[Stmt], close: usize) {
+    let open = f.lines.len();
+    f.depth += 1;
+    f.at_block_start = true;
+    for statement in statements {
+        stmt(f, statement);
+    }
+    flush_comments(f, close);
+    f.depth -= 1;
+    if f.lines.len() == open {
+        append(f, "}");
+    } else {
+        line(f, "}".to_string());
+    }
+}
+
+fn expr(f: &mut Formatter, e: &Expr) -> String {
+    match e {
+        // Literals are written exactly as they appear in the source, so escapes and number formatting are kept
+        Expr::StringLiteral(v, span) => source_text(f, span.start, span.end).unwrap_or_else(|| format!("\"{}\"", v)),
+        Expr::IntLiteral(v, span) => source_text(f, span.start, span.end).unwrap_or_else(|| v.to_string()),
+        Expr::FloatLiteral(v, span) => source_text(f, span.start, span.end).unwrap_or_else(|| format!("{:?}", v)),
+        Expr::BoolLiteral(v, _) => v.to_string(),
+        Expr::NilLiteral(_) => "nil".to_string(),
+        Expr::Unary(operand, op) => format!("{}{}", op.lexeme, expr(f, operand)),
+        Expr::Binary(left, op, right) | Expr::Logical(left, op, right) => {
+            format!("{} {} {}", expr(f, left), op.lexeme, expr(f, right))
+        }
+        Expr::Sequence(expressions) => expressions.iter().map(|e| expr(f, e)).collect::<Vec<String>>().join(", "),
+        Expr::Ternary(condition, if_true, if_false) => {
+            format!("{} ? {} : {}", expr(f, condition), expr(f, if_true), expr(f, if_false))
+        }
+        Expr::Grouping(inner, _) => format!("({})", expr(f, inner)),
+        Expr::Variable(name) => name.lexeme.clone(),
+        Expr::Assign(name, value) => format!("{} = {}", name.lexeme, expr(f, value)),
+        Expr::Call(callee, _, arguments) => {
+            let arguments: Vec<String> = arguments.iter().map(|a| expr(f, a)).collect();
+            format!("{}({})", expr(f, callee), arguments.join(", "))
+        }
+        Expr::Get(object, name) => format!("{}.{}", expr(f, object), name.lexeme),
+        Expr::List(elements, _) => {
+            let elements: Vec<String> = elements.iter().map(|e| expr(f, e)).collect();
+            format!("[{}]", elements.join(", "))
+        }
+        Expr::Map(entries, _) => {
+            let entries: Vec<String> =
+                entries.iter().map(|(key, value)| format!("{}: {}", expr(f, key), expr(f, value))).collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+        Expr::Index(object, index, _) => format!("{}[{}]", expr(f, object), expr(f, index)),
+        Expr::Slice(object, start, end, _) => {
+            let object = expr(f, object);
+            let start = start.as_ref().map_or(String::new(), |e| expr(f, e));
+            let end = end.as_ref().map_or(String::new(), |e| expr(f, e));
+            format!("{}[{}:{}]", object, start, end)
+        }
+        Expr::SetIndex(object, index, _, value) => {
+            format!("{}[{}] = {}", expr(f, object), expr(f, index), expr(f, value))
+        }
+        Expr::Interpolation(parts) => {
+            let mut s = String::new();
+            for (i, part) in parts.iter().enumerate() {
+                match part {
+                    // The literal parts' spans include the delimiters around them
+                    Expr::StringLiteral(v, span) if i % 2 == 0 => {
+                        let open = if i == 0 { "\"" } else { "}" };
+                        let close = if i == parts.len() - 1 { "\"" } else { "${" };
+                        s.push_str(&source_text(f, span.start, span.end).unwrap_or_else(|| open.to_string() + v + close))
+                    }
+                    part => s.push_str(&expr(f, part)),
+                }
+            }
+            s
+        }
+        Expr::Lambda(decl, arrow) => {
+            let params: Vec<&str> = decl.params.iter().map(|p| p.lexeme.as_str()).collect();
+            match (arrow, decl.body.as_slice()) {
+                (true, [Stmt::Return(Some(value), _)]) => format!("({}) => {}", params.join(", "), expr(f, value)),
+                _ => {
+                    // The body is written to lines of its own, the first of which carries on from the `{`
+                    let outer = std::mem::replace(&mut f.lines, vec![String::new()]);
+                    block_body(f, &decl.body, decl.span.end - 1);
+                    let body = std::mem::replace(&mut f.lines, outer);
+                    format!("func ({}) {{{}", params.join(", "), body.join("\n"))
+                }
+            }
+        }
+    }
+}
+
+fn source_text(f: &Formatter, start: usize, end: usize) -> Option<String> {
+    if start < end {
+        f.source.get(start..end).map(|s| s.to_string())
+    } else {
+        None
+    }
+}
+
+/**
+ * Write out every remaining comment that starts before `offset`.
+ */
+fn flush_comments(f: &mut Formatter, offset: usize) {
+    while let Some(comment) = f.comments.front() {
+        if comment.span.start >= offset {
+            break;
+        }
+        let comment = f.comments.pop_front().unwrap();
+        let line_start = f.line_starts[line_of(f, comment.span.start) - 1];
+        let trailing = !f.source[line_start..comment.span.start].trim().is_empty();
+        if trailing && !f.lines.is_empty() {
+            append(f, &format!(" {}", comment.text));
+        } else {
+            separate(f, comment.span.start);
+            let mut comment_lines = comment.text.lines();
+            if let Some(first) = comment_lines.next() {
+                line(f, first.to_string());
+            }
+            // The rest of a multi-line block comment is left exactly as written
+            for rest in comment_lines {
+                f.lines.push(rest.to_string());
+            }
+        }
+        f.last_line = line_of(f, comment.span.end.saturating_sub(1));
+        f.at_block_start = false;
+    }
+}
+
+/**
+ * Keep a single blank line before something starting at `offset` if there was at least one in the source.
+ */
+fn separate(f: &mut Formatter, offset: usize) {
+    if !f.at_block_start && line_of(f, offset) > f.last_line + 1 {
+        f.lines.push(String::new());
+    }
+}
+
+fn line(f: &mut Formatter, text: String) {
+    f.lines.push(format!("{}{}", INDENT.repeat(f.depth), text));
+}
+
+fn append(f: &mut Formatter, text: &str) {
+    if let Some(last) = f.lines.last_mut() {
+        last.push_str(text);
+    }
+}
+
+// 1-based line number of `offset`
+fn line_of(f: &Formatter, offset: usize) -> usize {
+    match f.line_starts.binary_search(&offset) {
+        Ok(i) => i + 1,
+        Err(i) => i,
+    }
+}
@@ -1,148 +1,957 @@
 use crate::{
-    scanner::{Token, TokenType},
-    syntax_tree::Expr,
+    environment::Environment,
+    scanner::{underline, Span, Token, TokenType},
+    syntax_tree::{quoted, visit_any, Expr, FunctionDecl, Stmt},
 };
 use core::any::Any;
-use std::any::TypeId;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{stdout, Write};
+use std::rc::Rc;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Nil {}
 
-pub fn interpret(e: Expr) -> Result<Box<dyn Any>, String> {
+/**
+ * What a `catch` clause receives when the interpreter itself raised the error, rather than a `throw` statement.
+ * Its fields can be read as `e.message` and `e.line`.
+ */
+#[derive(Debug, Clone)]
+pub struct ErrorObject {
+    pub message: String,
+    pub line: u32,
+}
+
+/**
+ * Anything that can be invoked with `()`. Callables are stored as `Rc<dyn Callable>` values so that they can be
+ * copied in and out of environments cheaply.
+ */
+pub trait Callable {
+    fn name(&self) -> String;
+    fn arity(&self) -> usize;
+    fn call(&self, i: &mut Interpreter, arguments: Vec<Box<dyn Any>>) -> Result<Box<dyn Any>, RuntimeError>;
+}
+
+/**
+ * How deep calls can nest. Going any deeper is almost always runaway recursion, which is reported as a runtime error
+ * rather than left to overflow the interpreter's own stack.
+ */
+pub const MAX_FRAMES: usize = 1000;
+
+/**
+ * A user-defined function, together with the environment it was declared in.
+ */
+pub struct Function {
+    decl: Rc<FunctionDecl>,
+    closure: Rc<RefCell<Environment>>,
+}
+impl Callable for Function {
+    fn name(&self) -> String {
+        self.decl.name.lexeme.clone()
+    }
+
+    fn arity(&self) -> usize {
+        self.decl.params.len()
+    }
+
+    fn call(&self, i: &mut Interpreter, arguments: Vec<Box<dyn Any>>) -> Result<Box<dyn Any>, RuntimeError> {
+        let mut env = Environment::new_enclosed(Rc::clone(&self.closure));
+        for (param, arg) in self.decl.params.iter().zip(arguments) {
+            env.define(param.lexeme.clone(), arg);
+        }
+        let env = Rc::new(RefCell::new(env));
+        if i.frames.len() >= MAX_FRAMES {
+            return Err(RuntimeError::new(i, String::from("stack overflow"), None));
+        }
+        i.frames.push(Frame { name: self.name(), line: self.decl.span.line, environment: Rc::clone(&env) });
+        let result = execute_block(i, &self.decl.body, env);
+        i.frames.pop();
+        match result? {
+            Some(value) => Ok(value),
+            None => Ok(Box::new(Nil {})),
+        }
+    }
+}
+
+/**
+ * A list. Lists are shared rather than copied, so changing one through any variable holding it changes it everywhere.
+ */
+pub type List = Rc<RefCell<Vec<Box<dyn Any>>>>;
+
+/**
+ * A map. Like lists, maps are shared rather than copied.
+ */
+pub type Map = Rc<RefCell<Entries>>;
+
+/**
+ * The contents of a map. Keys keep the order they were first added in, which is the order `keys()` and `values()`
+ * return them in and the order a map prints in.
+ */
+#[derive(Default)]
+pub struct Entries {
+    // Each value is stored along with when its key was added
+    entries: HashMap<MapKey, (u64, Box<dyn Any>)>,
+    added: u64,
+}
+impl Entries {
+    pub fn get(&self, key: &Box<dyn Any>) -> Result<Option<&Box<dyn Any>>, String> {
+        Ok(self.entries.get(&MapKey::new(key)?).map(|(_, value)| value))
+    }
+
+    pub fn insert(&mut self, key: &Box<dyn Any>, value: Box<dyn Any>) -> Result<(), String> {
+        let key = MapKey::new(key)?;
+        match self.entries.get_mut(&key) {
+            Some(entry) => entry.1 = value,
+            None => {
+                self.entries.insert(key, (self.added, value));
+                self.added += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &Box<dyn Any>) -> Result<Option<Box<dyn Any>>, String> {
+        Ok(self.entries.remove(&MapKey::new(key)?).map(|(_, value)| value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /**
+     * The keys and values, in order.
+     */
+    pub fn iter(&self) -> impl Iterator<Item = (&Box<dyn Any>, &Box<dyn Any>)> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(_, (added, _))| *added);
+        entries.into_iter().map(|(key, (_, value))| (&key.0, value))
+    }
+}
+
+/**
+ * A map key. Keys that are `==` are the same key, so integers and floats hash by numeric value and `1` and `1.0` find
+ * the same entry. Only strings, numbers, bools and nil can be keys, and NaN can't, since it isn't equal to itself.
+ */
+struct MapKey(Box<dyn Any>);
+impl MapKey {
+    fn new(key: &Box<dyn Any>) -> Result<Self, String> {
+        match Number::from_value(key) {
+            Some(n) if n.to_f64().is_nan() => Err("nan can't be a map key.".to_string()),
+            Some(_) => Ok(MapKey(clone_value(key))),
+            None if (*key).is::<String>() || (*key).is::<bool>() || (*key).is::<Nil>() => Ok(MapKey(clone_value(key))),
+            None => Err(format!("Only strings, numbers, bools and nil can be map keys, not {}.", type_name(key))),
+        }
+    }
+}
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let Some(n) = Number::from_value(&self.0) {
+            // Floats equal to an integer hash the same as it, which takes care of -0.0 too
+            match n.to_exact_i64() {
+                Some(n) => n.hash(state),
+                None => n.to_f64().to_bits().hash(state),
+            }
+        } else if let Some(s) = (*self.0).downcast_ref::<String>() {
+            s.hash(state);
+        } else if let Some(b) = (*self.0).downcast_ref::<bool>() {
+            b.hash(state);
+        }
+    }
+}
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        are_equal(&self.0, &other.0)
+    }
+}
+impl Eq for MapKey {}
+
+// The methods lists and maps have, and how many arguments each one takes
+const LIST_METHODS: [(&str, usize); 5] = [("push", 1), ("pop", 0), ("len", 0), ("insert", 2), ("remove", 1)];
+const MAP_METHODS: [(&str, usize); 5] = [("keys", 0), ("values", 0), ("has", 1), ("remove", 1), ("len", 0)];
+
+/**
+ * A method looked up on a particular list or map, so that `xs.push` is a function pushing onto `xs`.
+ */
+struct Method {
+    receiver: Receiver,
+    name: &'static str,
+    arity: usize,
+}
+enum Receiver {
+    List(List),
+    Map(Map),
+}
+impl Callable for Method {
+    fn name(&self) -> String {
+        self.name.to_string()
+    }
+
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn call(&self, i: &mut Interpreter, arguments: Vec<Box<dyn Any>>) -> Result<Box<dyn Any>, RuntimeError> {
+        let result = match &self.receiver {
+            Receiver::List(list) => list_method(&mut list.borrow_mut(), self.name, arguments),
+            Receiver::Map(map) => map_method(&mut map.borrow_mut(), self.name, arguments),
+        };
+        result.map_err(|msg| RuntimeError::new(i, msg, None))
+    }
+}
+
+fn list_method(
+    list: &mut Vec<Box<dyn Any>>,
+    name: &str,
+    mut arguments: Vec<Box<dyn Any>>,
+) -> Result<Box<dyn Any>, String> {
+    match name {
+        "push" => {
+            list.push(arguments.pop().unwrap());
+            Ok(Box::new(Nil {}))
+        }
+        "pop" => list.pop().ok_or_else(|| "Can't pop from an empty list.".to_string()),
+        "len" => Ok(Box::new(list.len() as i64)),
+        "insert" => {
+            let value = arguments.pop().unwrap();
+            // Inserting at the length appends
+            match list_position(&arguments[0], list.len())? {
+                at if at >= 0 && at <= list.len() as i64 => {
+                    list.insert(at as usize, value);
+                    Ok(Box::new(Nil {}))
+                }
+                _ => Err(index_error(&arguments[0], list.len())),
+            }
+        }
+        _ => list_index(&arguments[0], list.len()).map(|at| list.remove(at)),
+    }
+}
+
+fn map_method(map: &mut Entries, name: &str, arguments: Vec<Box<dyn Any>>) -> Result<Box<dyn Any>, String> {
+    match name {
+        "keys" => {
+            let keys = map.iter().map(|(key, _)| clone_value(key)).collect();
+            Ok(Box::new(Rc::new(RefCell::new(keys)) as List))
+        }
+        "values" => {
+            let values = map.iter().map(|(_, value)| clone_value(value)).collect();
+            Ok(Box::new(Rc::new(RefCell::new(values)) as List))
+        }
+        "has" => Ok(Box::new(map.get(&arguments[0])?.is_some())),
+        "len" => Ok(Box::new(map.len() as i64)),
+        _ => map.remove(&arguments[0])?.ok_or_else(|| key_error(&arguments[0])),
+    }
+}
+
+/**
+ * A function call in progress, or the top level of the program. Frames are kept up to date as statements run so that
+ * debuggers can show where each one is.
+ */
+pub struct Frame {
+    pub name: String,
+    // Line of the statement currently running in this frame
+    pub line: u32,
+    // The innermost scope that statement is running in
+    pub environment: Rc<RefCell<Environment>>,
+}
+
+/**
+ * An error raised while a program runs, along with where it happened: the innermost expression that failed, and the
+ * function name and current line of every frame on the call stack at the time, innermost first.
+ *
+ * Errors unwind until a `try` statement catches them. `thrown` holds the value given to a `throw` statement; errors
+ * from the interpreter itself have none, and are caught as an `ErrorObject` instead. Fatal errors, such as a debugger
+ * stopping the program, can't be caught at all.
+ */
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    pub span: Option<Span>,
+    pub trace: Vec<(String, u32)>,
+    pub thrown: Option<Box<dyn Any>>,
+    pub fatal: bool,
+}
+impl RuntimeError {
+    pub fn new(i: &Interpreter, message: String, span: Option<Span>) -> Self {
+        let mut trace: Vec<(String, u32)> = i.frames.iter().rev().map(|f| (f.name.clone(), f.line)).collect();
+        // The failing expression may be on a later line than the start of the statement it's in
+        if let (Some(span), Some(innermost)) = (span, trace.first_mut()) {
+            innermost.1 = span.line;
+        }
+        RuntimeError { message, span, trace, thrown: None, fatal: false }
+    }
+
+    /**
+     * An error raised by `throw value`. Rethrowing a caught `ErrorObject` keeps its original message.
+     */
+    pub fn thrown(i: &Interpreter, value: Box<dyn Any>, span: Span) -> Self {
+        let message = match (*value).downcast_ref::<ErrorObject>() {
+            Some(error) => error.message.clone(),
+            None => format!("Uncaught exception: {}", visit_any(clone_value(&value))),
+        };
+        RuntimeError { thrown: Some(value), ..RuntimeError::new(i, message, Some(span)) }
+    }
+
+    /**
+     * The value a `catch` clause binds for this error.
+     */
+    pub fn value(&self) -> Box<dyn Any> {
+        match &self.thrown {
+            Some(value) => clone_value(value),
+            None => Box::new(ErrorObject { message: self.message.clone(), line: self.line() }),
+        }
+    }
+
+    pub fn line(&self) -> u32 {
+        match (self.span, self.trace.first()) {
+            (Some(span), _) => span.line,
+            (None, Some((_, line))) => *line,
+            (None, None) => 0,
+        }
+    }
+}
+impl RuntimeError {
+    /**
+     * The error as shown to users: the message, the failing expression underlined in `source` (when it's available),
+     * and a traceback if the error happened inside a function call.
+     */
+    pub fn report(&self, source: Option<&str>) -> String {
+        let mut lines = vec![self.to_string()];
+        if let (Some(span), Some(source)) = (self.span, source) {
+            lines.extend(underline(source, span));
+        }
+        if self.trace.len() > 1 {
+            lines.push("Traceback (most recent call first):".to_string());
+            // Runaway recursion repeats the same frame hundreds of times, so runs of one frame are shown once
+            let mut frames = self.trace.iter().peekable();
+            while let Some((name, line)) = frames.next() {
+                lines.push(format!("    in {} at line {}", name, line));
+                let mut repeats = 0;
+                while frames.next_if(|(n, l)| n == name && l == line).is_some() {
+                    repeats += 1;
+                }
+                if repeats > 0 {
+                    let times = if repeats == 1 { "time" } else { "times" };
+                    lines.push(format!("    ... repeated {} more {}", repeats, times));
+                }
+            }
+        }
+        lines.join("\n")
+    }
+}
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}] Error: {}", self.line(), self.message)
+    }
+}
+
+/**
+ * What dividing a float by zero (or taking its remainder) does. Dividing an integer by zero is always an error.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloatDivision {
+    /// Follow IEEE 754: `1.0 / 0` is `inf` and `0.0 / 0` is `nan`
+    Ieee,
+    /// Raise a runtime error, the same as for integers
+    Error,
+}
+
+/**
+ * Callbacks into a debugger. The interpreter calls `before_statement` just before running each statement, once the
+ * current frame has been updated to point at it. Returning an error stops the program.
+ */
+pub trait Hook {
+    fn before_statement(&mut self, i: &mut Interpreter, s: &Stmt) -> Result<(), String>;
+}
+
+/**
+ * Interpreter state that lives across calls to `execute`. Keeping one of these around (e.g. for the lifetime of a REPL
+ * session) keeps every global variable and function around with it.
+ */
+pub struct Interpreter {
+    environment: Rc<RefCell<Environment>>,
+    // The call stack, outermost first. The bottom frame is the top level of the program.
+    pub frames: Vec<Frame>,
+    pub hook: Option<Box<dyn Hook>>,
+    /// Where `print` writes to
+    pub output: Box<dyn Write>,
+    pub float_division: FloatDivision,
+}
+impl Interpreter {
+    pub fn new() -> Self {
+        let globals = Rc::new(RefCell::new(Environment::new()));
+        let top = Frame { name: "<script>".to_string(), line: 0, environment: Rc::clone(&globals) };
+        Interpreter {
+            environment: globals,
+            frames: vec![top],
+            hook: None,
+            output: Box::new(stdout()),
+            float_division: FloatDivision::Ieee,
+        }
+    }
+}
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ * Execute a single statement. Returns `Some(value)` if a `return` statement was hit, which unwinds up to the
+ * enclosing function call.
+ */
+pub fn execute(i: &mut Interpreter, s: &Stmt) -> Result<Option<Box<dyn Any>>, RuntimeError> {
+    if let Some(frame) = i.frames.last_mut() {
+        frame.line = s.span().line;
+        frame.environment = Rc::clone(&i.environment);
+    }
+    // The hook is taken out while it runs so that it can have the interpreter to itself
+    if let Some(mut hook) = i.hook.take() {
+        let result = hook.before_statement(i, s);
+        i.hook = Some(hook);
+        result.map_err(|msg| RuntimeError { fatal: true, ..RuntimeError::new(i, msg, None) })?;
+    }
+    match s {
+        Stmt::Expression(e, _) => {
+            interpret(i, e)?;
+            Ok(None)
+        }
+        Stmt::Print(e, _) => {
+            let value = visit_any(interpret(i, e)?);
+            if let Err(e) = writeln!(i.output, "{}", value) {
+                return Err(RuntimeError::new(i, format!("couldn't print: {}", e), Some(s.span())));
+            }
+            Ok(None)
+        }
+        Stmt::Var(name, initializer, ..) => {
+            let value = match initializer {
+                Some(e) => interpret(i, e)?,
+                None => Box::new(Nil {}),
+            };
+            i.environment.borrow_mut().define(name.lexeme.clone(), value);
+            Ok(None)
+        }
+        Stmt::Block(statements, _) => {
+            let env = Environment::new_enclosed(Rc::clone(&i.environment));
+            execute_block(i, statements, Rc::new(RefCell::new(env)))
+        }
+        Stmt::If(condition, then_branch, else_branch, _) => {
+            if is_truthful(interpret(i, condition)?) {
+                execute(i, then_branch)
+            } else if let Some(else_branch) = else_branch {
+                execute(i, else_branch)
+            } else {
+                Ok(None)
+            }
+        }
+        Stmt::While(condition, body, _) => {
+            while is_truthful(interpret(i, condition)?) {
+                if let Some(value) = execute(i, body)? {
+                    return Ok(Some(value));
+                }
+            }
+            Ok(None)
+        }
+        Stmt::For(initializer, condition, increment, body, _) => {
+            // The initializer gets a scope of its own, so loop variables don't leak out
+            let env = Environment::new_enclosed(Rc::clone(&i.environment));
+            let previous = std::mem::replace(&mut i.environment, Rc::new(RefCell::new(env)));
+            let result = execute_for(i, initializer, condition, increment, body);
+            i.environment = previous;
+            result
+        }
+        Stmt::Function(decl) => {
+            let function: Rc<dyn Callable> = Rc::new(Function {
+                decl: Rc::clone(decl),
+                closure: Rc::clone(&i.environment),
+            });
+            i.environment.borrow_mut().define(decl.name.lexeme.clone(), Box::new(function));
+            Ok(None)
+        }
+        Stmt::Return(value, _) => match value {
+            Some(e) => Ok(Some(interpret(i, e)?)),
+            None => Ok(Some(Box::new(Nil {}))),
+        },
+        Stmt::Throw(e, _) => {
+            let value = interpret(i, e)?;
+            Err(RuntimeError::thrown(i, value, e.span()))
+        }
+        Stmt::Try(body, catch, finally, _) => {
+            let mut result = execute(i, body);
+            if let (Err(error), Some((name, handler))) = (&result, catch) {
+                if !error.fatal {
+                    // The caught value gets a scope of its own, outside the handler's block
+                    let mut env = Environment::new_enclosed(Rc::clone(&i.environment));
+                    env.define(name.lexeme.clone(), error.value());
+                    result = execute_block(i, std::slice::from_ref(&**handler), Rc::new(RefCell::new(env)));
+                }
+            }
+            if let Some(finally) = finally {
+                // A `finally` block that returns or raises an error of its own replaces whatever happened before it
+                let finished = execute(i, finally);
+                if !matches!(finished, Ok(None)) {
+                    return finished;
+                }
+            }
+            result
+        }
+    }
+}
+
+fn execute_for(
+    i: &mut Interpreter,
+    initializer: &Option<Box<Stmt>>,
+    condition: &Option<Expr>,
+    increment: &Option<Expr>,
+    body: &Stmt,
+) -> Result<Option<Box<dyn Any>>, RuntimeError> {
+    if let Some(initializer) = initializer {
+        execute(i, initializer)?;
+    }
+    loop {
+        if let Some(condition) = condition {
+            if !is_truthful(interpret(i, condition)?) {
+                return Ok(None);
+            }
+        }
+        if let Some(value) = execute(i, body)? {
+            return Ok(Some(value));
+        }
+        if let Some(increment) = increment {
+            interpret(i, increment)?;
+        }
+    }
+}
+
+/**
+ * Execute `statements` inside of `env`, restoring the previous environment afterwards whether or not execution
+ * succeeded.
+ */
+pub fn execute_block(
+    i: &mut Interpreter,
+    statements: &[Stmt],
+    env: Rc<RefCell<Environment>>,
+) -> Result<Option<Box<dyn Any>>, RuntimeError> {
+    let previous = std::mem::replace(&mut i.environment, env);
+    let mut result = Ok(None);
+    for statement in statements {
+        result = execute(i, statement);
+        if !matches!(result, Ok(None)) {
+            break;
+        }
+    }
+    i.environment = previous;
+    result
+}
+
+/**
+ * Evaluate an expression as if it appeared in `env`, e.g. a scope of a paused function.
+ */
+pub fn interpret_in(
+    i: &mut Interpreter,
+    e: &Expr,
+    env: Rc<RefCell<Environment>>,
+) -> Result<Box<dyn Any>, RuntimeError> {
+    let previous = std::mem::replace(&mut i.environment, env);
+    let result = interpret(i, e);
+    i.environment = previous;
+    result
+}
+
+pub fn interpret(i: &mut Interpreter, e: &Expr) -> Result<Box<dyn Any>, RuntimeError> {
     match e {
-        Expr::StringLiteral(v) => Ok(Box::new(v)),
-        Expr::IntLiteral(v) => Ok(Box::new(v as f64)),
-        Expr::FloatLiteral(v) => Ok(Box::new(v)),
-        Expr::BoolLiteral(v) => Ok(Box::new(v)),
-        Expr::NilLiteral() => Ok(Box::new(Nil {})),
-        Expr::Grouping(e) => interpret(*e),
-        Expr::Unary(ue, t) => match interpret(*ue) {
-            Ok(value) => match t.token_type {
-                TokenType::Minus => match (*value).downcast_ref::<f64>() {
-                    Some(f) => Ok(Box::new(-1.0 * (*f))),
-                    None => Err(format!("failed to convert value to f64")),
+        Expr::StringLiteral(v, _) => Ok(Box::new(v.clone())),
+        Expr::IntLiteral(v, _) => Ok(Box::new(*v)),
+        Expr::FloatLiteral(v, _) => Ok(Box::new(*v)),
+        Expr::BoolLiteral(v, _) => Ok(Box::new(*v)),
+        Expr::NilLiteral(_) => Ok(Box::new(Nil {})),
+        Expr::Grouping(e, _) => interpret(i, e),
+        Expr::Variable(name) => {
+            let value = i.environment.borrow().get(name);
+            value.map_err(|msg| RuntimeError::new(i, msg, Some(e.span())))
+        }
+        Expr::Assign(name, value) => {
+            let value = interpret(i, value)?;
+            let result = i.environment.borrow_mut().assign(name, clone_value(&value));
+            result.map_err(|msg| RuntimeError::new(i, msg, Some(e.span())))?;
+            Ok(value)
+        }
+        Expr::Logical(left, op, right) => {
+            let left_value = interpret(i, left)?;
+            // Short-circuit: `or` returns a truthy left operand, `and` returns a falsey one
+            let left_truthful = is_truthful(clone_value(&left_value));
+            if (op.token_type == TokenType::Or) == left_truthful {
+                Ok(left_value)
+            } else {
+                interpret(i, right)
+            }
+        }
+        Expr::Call(callee, _, arguments) => {
+            let callee = interpret(i, callee)?;
+            let mut args = vec![];
+            for argument in arguments {
+                args.push(interpret(i, argument)?);
+            }
+            match (*callee).downcast_ref::<Rc<dyn Callable>>() {
+                Some(function) => {
+                    if args.len() != function.arity() {
+                        let msg = format!("Expected {} arguments but got {}.", function.arity(), args.len());
+                        Err(RuntimeError::new(i, msg, Some(e.span())))
+                    } else {
+                        // Built-in functions don't know where they were called from
+                        Rc::clone(function).call(i, args).map_err(|error| match error.span {
+                            None if !error.fatal => RuntimeError::new(i, error.message, Some(e.span())),
+                            _ => error,
+                        })
+                    }
+                }
+                None => Err(RuntimeError::new(i, "Can only call functions.".to_string(), Some(e.span()))),
+            }
+        }
+        Expr::Unary(operand, t) => {
+            let value = interpret(i, operand)?;
+            let result: Result<Box<dyn Any>, String> = match t.token_type {
+                TokenType::Minus => match Number::from_value(&value) {
+                    Some(Number::Int(n)) => match n.checked_neg() {
+                        Some(n) => Ok(Box::new(n)),
+                        None => Err("Integer overflow in '-'.".to_string()),
+                    },
+                    Some(Number::Float(n)) => Ok(Box::new(-n)),
+                    None => Err(format!("Operand of '-' must be a number, not {}.", type_name(&value))),
                 },
                 TokenType::Bang => Ok(Box::new(!is_truthful(value))),
-                _ => Err(format!(
-                    "could not evaluate unary expression {:?} {:?}.",
-                    t, value
-                )),
-            },
-            x @ Err(_) => x,
-        },
-        Expr::Binary(left, op, right) => match interpret(*left) {
-            // First interpet the left expression
-            Ok(left_value) => match interpret(*right) {
-                // If that succeeds, interpret the right expression
-                Ok(right_value) => match op.token_type {
-                    TokenType::Minus => {
-                        eval_binary_numeric_op(&left_value, &right_value, |x, y| x - y)
-                    }
-                    TokenType::Star => {
-                        eval_binary_numeric_op(&left_value, &right_value, |x, y| x * y)
-                    }
-                    TokenType::Slash => {
-                        eval_binary_numeric_op(&left_value, &right_value, |x, y| x / y)
-                    }
-                    TokenType::Plus => {
-                        match ((*left_value).is::<String>(), (*right_value).is::<String>()) { 
-                            (true, true) => // Both args are string; concatenate
-                                Ok(Box::new(
-                                    (*left_value).downcast_ref::<String>().unwrap().to_owned()
-                                        + (*right_value).downcast_ref::<String>().unwrap(),
-                                )),
-                            (true, false) => { // Left is string; to_string(right) and add
-                                match to_string(right_value) {
-                                    Ok(v) => 
-                                        Ok(Box::new(
-                                            (*left_value).downcast_ref::<String>().unwrap().to_owned()
-                                                + &v,
-                                        )),
-                                    Err(e) => Err(e),
-                                }
-                            },
-                            (false, true) => { // Right is string; to_string(left) and add
-                                match to_string(left_value) {
-                                    Ok(v) => 
-                                        Ok(Box::new(
-                                            v +
-                                            &(*right_value).downcast_ref::<String>().unwrap().to_owned()
-                                        )),
-                                    Err(e) => Err(e),
-                                }
-                            },
-                            (false, false) => // Neither are string; attempt to add as numerics
-                                eval_binary_numeric_op(&left_value, &right_value, |x, y| x + y)
-                        }
-                    }
-                    TokenType::Greater => {
-                        eval_binary_boolean_op(&left_value, &right_value, |x, y| x > y)
-                    }
-                    TokenType::GreaterEqual => {
-                        eval_binary_boolean_op(&left_value, &right_value, |x, y| x >= y)
-                    }
-                    TokenType::Less => {
-                        eval_binary_boolean_op(&left_value, &right_value, |x, y| x < y)
-                    }
-                    TokenType::LessEqual => {
-                        eval_binary_boolean_op(&left_value, &right_value, |x, y| x <= y)
-                    }
-                    TokenType::EqualEqual => Ok(Box::new(are_equal(&left_value, &right_value))),
-                    TokenType::BangEqual => Ok(Box::new(!are_equal(&left_value, &right_value))),
-                    _ => todo!(),
+                _ => Err(format!("could not evaluate unary expression {:?} {:?}.", t, value)),
+            };
+            result.map_err(|msg| RuntimeError::new(i, msg, Some(e.span())))
+        }
+        Expr::Binary(left, op, right) => {
+            let left_value = interpret(i, left)?;
+            let right_value = interpret(i, right)?;
+            let result = eval_binary(op, left_value, right_value, i.float_division);
+            result.map_err(|msg| RuntimeError::new(i, msg, Some(e.span())))
+        }
+        Expr::Get(object, name) => {
+            let object = interpret(i, object)?;
+            get_property(&object, name).map_err(|msg| RuntimeError::new(i, msg, Some(e.span())))
+        }
+        Expr::Interpolation(parts) => {
+            let mut s = String::new();
+            for part in parts {
+                s.push_str(&visit_any(interpret(i, part)?));
+            }
+            Ok(Box::new(s))
+        }
+        Expr::Ternary(condition, if_true, if_false) => {
+            if is_truthful(interpret(i, condition)?) {
+                interpret(i, if_true)
+            } else {
+                interpret(i, if_false)
+            }
+        }
+        Expr::Sequence(expressions) => {
+            let (last, rest) = expressions.split_last().unwrap();
+            for e in rest {
+                interpret(i, e)?;
+            }
+            interpret(i, last)
+        }
+        Expr::Lambda(decl, _) => {
+            let function: Rc<dyn Callable> = Rc::new(Function {
+                decl: Rc::clone(decl),
+                closure: Rc::clone(&i.environment),
+            });
+            Ok(Box::new(function))
+        }
+        Expr::List(elements, _) => {
+            let mut list = vec![];
+            for element in elements {
+                list.push(interpret(i, element)?);
+            }
+            Ok(Box::new(Rc::new(RefCell::new(list)) as List))
+        }
+        Expr::Map(entries, _) => {
+            let mut map = Entries::default();
+            for (key, value) in entries {
+                let k = interpret(i, key)?;
+                let v = interpret(i, value)?;
+                map.insert(&k, v).map_err(|msg| RuntimeError::new(i, msg, Some(key.span())))?;
+            }
+            Ok(Box::new(Rc::new(RefCell::new(map)) as Map))
+        }
+        Expr::Index(object, index, _) => {
+            let object = interpret(i, object)?;
+            let index = interpret(i, index)?;
+            get_index(&object, &index).map_err(|msg| RuntimeError::new(i, msg, Some(e.span())))
+        }
+        Expr::Slice(object, start, end, _) => {
+            let object = interpret(i, object)?;
+            let start = match start {
+                Some(start) => Some(interpret(i, start)?),
+                None => None,
+            };
+            let end = match end {
+                Some(end) => Some(interpret(i, end)?),
+                None => None,
+            };
+            slice(&object, start, end).map_err(|msg| RuntimeError::new(i, msg, Some(e.span())))
+        }
+        Expr::SetIndex(object, index, _, value) => {
+            let object = interpret(i, object)?;
+            let index = interpret(i, index)?;
+            let value = interpret(i, value)?;
+            let result = set_index(&object, &index, clone_value(&value));
+            result.map_err(|msg| RuntimeError::new(i, msg, Some(e.span())))?;
+            Ok(value)
+        }
+    }
+}
+
+fn get_property(object: &Box<dyn Any>, name: &Token) -> Result<Box<dyn Any>, String> {
+    let (receiver, methods) = if let Some(list) = (*object).downcast_ref::<List>() {
+        (Receiver::List(Rc::clone(list)), &LIST_METHODS)
+    } else if let Some(map) = (*object).downcast_ref::<Map>() {
+        (Receiver::Map(Rc::clone(map)), &MAP_METHODS)
+    } else {
+        return match ((*object).downcast_ref::<ErrorObject>(), name.lexeme.as_str()) {
+            (Some(error), "message") => Ok(Box::new(error.message.clone())),
+            (Some(error), "line") => Ok(Box::new(error.line as i64)),
+            (Some(_), _) => Err(format!("Undefined property '{}' on error.", name.lexeme)),
+            (None, _) => Err(format!("Only errors, lists and maps have properties, not {}.", type_name(object))),
+        };
+    };
+    match methods.iter().find(|(method, _)| *method == name.lexeme) {
+        Some((method, arity)) => {
+            let method: Rc<dyn Callable> = Rc::new(Method { receiver, name: method, arity: *arity });
+            Ok(Box::new(method))
+        }
+        None => Err(format!("Undefined method '{}' on {}.", name.lexeme, type_name(object))),
+    }
+}
+
+fn get_index(object: &Box<dyn Any>, index: &Box<dyn Any>) -> Result<Box<dyn Any>, String> {
+    if let Some(list) = (*object).downcast_ref::<List>() {
+        let list = list.borrow();
+        Ok(clone_value(&list[list_index(index, list.len())?]))
+    } else if let Some(map) = (*object).downcast_ref::<Map>() {
+        map.borrow().get(index)?.map(clone_value).ok_or_else(|| key_error(index))
+    } else {
+        Err(format!("Only lists and maps can be indexed, not {}.", type_name(object)))
+    }
+}
+
+fn set_index(object: &Box<dyn Any>, index: &Box<dyn Any>, value: Box<dyn Any>) -> Result<(), String> {
+    if let Some(list) = (*object).downcast_ref::<List>() {
+        let mut list = list.borrow_mut();
+        let at = list_index(index, list.len())?;
+        list[at] = value;
+        Ok(())
+    } else if let Some(map) = (*object).downcast_ref::<Map>() {
+        map.borrow_mut().insert(index, value)
+    } else {
+        Err(format!("Only lists and maps can be indexed, not {}.", type_name(object)))
+    }
+}
+
+/**
+ * A new list holding part of `object`. Like indices, the ends can count back from the end of the list, but a slice
+ * reaching past either end of the list just stops there.
+ */
+fn slice(
+    object: &Box<dyn Any>,
+    start: Option<Box<dyn Any>>,
+    end: Option<Box<dyn Any>>,
+) -> Result<Box<dyn Any>, String> {
+    let list = match (*object).downcast_ref::<List>() {
+        Some(list) => list.borrow(),
+        None => return Err(format!("Only lists can be sliced, not {}.", type_name(object))),
+    };
+    let len = list.len() as i64;
+    let start = match start {
+        Some(start) => list_position(&start, list.len())?.clamp(0, len),
+        None => 0,
+    };
+    let end = match end {
+        Some(end) => list_position(&end, list.len())?.clamp(0, len),
+        None => len,
+    };
+    let elements = list.iter().take(end as usize).skip(start as usize).map(clone_value).collect();
+    Ok(Box::new(Rc::new(RefCell::new(elements)) as List))
+}
+
+// Where `index` points to in a list of length `len`, which must be an element of the list
+fn list_index(index: &Box<dyn Any>, len: usize) -> Result<usize, String> {
+    match list_position(index, len)? {
+        at if at >= 0 && at < len as i64 => Ok(at as usize),
+        _ => Err(index_error(index, len)),
+    }
+}
+
+// Where `index` points to in a list of length `len`, with negative indices counting back from the end. The result
+// may be outside the list.
+fn list_position(index: &Box<dyn Any>, len: usize) -> Result<i64, String> {
+    match (*index).downcast_ref::<i64>() {
+        Some(n) if *n < 0 => Ok(n.saturating_add(len as i64)),
+        Some(n) => Ok(*n),
+        None if (*index).is::<f64>() => Err("List indices must be integers, not floats.".to_string()),
+        None => Err(format!("List indices must be integers, not {}.", type_name(index))),
+    }
+}
+
+fn index_error(index: &Box<dyn Any>, len: usize) -> String {
+    format!("List index {} is out of range for a list of length {}.", visit_any(clone_value(index)), len)
+}
+
+fn key_error(key: &Box<dyn Any>) -> String {
+    format!("Key {} isn't in the map.", quoted(key))
+}
+
+fn eval_binary(
+    op: &Token,
+    left_value: Box<dyn Any>,
+    right_value: Box<dyn Any>,
+    float_division: FloatDivision,
+) -> Result<Box<dyn Any>, String> {
+    match op.token_type {
+        TokenType::Minus | TokenType::Star | TokenType::Slash | TokenType::Percent => {
+            eval_binary_numeric_op(&left_value, op, &right_value, float_division)
+        }
+        TokenType::Plus => {
+            match ((*left_value).is::<String>(), (*right_value).is::<String>()) {
+                (true, true) => // Both args are string; concatenate
+                    Ok(Box::new(
+                        (*left_value).downcast_ref::<String>().unwrap().to_owned()
+                            + (*right_value).downcast_ref::<String>().unwrap(),
+                    )),
+                (true, false) => { // Left is string; stringify right the way print would and add
+                    let v = visit_any(right_value);
+                    Ok(Box::new((*left_value).downcast_ref::<String>().unwrap().to_owned() + &v))
                 },
-                Err(right_reason) => Err(format!(
-                    "Could not evaluate right operand of binary expression. Reason = {}",
-                    right_reason
-                )),
-            },
-            Err(left_reason) => Err(format!(
-                "Could not evaluate left operand of binary expression. Reason = {}",
-                left_reason
-            )),
-        },
-        _ => todo!(),
+                (false, true) => { // Right is string; stringify left the way print would and add
+                    let v = visit_any(left_value);
+                    Ok(Box::new(v + (*right_value).downcast_ref::<String>().unwrap()))
+                },
+                (false, false) => // Neither are string; attempt to add as numerics
+                    eval_binary_numeric_op(&left_value, op, &right_value, float_division)
+            }
+        }
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
+            eval_binary_boolean_op(&left_value, op, &right_value)
+        }
+        TokenType::EqualEqual => Ok(Box::new(are_equal(&left_value, &right_value))),
+        TokenType::BangEqual => Ok(Box::new(!are_equal(&left_value, &right_value))),
+        _ => Err(format!("Unknown binary operator '{}'.", op.lexeme)),
     }
 }
 
+// A number operand. Integers stay exact until they meet a float.
+#[derive(Clone, Copy)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+impl Number {
+    fn from_value(v: &Box<dyn Any>) -> Option<Number> {
+        if let Some(n) = (*v).downcast_ref::<i64>() {
+            Some(Number::Int(*n))
+        } else {
+            (*v).downcast_ref::<f64>().map(|n| Number::Float(*n))
+        }
+    }
+
+    fn to_f64(self) -> f64 {
+        match self {
+            Number::Int(n) => n as f64,
+            Number::Float(n) => n,
+        }
+    }
+
+    // The integer this number is exactly equal to, if there is one. Going through f64 instead would make integers
+    // above 2^53 equal to floats that are only close to them.
+    fn to_exact_i64(self) -> Option<i64> {
+        match self {
+            Number::Int(n) => Some(n),
+            // 2^63 is the first float past the end of i64's range
+            Number::Float(n) if n.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&n) => {
+                Some(n as i64)
+            }
+            Number::Float(_) => None,
+        }
+    }
+}
+
+/**
+ * Arithmetic on two numbers. Integers give integers, with `/` truncating towards zero; overflow and dividing an
+ * integer by zero are errors. Once either side is a float both sides are treated as floats, and dividing by zero is up
+ * to `float_division`.
+ */
 pub fn eval_binary_numeric_op(
     left_value: &Box<dyn Any>,
+    op: &Token,
     right_value: &Box<dyn Any>,
-    op: fn(f64, f64) -> f64,
+    float_division: FloatDivision,
 ) -> Result<Box<dyn Any>, String> {
-    if (*left_value).is::<i64>() {
-        match (*right_value).downcast_ref::<i64>() {
-            Some(right_i64) => Ok(Box::new(op((*(*left_value).downcast_ref::<i64>().unwrap()) as f64, (*right_i64) as f64))),
-            None => Err(String::from("right value is not an i64")),
+    let divides = matches!(op.token_type, TokenType::Slash | TokenType::Percent);
+    match (Number::from_value(left_value), Number::from_value(right_value)) {
+        (Some(Number::Int(x)), Some(Number::Int(y))) => {
+            if divides && y == 0 {
+                return Err("Division by zero.".to_string());
+            }
+            let result = match op.token_type {
+                TokenType::Plus => x.checked_add(y),
+                TokenType::Minus => x.checked_sub(y),
+                TokenType::Star => x.checked_mul(y),
+                TokenType::Slash => x.checked_div(y),
+                _ => x.checked_rem(y),
+            };
+            match result {
+                Some(n) => Ok(Box::new(n)),
+                None => Err(format!("Integer overflow in '{}'.", op.lexeme)),
+            }
         }
-    } else if (*left_value).is::<f64>() {
-        match (*right_value).downcast_ref::<f64>() {
-            Some(right_f64) => Ok(Box::new(op(*(*left_value).downcast_ref::<f64>().unwrap(), *right_f64))),
-            None => Err(String::from("right value is not an f64")),
+        (Some(x), Some(y)) => {
+            let (x, y) = (x.to_f64(), y.to_f64());
+            if divides && y == 0.0 && float_division == FloatDivision::Error {
+                return Err("Division by zero.".to_string());
+            }
+            Ok(Box::new(match op.token_type {
+                TokenType::Plus => x + y,
+                TokenType::Minus => x - y,
+                TokenType::Star => x * y,
+                TokenType::Slash => x / y,
+                _ => x % y,
+            }))
         }
-    } else {
-        Err(format!("left value is not a numeric type."))
+        _ => Err(operand_error(left_value, op, right_value)),
     }
 }
 
+/**
+ * Compare two numbers. Integers are compared exactly; anything compared with NaN is false.
+ */
 pub fn eval_binary_boolean_op(
     left_value: &Box<dyn Any>,
+    op: &Token,
     right_value: &Box<dyn Any>,
-    op: fn(f64, f64) -> bool,
 ) -> Result<Box<dyn Any>, String> {
-    match (*left_value).downcast_ref::<f64>() {
-        Some(left_f64) => match (*right_value).downcast_ref::<f64>() {
-            Some(right_f64) => Ok(Box::new(op(*left_f64, *right_f64))),
-            None => Err(format!(
-                "failed to convert left value to f64: {:?}",
-                right_value
-            )),
-        },
-        None => Err(format!(
-            "failed to convert left value to f64: {:?}",
-            left_value
-        )),
-    }
+    let ordering = match (Number::from_value(left_value), Number::from_value(right_value)) {
+        (Some(Number::Int(x)), Some(Number::Int(y))) => Some(x.cmp(&y)),
+        (Some(x), Some(y)) => x.to_f64().partial_cmp(&y.to_f64()),
+        _ => return Err(operand_error(left_value, op, right_value)),
+    };
+    let result = match (op.token_type, ordering) {
+        (_, None) => false,
+        (TokenType::Greater, Some(o)) => o == Ordering::Greater,
+        (TokenType::GreaterEqual, Some(o)) => o != Ordering::Less,
+        (TokenType::Less, Some(o)) => o == Ordering::Less,
+        (_, Some(o)) => o != Ordering::Greater,
+    };
+    Ok(Box::new(result))
+}
+
+fn operand_error(left_value: &Box<dyn Any>, op: &Token, right_value: &Box<dyn Any>) -> String {
+    format!("Operands of '{}' must be numbers, not {} and {}.", op.lexeme, type_name(left_value), type_name(right_value))
 }
 
 /**
@@ -161,42 +970,185 @@ pub fn is_truthful(v: Box<dyn Any>) -> bool {
     }
 }
 
+/**
+ * Equality as `==` sees it. Values of different types are never equal, except that integers and floats compare by
+ * numeric value (`1 == 1.0`). NaN isn't equal to anything, itself included, and `0.0 == -0.0`, both as in IEEE 754.
+ */
 // TODO: This should be tested
 pub fn are_equal(left_value: &Box<dyn Any>, right_value: &Box<dyn Any>) -> bool {
-    if (*left_value).type_id() == (*right_value).type_id() {
+    equal_in(left_value, right_value, &mut vec![])
+}
+
+// `comparing` holds the pairs of lists and maps being compared further out. Meeting one of them again means the two
+// sides contain themselves the same way, and nothing found so far tells them apart.
+type Pairs = Vec<(*const (), *const ())>;
+
+fn equal_in(left_value: &Box<dyn Any>, right_value: &Box<dyn Any>, comparing: &mut Pairs) -> bool {
+    match (Number::from_value(left_value), Number::from_value(right_value)) {
+        (Some(Number::Float(l)), Some(Number::Float(r))) => return l == r,
+        (Some(l), Some(r)) => return l.to_exact_i64().is_some() && l.to_exact_i64() == r.to_exact_i64(),
+        _ => (),
+    }
+    if (**left_value).type_id() == (**right_value).type_id() {
         // Without restricting all values to dyn Any + Eq, you have to explicitly compare on each supported type.
-        match (*left_value).downcast_ref::<f64>() {
-            Some(l) => return *l == *(right_value).downcast_ref::<f64>().unwrap(),
-            None => (),
-        };
-        match (*left_value).downcast_ref::<bool>() {
-            Some(l) => return *l == *(right_value).downcast_ref::<bool>().unwrap(),
-            None => (),
-        };
-        match (*left_value).downcast_ref::<String>() {
-            Some(l) => return *l == *(right_value).downcast_ref::<String>().unwrap(),
-            None => (),
-        };
-        match (*left_value).downcast_ref::<Nil>() {
-            Some(l) => true,
-            None => false,
+        if let Some(l) = (*left_value).downcast_ref::<bool>() {
+            return *l == *(right_value).downcast_ref::<bool>().unwrap();
+        }
+        if let Some(l) = (*left_value).downcast_ref::<String>() {
+            return *l == *(right_value).downcast_ref::<String>().unwrap();
+        }
+        // Lists are equal when their elements are
+        if let Some(l) = (*left_value).downcast_ref::<List>() {
+            let r = (*right_value).downcast_ref::<List>().unwrap();
+            let pair = (Rc::as_ptr(l) as *const (), Rc::as_ptr(r) as *const ());
+            if Rc::ptr_eq(l, r) || comparing.contains(&pair) {
+                return true;
+            }
+            comparing.push(pair);
+            let (l, r) = (l.borrow(), r.borrow());
+            let equal = l.len() == r.len() && l.iter().zip(r.iter()).all(|(l, r)| equal_in(l, r, comparing));
+            comparing.pop();
+            return equal;
         }
+        // Maps are equal when they have the same keys with equal values, whatever order the keys were added in
+        if let Some(l) = (*left_value).downcast_ref::<Map>() {
+            let r = (*right_value).downcast_ref::<Map>().unwrap();
+            let pair = (Rc::as_ptr(l) as *const (), Rc::as_ptr(r) as *const ());
+            if Rc::ptr_eq(l, r) || comparing.contains(&pair) {
+                return true;
+            }
+            comparing.push(pair);
+            let (l, r) = (l.borrow(), r.borrow());
+            let equal = l.len() == r.len()
+                && l.iter().all(|(key, l)| r.get(key).ok().flatten().is_some_and(|r| equal_in(l, r, comparing)));
+            comparing.pop();
+            return equal;
+        }
+        (*left_value).is::<Nil>()
     } else {
         false
     }
 }
 
-pub fn to_string(val: Box<dyn Any>) -> Result<String, String> {
+/**
+ * How floats are printed. They always show a fractional part or an exponent, so they can be told apart from integers
+ * (`3.0`, `-0.0`, `1e300`), and the special values are written `nan`, `inf` and `-inf`.
+ */
+pub fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        "nan".to_string()
+    } else if f.is_infinite() {
+        if f > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        format!("{:?}", f)
+    }
+}
+
+/**
+ * Make a copy of a runtime value. Only the value types the interpreter knows about can be copied; callables, lists and
+ * maps are reference-counted, so copying them is just a refcount bump.
+ */
+pub fn clone_value(val: &Box<dyn Any>) -> Box<dyn Any> {
+    if let Some(v) = (*val).downcast_ref::<String>() {
+        Box::new(v.clone())
+    } else if let Some(v) = (*val).downcast_ref::<i64>() {
+        Box::new(*v)
+    } else if let Some(v) = (*val).downcast_ref::<f64>() {
+        Box::new(*v)
+    } else if let Some(v) = (*val).downcast_ref::<bool>() {
+        Box::new(*v)
+    } else if let Some(v) = (*val).downcast_ref::<Rc<dyn Callable>>() {
+        Box::new(Rc::clone(v))
+    } else if let Some(v) = (*val).downcast_ref::<ErrorObject>() {
+        Box::new(v.clone())
+    } else if let Some(v) = (*val).downcast_ref::<List>() {
+        Box::new(Rc::clone(v))
+    } else if let Some(v) = (*val).downcast_ref::<Map>() {
+        Box::new(Rc::clone(v))
+    } else {
+        Box::new(Nil {})
+    }
+}
+
+/**
+ * The user-facing name of a runtime value's type.
+ */
+pub fn type_name(val: &Box<dyn Any>) -> &'static str {
     if (*val).is::<String>() {
-        Ok(String::from((*val).downcast_ref::<String>().unwrap().as_str()))
-    } else if (*val).is::<i64>() {
-        Ok(format!("{}", (*val).downcast_ref::<i64>().unwrap()))
-    } else if (*val).is::<f64>() {
-        Ok(format!("{}", (*val).downcast_ref::<f64>().unwrap()))
+        "string"
+    } else if (*val).is::<i64>() || (*val).is::<f64>() {
+        "number"
     } else if (*val).is::<bool>() {
-        Ok(format!("{}", (*val).downcast_ref::<bool>().unwrap()))
+        "bool"
+    } else if (*val).is::<Rc<dyn Callable>>() {
+        "function"
+    } else if (*val).is::<ErrorObject>() {
+        "error"
+    } else if (*val).is::<List>() {
+        "list"
+    } else if (*val).is::<Map>() {
+        "map"
     } else {
-        Err(String::from("cannot parse type into string"))
+        "nil"
     }
+}
 
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax_tree::visit_any;
+
+    fn value<T: Any>(v: T) -> Box<dyn Any> {
+        Box::new(v)
+    }
+
+    // A list holding `first` and then itself
+    fn cyclic_list(first: i64) -> List {
+        let list: List = Rc::new(RefCell::new(vec![value(first)]));
+        list.borrow_mut().push(value(list.clone()));
+        list
+    }
+
+    #[test]
+    fn lists_containing_themselves_print_and_compare() {
+        let list = cyclic_list(1);
+        assert_eq!(visit_any(value(list.clone())), "[1, [...]]");
+        assert!(are_equal(&value(list.clone()), &value(list)));
+        assert!(are_equal(&value(cyclic_list(1)), &value(cyclic_list(1))));
+        assert!(!are_equal(&value(cyclic_list(1)), &value(cyclic_list(2))));
+    }
+
+    #[test]
+    fn ints_and_floats_are_equal_only_when_exactly_equal() {
+        let (big, close) = (value(9007199254740993i64), value(9007199254740992.0f64));
+        assert!(!are_equal(&big, &close));
+        assert!(are_equal(&value(9007199254740992i64), &close));
+        assert!(!are_equal(&value(i64::MAX), &value(9223372036854775807.0f64)));
+        assert!(are_equal(&value(0i64), &value(-0.0f64)));
+        assert!(!are_equal(&value(1i64), &value(1.5f64)));
+
+        let map: Map = Rc::new(RefCell::new(Entries::default()));
+        map.borrow_mut().insert(&big, value(String::from("a"))).unwrap();
+        map.borrow_mut().insert(&value(9007199254740992i64), value(String::from("b"))).unwrap();
+        assert_eq!(visit_any(clone_value(map.borrow().get(&close).unwrap().unwrap())), "b");
+        map.borrow_mut().insert(&value(-0.0f64), value(String::from("zero"))).unwrap();
+        assert_eq!(visit_any(clone_value(map.borrow().get(&value(0i64)).unwrap().unwrap())), "zero");
+    }
+
+    // A map holding "self" mapped to itself
+    fn cyclic_map() -> Map {
+        let map: Map = Rc::new(RefCell::new(Entries::default()));
+        map.borrow_mut().insert(&value(String::from("self")), value(map.clone())).unwrap();
+        map
+    }
+
+    #[test]
+    fn maps_containing_themselves_print_and_compare() {
+        let map = cyclic_map();
+        assert_eq!(visit_any(value(map.clone())), "{\"self\": {...}}");
+        assert!(are_equal(&value(map.clone()), &value(map)));
+        assert!(are_equal(&value(cyclic_map()), &value(cyclic_map())));
+        let outer: List = Rc::new(RefCell::new(vec![value(cyclic_map())]));
+        assert_eq!(visit_any(value(outer)), "[{\"self\": {...}}]");
+    }
+}
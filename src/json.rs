@@ -0,0 +1,280 @@
+use std::fmt;
+
+/**
+ * A minimal JSON value, enough for rslox to talk to external tools without pulling in any dependencies. Object keys
+ * keep their insertion order so output is stable.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    // Numbers written without a fraction or exponent that fit in an i64 are kept exactly, rather than rounded to the
+    // nearest f64
+    Int(i64),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /**
+     * Build an object from `(key, value)` pairs.
+     */
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    pub fn string(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+
+    /**
+     * Look up `key` in an object. Returns `None` for missing keys and for values that aren't objects.
+     */
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            Json::Int(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /**
+     * Parse a complete JSON document.
+     */
+    pub fn parse(source: &str) -> Result<Json, String> {
+        let mut p = JsonParser { chars: source.chars().collect(), cur: 0 };
+        let value = parse_value(&mut p)?;
+        skip_whitespace(&mut p);
+        if p.cur < p.chars.len() {
+            return Err(format!("unexpected trailing characters at offset {}", p.cur));
+        }
+        Ok(value)
+    }
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    cur: usize,
+}
+
+fn skip_whitespace(p: &mut JsonParser) {
+    while p.cur < p.chars.len() && p.chars[p.cur].is_whitespace() {
+        p.cur += 1;
+    }
+}
+
+fn expect(p: &mut JsonParser, c: char) -> Result<(), String> {
+    skip_whitespace(p);
+    if p.chars.get(p.cur) == Some(&c) {
+        p.cur += 1;
+        Ok(())
+    } else {
+        Err(format!("expected '{}' at offset {}", c, p.cur))
+    }
+}
+
+fn parse_value(p: &mut JsonParser) -> Result<Json, String> {
+    skip_whitespace(p);
+    match p.chars.get(p.cur) {
+        None => Err("unexpected end of JSON input".to_string()),
+        Some('{') => {
+            p.cur += 1;
+            let mut fields = vec![];
+            skip_whitespace(p);
+            if p.chars.get(p.cur) == Some(&'}') {
+                p.cur += 1;
+                return Ok(Json::Object(fields));
+            }
+            loop {
+                skip_whitespace(p);
+                let key = parse_string(p)?;
+                expect(p, ':')?;
+                fields.push((key, parse_value(p)?));
+                skip_whitespace(p);
+                match p.chars.get(p.cur) {
+                    Some(',') => p.cur += 1,
+                    Some('}') => {
+                        p.cur += 1;
+                        return Ok(Json::Object(fields));
+                    }
+                    _ => return Err(format!("expected ',' or '}}' at offset {}", p.cur)),
+                }
+            }
+        }
+        Some('[') => {
+            p.cur += 1;
+            let mut items = vec![];
+            skip_whitespace(p);
+            if p.chars.get(p.cur) == Some(&']') {
+                p.cur += 1;
+                return Ok(Json::Array(items));
+            }
+            loop {
+                items.push(parse_value(p)?);
+                skip_whitespace(p);
+                match p.chars.get(p.cur) {
+                    Some(',') => p.cur += 1,
+                    Some(']') => {
+                        p.cur += 1;
+                        return Ok(Json::Array(items));
+                    }
+                    _ => return Err(format!("expected ',' or ']' at offset {}", p.cur)),
+                }
+            }
+        }
+        Some('"') => Ok(Json::String(parse_string(p)?)),
+        Some(_) => {
+            // Everything else is a bare word: a number or one of the keywords
+            let start = p.cur;
+            while p.cur < p.chars.len() && !",]}: \t\r\n".contains(p.chars[p.cur]) {
+                p.cur += 1;
+            }
+            let word: String = p.chars[start..p.cur].iter().collect();
+            match word.as_str() {
+                "null" => Ok(Json::Null),
+                "true" => Ok(Json::Bool(true)),
+                "false" => Ok(Json::Bool(false)),
+                w if w.chars().all(|c| c == '-' || c.is_ascii_digit()) && w.parse::<i64>().is_ok() => {
+                    Ok(Json::Int(w.parse().unwrap()))
+                }
+                w => w
+                    .parse::<f64>()
+                    .map(Json::Number)
+                    .map_err(|_| format!("unexpected `{}` at offset {}", w, start)),
+            }
+        }
+    }
+}
+
+fn parse_string(p: &mut JsonParser) -> Result<String, String> {
+    if p.chars.get(p.cur) != Some(&'"') {
+        return Err(format!("expected a string at offset {}", p.cur));
+    }
+    p.cur += 1;
+    let mut s = String::new();
+    loop {
+        match p.chars.get(p.cur) {
+            None => return Err("unterminated string".to_string()),
+            Some('"') => {
+                p.cur += 1;
+                return Ok(s);
+            }
+            Some('\\') => {
+                p.cur += 1;
+                match p.chars.get(p.cur) {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => {
+                        let hex: String = p.chars.iter().skip(p.cur + 1).take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16)
+                            .map_err(|_| format!("invalid unicode escape at offset {}", p.cur))?;
+                        // Surrogate pairs aren't combined; anything unrepresentable becomes U+FFFD
+                        s.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                        p.cur += 4;
+                    }
+                    _ => return Err(format!("invalid escape at offset {}", p.cur)),
+                }
+                p.cur += 1;
+            }
+            Some(c) => {
+                s.push(*c);
+                p.cur += 1;
+            }
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            // JSON has no representation for NaN or the infinities
+            Json::Number(n) if !n.is_finite() => write!(f, "null"),
+            // Always with a fraction or an exponent, so it reads back as a float rather than an integer
+            Json::Number(n) => write!(f, "{:?}", n),
+            Json::Int(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
@@ -1,77 +1,390 @@
 use std::process::exit;
+use std::thread;
 use std::{
-    any,
-    fs::File,
-    io::{prelude::*, stdin, stdout},
-    path::Path,
+    fs::{self, File},
+    io::{prelude::*, stdin, stdout, ErrorKind},
+    path::{Path, PathBuf},
 };
 use scanner::*;
 use syntax_tree::*;
 use parser::*;
 
-use crate::interpreter::interpret;
+use crate::interpreter::{execute, interpret, type_name, Interpreter, RuntimeError};
+pub use crate::interpreter::FloatDivision;
+pub use crate::doc::DocFormat;
+use crate::resolver::resolve;
+use crate::serialize::*;
 
 mod scanner;
-mod syntax_tree;
+pub mod syntax_tree;
 mod parser;
 mod interpreter;
+mod environment;
+mod resolver;
+pub mod json;
+mod serialize;
+mod formatter;
+mod linter;
+mod lsp;
+mod debugger;
+mod dap;
+mod doc;
 
 pub enum Either<L, R> {
     Left(L),
     Right(R),
 }
 
-pub fn print_ast() {
-    let expr = Expr::Binary(
-        Box::new(Expr::Unary(
-            Box::new(Expr::IntLiteral(123 as i64)),
-            Token::new(TokenType::Minus, "-".to_string(), Box::new(""), 1),
-        )),
-        Token::new(TokenType::Star, "*".to_string(), Box::new(""), 1),
-        Box::new(Expr::Grouping(
-            Box::new(Expr::FloatLiteral(45.67)),
-        ))
-    );
-    println!("{}", visit(expr));
+/**
+ * The ways a program can be written down: as rslox source, or as a serialized syntax tree.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgramFormat {
+    Source,
+    Json,
+    SExpr,
+}
+
+/*
+ * Exit codes, following sysexits.h
+ */
+pub const EX_USAGE: i32 = 64;
+pub const EX_DATAERR: i32 = 65;
+pub const EX_NOINPUT: i32 = 66;
+pub const EX_SOFTWARE: i32 = 70;
+pub const EX_CANTCREAT: i32 = 73;
+
+// Enough for calls to nest as deep as the interpreter allows, even in a debug build
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+/**
+ * Run `f` on a thread with a stack big enough for deeply recursive programs, which the main thread's isn't, and return
+ * what it returns.
+ */
+pub fn with_large_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    let runner = thread::Builder::new().stack_size(STACK_SIZE).spawn(f).expect("couldn't start the interpreter thread");
+    runner.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
 }
 
 /**
  * Runs a REPL
  */
-pub fn run_prompt() {
+pub fn run_prompt(color: bool, float_division: FloatDivision) {
     let mut rslox = RsLox::new();
+    rslox.color = color;
+    rslox.interpreter.float_division = float_division;
     loop {
         print!("> ");
         let _ = stdout().flush();
         let mut line = String::new();
         stdin().read_line(&mut line).unwrap();
         if line.is_empty() {
-            println!("");
+            println!();
             break;
         }
-        rslox.run(line);
+        rslox.run_line(line);
         rslox.had_error = false;
+        rslox.had_runtime_error = false;
     }
 }
 
 /**
  * Reads from a file with rslox statements in it
  */
-pub fn run_file(file_path: &String) -> () {
-    let mut rslox = RsLox::new();
-    let path = Path::new(file_path);
-    let mut file = File::open(path).unwrap();
+pub fn run_file(file_path: &str) {
+    match read_source(file_path) {
+        Ok(program) => run_source(program, ProgramFormat::Source, true, FloatDivision::Ieee),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            exit(EX_NOINPUT);
+        }
+    }
+}
+
+/**
+ * Read a program from `file_path`, or from stdin if the path is `-`.
+ */
+pub fn read_source(file_path: &str) -> Result<String, String> {
     let mut program = String::new();
-    file.read_to_string(&mut program).unwrap();
+    if file_path == "-" {
+        stdin()
+            .read_to_string(&mut program)
+            .map_err(|e| format!("could not read from stdin: {}", e))?;
+    } else {
+        let path = Path::new(file_path);
+        let mut file = File::open(path).map_err(|e| format!("could not open {}: {}", file_path, e))?;
+        file.read_to_string(&mut program)
+            .map_err(|e| format!("could not read {}: {}", file_path, e))?;
+    }
+    Ok(program)
+}
+
+/**
+ * Run a whole program, exiting with the appropriate status if it fails to compile or run.
+ */
+pub fn run_source(program: String, from: ProgramFormat, color: bool, float_division: FloatDivision) {
+    let mut rslox = RsLox::new();
+    rslox.color = color;
+    rslox.interpreter.float_division = float_division;
+    if let Some(statements) = rslox.load(program, from) {
+        rslox.execute(&statements);
+    }
+    rslox.exit_on_error();
+}
+
+/**
+ * Parse and resolve a program without running it.
+ */
+pub fn check_source(program: String, from: ProgramFormat, color: bool) {
+    let mut rslox = RsLox::new();
+    rslox.color = color;
+    rslox.load(program, from);
+    rslox.exit_on_error();
+}
+
+/**
+ * Print each token in a program, either as a table or as one JSON object per line.
+ */
+pub fn print_tokens(program: String, color: bool, json: bool) {
+    let mut rslox = RsLox::new();
+    rslox.color = color;
+    if let Some(tokens) = rslox.scan(program) {
+        let mut out = stdout().lock();
+        let mut written = if json { Ok(()) } else { writeln!(out, "{}", TOKEN_TABLE_HEADER) };
+        for token in &tokens {
+            if written.is_err() {
+                break;
+            }
+            let line = if json { token.to_json().to_string() } else { token.to_table_row() };
+            written = writeln!(out, "{}", line);
+        }
+        // Stopping early is fine when the dump is piped into something like `head` that has seen enough
+        match written {
+            Err(e) if e.kind() != ErrorKind::BrokenPipe => {
+                eprintln!("Couldn't write tokens: {}", e);
+                exit(EX_SOFTWARE);
+            }
+            _ => (),
+        }
+    }
+    rslox.exit_on_error();
+}
+
+/**
+ * Print the syntax tree of a program, either as JSON or as S-expressions with one top-level statement per line.
+ */
+pub fn print_syntax_tree(program: String, from: ProgramFormat, to: ProgramFormat, color: bool) {
+    let mut rslox = RsLox::new();
+    rslox.color = color;
+    if let Some(statements) = rslox.load(program, from) {
+        match to {
+            ProgramFormat::Json => println!("{}", program_to_json(&statements)),
+            ProgramFormat::SExpr => println!("{}", program_to_sexpr(&statements)),
+            ProgramFormat::Source => panic!("can't print a syntax tree as source"),
+        }
+    }
+    rslox.exit_on_error();
+}
+
+/**
+ * What `format_source` should do with the formatted program.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormatMode {
+    /// Print it to stdout
+    Print,
+    /// Print nothing, but exit with status 1 if it differs from the input
+    Check,
+    /// Overwrite the script with it
+    Write,
+}
+
+/**
+ * Reformat a program. `path` names the script for messages and is the file overwritten in `FormatMode::Write`.
+ */
+pub fn format_source(program: String, path: Option<&str>, mode: FormatMode, color: bool) {
+    let mut rslox = RsLox::new();
+    rslox.color = color;
+    let formatted = rslox.scan(program.clone()).and_then(|tokens| {
+        let comments = tokens.iter().flat_map(|t| t.comments.iter().cloned()).collect();
+        let statements = rslox.parse_tokens(tokens.into_iter().map(Ok), &program, false)?;
+        Some(formatter::format(&program, comments, &statements))
+    });
+    rslox.exit_on_error();
+    let formatted = formatted.unwrap();
+    let name = path.unwrap_or("<input>");
+    match mode {
+        FormatMode::Print => print!("{}", formatted),
+        FormatMode::Check => {
+            if formatted != program {
+                println!("would reformat {}", name);
+                exit(1);
+            }
+        }
+        FormatMode::Write => {
+            if formatted != program {
+                if let Err(e) = File::create(name).and_then(|mut f| f.write_all(formatted.as_bytes())) {
+                    eprintln!("Couldn't write {}: {}", name, e);
+                    exit(EX_CANTCREAT);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Lint a program, printing a warning for each problem found. Exits with status 1 if there were any.
+ */
+pub fn lint_source(program: String, color: bool) {
+    let mut rslox = RsLox::new();
+    rslox.color = color;
+    let lints = rslox.scan(program.clone()).and_then(|tokens| {
+        let comments: Vec<Comment> = tokens.iter().flat_map(|t| t.comments.iter().cloned()).collect();
+        let statements = rslox.parse_tokens(tokens.into_iter().map(Ok), &program, false)?;
+        let statements = rslox.resolve(statements)?;
+        Some(linter::lint(&statements, &comments))
+    });
+    rslox.exit_on_error();
+    let lints = lints.unwrap();
+    for lint in &lints {
+        rslox.print_warning(format!("[line {}] warning[{}]: {}", lint.line, lint.rule, lint.message));
+    }
+    if !lints.is_empty() {
+        exit(1);
+    }
+}
+
+/**
+ * Print the ID and description of every lint rule.
+ */
+pub fn print_lint_rules() {
+    for (id, description) in linter::RULES.iter() {
+        println!("{:<20}{}", id, description);
+    }
+}
 
-    rslox.run(program);
-    if rslox.had_error {
-        exit(65);
+/**
+ * Run the language server on stdin and stdout until the client exits, returning the status to exit with.
+ */
+pub fn run_language_server() -> i32 {
+    match lsp::serve(stdin().lock(), stdout().lock()) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("rslox-lsp: {}", e);
+            EX_SOFTWARE
+        }
     }
 }
 
-pub fn err(line: u32, msg: &str) -> Result<(), String> {
-    Err(format!("{}: `{}`", line, msg))
+/**
+ * Debug a program from an interactive prompt.
+ */
+pub fn debug_source(program: String, color: bool) {
+    let mut rslox = RsLox::new();
+    rslox.color = color;
+    let statements = rslox.parse(program.clone(), false);
+    rslox.exit_on_error();
+    debugger::debug(&statements.unwrap(), &program, color);
+}
+
+/**
+ * Write reference pages for a script, or for every `.rslox` script in a directory and the directories inside it, to
+ * the directory `out`.
+ */
+pub fn document(path: &str, out: &str, format: DocFormat, color: bool) {
+    let root = Path::new(path);
+    let scripts = if root.is_dir() { find_scripts(root) } else { Ok(vec![root.to_path_buf()]) };
+    let scripts = match scripts {
+        Ok(scripts) if scripts.is_empty() => {
+            eprintln!("no .rslox scripts in {}", path);
+            exit(EX_NOINPUT);
+        }
+        Ok(scripts) => scripts,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            exit(EX_NOINPUT);
+        }
+    };
+    // Paths in the pages are relative to the directory being documented, or the one a lone script is in
+    let base = if root.is_dir() { root } else { root.parent().unwrap_or_else(|| Path::new("")) };
+
+    let mut rslox = RsLox::new();
+    rslox.color = color;
+    let mut pages = vec![];
+    for script in &scripts {
+        let name = script.strip_prefix(base).unwrap_or(script).to_string_lossy().replace('\\', "/");
+        let source = match read_source(&script.to_string_lossy()) {
+            Ok(source) => source,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                exit(EX_NOINPUT);
+            }
+        };
+        match rslox.parse(source.clone(), false) {
+            Some(statements) => pages.push(doc::Page { path: name, source, items: doc::items(&statements) }),
+            None => rslox.print_error(format!("in {}", name)),
+        }
+    }
+    rslox.exit_on_error();
+
+    let written = fs::create_dir_all(out).and_then(|_| {
+        let source_dir = relative_path(&fs::canonicalize(out)?, &fs::canonicalize(base)?);
+        for (file, contents) in doc::render(&pages, format, &source_dir) {
+            let file = Path::new(out).join(file);
+            if let Some(dir) = file.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(file, contents)?;
+        }
+        Ok(())
+    });
+    if let Err(e) = written {
+        eprintln!("Couldn't write documentation to {}: {}", out, e);
+        exit(EX_CANTCREAT);
+    }
+}
+
+// Every `.rslox` file under `dir`, sorted so pages come out in a stable order
+fn find_scripts(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("could not read {}: {}", dir.display(), e))?;
+    let mut scripts = vec![];
+    for entry in entries {
+        let path = entry.map_err(|e| format!("could not read {}: {}", dir.display(), e))?.path();
+        if path.is_dir() {
+            scripts.extend(find_scripts(&path)?);
+        } else if path.extension().is_some_and(|ext| ext == "rslox") {
+            scripts.push(path);
+        }
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+// The path to `to` from the directory `from`, with `/` separators. Both must be absolute.
+fn relative_path(from: &Path, to: &Path) -> String {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts: Vec<String> = vec!["..".to_string(); from.len() - common];
+    parts.extend(to[common..].iter().map(|c| c.as_os_str().to_string_lossy().into_owned()));
+    if parts.is_empty() {
+        ".".to_string()
+    } else {
+        parts.join("/")
+    }
+}
+
+/**
+ * Run the debug adapter on stdin and stdout until the client disconnects, returning the status to exit with.
+ */
+pub fn run_debug_adapter() -> i32 {
+    match dap::serve(stdin().lock(), stdout().lock()) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("rslox-dap: {}", e);
+            EX_SOFTWARE
+        }
+    }
 }
 
 /**
@@ -79,36 +392,145 @@ pub fn err(line: u32, msg: &str) -> Result<(), String> {
  */
 pub struct RsLox {
     had_error: bool,
+    had_runtime_error: bool,
+    // The program being run, for showing where runtime errors happened. REPL lines aren't kept, since functions
+    // declared on one line can fail on another.
+    source: Option<String>,
+    /// Whether diagnostics should be highlighted with ANSI colors
+    pub color: bool,
+    interpreter: Interpreter,
 }
 impl RsLox {
     pub fn new() -> Self {
-        RsLox { had_error: false }
+        RsLox {
+            had_error: false,
+            had_runtime_error: false,
+            source: None,
+            color: false,
+            interpreter: Interpreter::new(),
+        }
+    }
+
+    /**
+     * Execute each statement in turn, stopping at the first runtime error.
+     */
+    fn execute(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            if let Err(msg) = execute(&mut self.interpreter, statement) {
+                self.runtime_error(msg);
+                return;
+            }
+        }
     }
 
     /**
-     * Evaluate a string of tokens and execute them.
+     * Evaluate a single line of REPL input. Top-level expression statements echo their value and its type; everything
+     * else runs silently. Interpreter state is kept between lines.
      */
-    fn run(&mut self, program: String) {
-        let mut scanner = scanner::Scanner::new(program);
-        let res: Result<(), String> = scanner.scan_tokens();
-        if res.is_err() {
-            self.error(scanner.line, res.err().unwrap());
-            exit(1);
-        }
-        // Print each token
-        // for token in &scanner.tokens {
-        //     println!("{:?}", token);
-        // }
-        let mut p: Parser = Parser::new(scanner.tokens);
-        match expression(&mut p) {
-            Ok(expr) => {
-                match interpret(expr) {
-                    // TODO: why are result values string?
-                    Ok(v) => println!("{}", visit_any(v)), //println!(": {:?}", visit_any(v)),
-                    Err(msg) => println!("{}", msg)
+    fn run_line(&mut self, line: String) {
+        if let Some(statements) = self.parse(line, true) {
+            for statement in &statements {
+                let res = match statement {
+                    Stmt::Expression(e, _) => interpret(&mut self.interpreter, e).map(|v| {
+                        let ty = type_name(&v);
+                        if ty == "string" {
+                            println!("{:?} : {}", visit_any(v), ty);
+                        } else {
+                            println!("{} : {}", visit_any(v), ty);
+                        }
+                    }),
+                    _ => execute(&mut self.interpreter, statement).map(|_| ()),
+                };
+                if let Err(msg) = res {
+                    self.runtime_error(msg);
+                    return;
                 }
             }
-            Err(e) => println!("{}", e),
+        }
+    }
+
+    /**
+     * Scan all of a program's tokens at once, for the commands that need them all, or the comments attached to them.
+     */
+    fn scan(&mut self, source: String) -> Option<Vec<Token>> {
+        match scanner::Scanner::new(source.clone()).collect() {
+            Ok(tokens) => Some(tokens),
+            Err(e) => {
+                self.compile_error(e.report(&source));
+                None
+            }
+        }
+    }
+
+    /**
+     * Scan, parse and resolve a program. Returns `None` (after reporting why) if any of those steps fail.
+     */
+    fn parse(&mut self, source: String, repl: bool) -> Option<Vec<Stmt>> {
+        self.source = if repl { None } else { Some(source.clone()) };
+        let tokens = scanner::Scanner::new(source.clone());
+        let statements = self.parse_tokens(tokens, &source, repl)?;
+        self.resolve(statements)
+    }
+
+    /**
+     * Parse a program from its tokens, which are scanned as the parser gets to them. `source` is used to show where
+     * a scan error happened.
+     */
+    fn parse_tokens(
+        &mut self,
+        tokens: impl Iterator<Item = Result<Token, ScanError>> + 'static,
+        source: &str,
+        repl: bool,
+    ) -> Option<Vec<Stmt>> {
+        let mut p: Parser = Parser::new(tokens);
+        p.repl = repl;
+        match program(&mut p) {
+            Ok(statements) => Some(statements),
+            Err(e) => {
+                match p.scan_error() {
+                    Some(scan_error) => self.compile_error(scan_error.report(source)),
+                    None => self.compile_error(e),
+                }
+                None
+            }
+        }
+    }
+
+    /**
+     * Load a program written in any of the supported formats, resolving it before it's returned.
+     */
+    fn load(&mut self, program: String, from: ProgramFormat) -> Option<Vec<Stmt>> {
+        let statements = match from {
+            ProgramFormat::Source => return self.parse(program, false),
+            ProgramFormat::Json => json::Json::parse(&program).and_then(|json| program_from_json(&json)),
+            ProgramFormat::SExpr => program_from_sexpr(&program),
+        };
+        match statements {
+            Ok(statements) => self.resolve(statements),
+            Err(e) => {
+                self.compile_error(format!("Error reading syntax tree: {}", e));
+                None
+            }
+        }
+    }
+
+    fn resolve(&mut self, statements: Vec<Stmt>) -> Option<Vec<Stmt>> {
+        match resolve(&statements) {
+            Ok(()) => Some(statements),
+            Err(errors) => {
+                for e in errors {
+                    self.compile_error(e);
+                }
+                None
+            }
+        }
+    }
+
+    fn exit_on_error(&self) {
+        if self.had_error {
+            exit(EX_DATAERR);
+        } else if self.had_runtime_error {
+            exit(EX_SOFTWARE);
         }
     }
 
@@ -121,7 +543,38 @@ impl RsLox {
     }
 
     fn report(&mut self, line: u32, where_at: String, message: String) {
-        eprintln!("[line {}] Error {}: {}", line, where_at, message);
+        self.print_error(format!("[line {}] Error {}: {}", line, where_at, message));
         self.had_error = true;
     }
-}
\ No newline at end of file
+
+    fn compile_error(&mut self, message: String) {
+        self.print_error(message);
+        self.had_error = true;
+    }
+
+    fn runtime_error(&mut self, error: RuntimeError) {
+        self.print_error(error.report(self.source.as_deref()));
+        self.had_runtime_error = true;
+    }
+
+    fn print_warning(&self, message: String) {
+        if self.color {
+            eprintln!("\x1b[33m{}\x1b[0m", message);
+        } else {
+            eprintln!("{}", message);
+        }
+    }
+
+    fn print_error(&self, message: String) {
+        if self.color {
+            eprintln!("\x1b[31m{}\x1b[0m", message);
+        } else {
+            eprintln!("{}", message);
+        }
+    }
+}
+impl Default for RsLox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,280 @@
+use crate::scanner::{Comment, Token, TokenType};
+use crate::syntax_tree::{walk_expr, walk_stmt, Expr, Stmt, Visitor};
+
+/**
+ * Every lint rule, by ID. Any of them can be turned off for a whole file with a comment such as
+ * `// lint: allow unused-variable, shadowing`.
+ */
+pub const RULES: [(&str, &str); 6] = [
+    ("unused-variable", "a local variable is declared but never read"),
+    ("unreachable-code", "a statement follows a `return` or `throw` in the same block"),
+    ("shadowing", "a declaration hides a variable of the same name in an enclosing scope"),
+    ("nil-comparison", "`==` or `!=` compares `nil` with something that can never be `nil`"),
+    ("self-assignment", "a variable is assigned to itself"),
+    ("constant-condition", "an `if` or `while` condition doesn't depend on anything"),
+];
+
+const DIRECTIVE: &str = "lint: allow";
+
+/**
+ * A single warning reported by the linter.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    pub rule: &'static str,
+    pub line: u32,
+    pub message: String,
+}
+
+/**
+ * Style and correctness checks for programs that have already passed the resolver. Unlike resolver errors, lints
+ * never stop a program from running.
+ */
+pub struct Linter {
+    // Innermost scope last. The global scope is at the bottom, and is only used for detecting shadowing.
+    scopes: Vec<Vec<Local>>,
+    allowed: Vec<String>,
+    lints: Vec<Lint>,
+}
+
+struct Local {
+    name: Token,
+    used: bool,
+}
+
+/**
+ * Lint a resolved program. `comments` are searched for directives turning rules off; a directive naming a rule that
+ * doesn't exist is reported as a lint of its own.
+ */
+pub fn lint(statements: &[Stmt], comments: &[Comment]) -> Vec<Lint> {
+    let mut l = Linter { scopes: vec![vec![]], allowed: vec![], lints: vec![] };
+    for comment in comments {
+        let text = comment.text.trim_start_matches('/').trim_start_matches('*').trim_end_matches("*/").trim();
+        if let Some(rules) = text.strip_prefix(DIRECTIVE) {
+            for rule in rules.split(|c: char| c == ',' || c.is_whitespace()).filter(|r| !r.is_empty()) {
+                if RULES.iter().any(|(id, _)| *id == rule) {
+                    l.allowed.push(rule.to_string());
+                } else {
+                    l.lints.push(Lint {
+                        rule: "unknown-rule",
+                        line: comment.span.line,
+                        message: format!("no lint rule called '{}'", rule),
+                    });
+                }
+            }
+        }
+    }
+    for statement in statements {
+        l.visit_stmt(statement);
+    }
+    l.lints.sort_by_key(|lint| lint.line);
+    l.lints
+}
+
+impl Visitor for Linter {
+    fn visit_stmt(&mut self, s: &Stmt) {
+        match s {
+            Stmt::Var(name, initializer, ..) => {
+                if let Some(e) = initializer {
+                    self.visit_expr(e);
+                }
+                declare(self, name);
+            }
+            Stmt::Block(statements, _) => {
+                self.scopes.push(vec![]);
+                unreachable_after_return(self, statements);
+                walk_stmt(self, s);
+                end_scope(self);
+            }
+            Stmt::For(..) => {
+                self.scopes.push(vec![]);
+                walk_stmt(self, s);
+                end_scope(self);
+            }
+            Stmt::Try(body, catch, finally, _) => {
+                self.visit_stmt(body);
+                if let Some((name, handler)) = catch {
+                    // Like parameters, a caught value doesn't have to be used
+                    self.scopes.push(vec![]);
+                    declare(self, name);
+                    mark_used(self, &name.lexeme);
+                    self.visit_stmt(handler);
+                    end_scope(self);
+                }
+                if let Some(finally) = finally {
+                    self.visit_stmt(finally);
+                }
+            }
+            Stmt::If(condition, ..) => {
+                constant_condition(self, condition, "if");
+                walk_stmt(self, s);
+            }
+            Stmt::While(condition, ..) => {
+                // `while (true)` is the usual way to write a loop that only ends with a `return`
+                if !matches!(condition, Expr::BoolLiteral(true, _)) {
+                    constant_condition(self, condition, "while");
+                }
+                walk_stmt(self, s);
+            }
+            Stmt::Function(decl) => {
+                declare(self, &decl.name);
+                // Functions are values that are easy to declare for later use, so never report them as unused
+                mark_used(self, &decl.name.lexeme);
+                self.scopes.push(vec![]);
+                for param in &decl.params {
+                    declare(self, param);
+                    mark_used(self, &param.lexeme);
+                }
+                unreachable_after_return(self, &decl.body);
+                walk_stmt(self, s);
+                end_scope(self);
+            }
+            _ => walk_stmt(self, s),
+        }
+    }
+
+    fn visit_expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Variable(name) => mark_used(self, &name.lexeme),
+            Expr::Assign(name, value) => {
+                if let Expr::Variable(source) = &**value {
+                    if source.lexeme == name.lexeme {
+                        report(self, "self-assignment", name.line, format!("'{}' is assigned to itself", name.lexeme));
+                    }
+                }
+            }
+            Expr::Binary(left, op, right) if matches!(op.token_type, TokenType::EqualEqual | TokenType::BangEqual) => {
+                let other = match (strip_grouping(left), strip_grouping(right)) {
+                    (Expr::NilLiteral(_), other) | (other, Expr::NilLiteral(_)) => Some(other),
+                    _ => None,
+                };
+                if let Some(other) = other {
+                    if is_never_nil(other) {
+                        let always = if op.token_type == TokenType::EqualEqual { "false" } else { "true" };
+                        report(self, "nil-comparison", op.line, format!("comparison with nil is always {}", always));
+                    }
+                }
+            }
+            Expr::Lambda(decl, _) => {
+                self.scopes.push(vec![]);
+                for param in &decl.params {
+                    declare(self, param);
+                    mark_used(self, &param.lexeme);
+                }
+                unreachable_after_return(self, &decl.body);
+                walk_expr(self, e);
+                end_scope(self);
+                return;
+            }
+            _ => (),
+        }
+        walk_expr(self, e);
+    }
+}
+
+fn declare(l: &mut Linter, name: &Token) {
+    // The resolver has already rejected redeclarations within a scope, so any match further out is shadowing.
+    // Redeclaring a global is allowed, and isn't shadowing either.
+    let depth = l.scopes.len() - 1;
+    if depth > 0 {
+        let shadowed = l.scopes[..depth].iter().rev().flatten().find(|local| local.name.lexeme == name.lexeme);
+        if let Some(shadowed) = shadowed {
+            let message = format!("'{}' shadows the variable declared on line {}", name.lexeme, shadowed.name.line);
+            report(l, "shadowing", name.line, message);
+        }
+    }
+    let scope = l.scopes.last_mut().unwrap();
+    scope.retain(|local| local.name.lexeme != name.lexeme);
+    scope.push(Local { name: name.clone(), used: false });
+}
+
+fn mark_used(l: &mut Linter, name: &str) {
+    if let Some(local) = l.scopes.iter_mut().rev().flatten().find(|local| local.name.lexeme == name) {
+        local.used = true;
+    }
+}
+
+fn end_scope(l: &mut Linter) {
+    for local in l.scopes.pop().unwrap() {
+        // A leading underscore marks a variable as deliberately unused
+        if !local.used && !local.name.lexeme.starts_with('_') {
+            let message = format!("variable '{}' is never used", local.name.lexeme);
+            report(l, "unused-variable", local.name.line, message);
+        }
+    }
+}
+
+fn unreachable_after_return(l: &mut Linter, statements: &[Stmt]) {
+    if let Some(i) = statements.iter().position(|s| matches!(s, Stmt::Return(..) | Stmt::Throw(..))) {
+        let keyword = if let Stmt::Throw(..) = statements[i] { "throw" } else { "return" };
+        if let Some(next) = statements.get(i + 1) {
+            report(l, "unreachable-code", next.span().line, format!("unreachable statement after {}", keyword));
+        }
+    }
+}
+
+fn constant_condition(l: &mut Linter, condition: &Expr, keyword: &str) {
+    if is_constant(condition) {
+        report(l, "constant-condition", condition.span().line, format!("{} condition is always the same", keyword));
+    }
+}
+
+// Whether an expression is made only of literals, so always evaluates to the same value
+fn is_constant(e: &Expr) -> bool {
+    match e {
+        Expr::StringLiteral(..)
+        | Expr::IntLiteral(..)
+        | Expr::FloatLiteral(..)
+        | Expr::BoolLiteral(..)
+        | Expr::NilLiteral(_) => true,
+        Expr::Grouping(inner, _) | Expr::Unary(inner, _) => is_constant(inner),
+        Expr::Binary(left, _, right) | Expr::Logical(left, _, right) => is_constant(left) && is_constant(right),
+        Expr::Ternary(condition, if_true, if_false) => {
+            is_constant(condition) && is_constant(if_true) && is_constant(if_false)
+        }
+        Expr::Interpolation(parts) | Expr::Sequence(parts) | Expr::List(parts, _) => parts.iter().all(is_constant),
+        Expr::Map(entries, _) => entries.iter().all(|(key, value)| is_constant(key) && is_constant(value)),
+        Expr::Variable(_)
+        | Expr::Assign(..)
+        | Expr::Call(..)
+        | Expr::Get(..)
+        | Expr::Lambda(..)
+        | Expr::Index(..)
+        | Expr::Slice(..)
+        | Expr::SetIndex(..) => false,
+    }
+}
+
+// Whether an expression can be seen to produce a value other than nil without running it
+fn is_never_nil(e: &Expr) -> bool {
+    match e {
+        Expr::StringLiteral(..)
+        | Expr::Interpolation(_)
+        | Expr::IntLiteral(..)
+        | Expr::FloatLiteral(..)
+        | Expr::BoolLiteral(..)
+        | Expr::Lambda(..)
+        | Expr::List(..)
+        | Expr::Map(..)
+        | Expr::Slice(..) => true,
+        Expr::Grouping(inner, _) => is_never_nil(inner),
+        // Arithmetic, comparisons and negation all produce numbers, strings or booleans (or fail)
+        Expr::Unary(..) => true,
+        Expr::Binary(..) => true,
+        Expr::Sequence(expressions) => is_never_nil(&expressions[expressions.len() - 1]),
+        _ => false,
+    }
+}
+
+fn strip_grouping(e: &Expr) -> &Expr {
+    match e {
+        Expr::Grouping(inner, _) => strip_grouping(inner),
+        e => e,
+    }
+}
+
+fn report(l: &mut Linter, rule: &'static str, line: u32, message: String) {
+    if !l.allowed.iter().any(|r| r == rule) {
+        l.lints.push(Lint { rule, line, message });
+    }
+}
@@ -0,0 +1,782 @@
+use crate::json::Json;
+use crate::linter::lint;
+use crate::parser::{program, Parser};
+use crate::resolver::resolve;
+use crate::scanner::{Comment, ScanError, Scanner, Span, Token, TokenType};
+use crate::syntax_tree::{walk_expr, walk_stmt, Expr, Stmt, Visitor};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+const KEYWORDS: [&str; 17] = [
+    "and", "catch", "else", "false", "finally", "for", "func", "if", "nil", "or", "print", "return", "throw", "true",
+    "try", "var", "while",
+];
+
+// JSON-RPC error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INVALID_REQUEST: i64 = -32600;
+
+/**
+ * A Language Server Protocol server for rslox, talking JSON-RPC over a pair of streams (stdin and stdout in the
+ * `rslox-lsp` binary). Documents are kept in memory and re-analyzed from scratch on every change: they're scanned,
+ * parsed, resolved and linted for diagnostics, then indexed for navigation.
+ *
+ * Only full document sync is supported, and positions are assumed to be in UTF-16 code units as the protocol requires.
+ */
+pub struct Server<W: Write> {
+    out: W,
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+}
+
+// While the document doesn't parse, this keeps the last version that did, so navigation keeps working while it's
+// being edited. Positions are always resolved against the text the index was built from.
+struct Document {
+    text: String,
+    index: Option<Index>,
+}
+
+/**
+ * Serve requests read from `input` until the client sends `exit` or closes the stream. Returns the process exit
+ * status: 0 if the client shut the server down properly first, 1 otherwise.
+ */
+pub fn serve<R: BufRead, W: Write>(mut input: R, out: W) -> io::Result<i32> {
+    let mut server = Server { out, documents: HashMap::new(), shutdown: false };
+    while let Some(body) = read_message(&mut input)? {
+        let message = match Json::parse(&body) {
+            Ok(message) => message,
+            Err(e) => {
+                let error = error_response(Json::Null, INVALID_REQUEST, &format!("invalid JSON: {}", e));
+                write_message(&mut server.out, &error)?;
+                continue;
+            }
+        };
+        if message.get("method").and_then(Json::as_str) == Some("exit") {
+            return Ok(if server.shutdown { 0 } else { 1 });
+        }
+        handle(&mut server, &message)?;
+    }
+    Ok(1)
+}
+
+/*
+ * Message framing
+ */
+
+/**
+ * Read the body of one message, or `None` at the end of the stream. The debug adapter frames its messages the same way.
+ */
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message<W: Write>(out: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+fn response(id: Json, result: Json) -> Json {
+    Json::object(vec![("jsonrpc", Json::string("2.0")), ("id", id), ("result", result)])
+}
+
+fn error_response(id: Json, code: i64, message: &str) -> Json {
+    let error = Json::object(vec![("code", Json::Int(code)), ("message", Json::string(message))]);
+    Json::object(vec![("jsonrpc", Json::string("2.0")), ("id", id), ("error", error)])
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object(vec![("jsonrpc", Json::string("2.0")), ("method", Json::string(method)), ("params", params)])
+}
+
+/*
+ * Dispatch
+ */
+
+fn handle<W: Write>(server: &mut Server<W>, message: &Json) -> io::Result<()> {
+    let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+    let params = message.get("params").cloned().unwrap_or(Json::Null);
+    let id = match message.get("id") {
+        Some(id) => id.clone(),
+        // Notifications get no response, even when they fail
+        None => {
+            match method {
+                "textDocument/didOpen" => {
+                    let document = params.get("textDocument");
+                    let uri = document.and_then(|d| d.get("uri")).and_then(Json::as_str);
+                    let text = document.and_then(|d| d.get("text")).and_then(Json::as_str);
+                    if let (Some(uri), Some(text)) = (uri, text) {
+                        update(server, uri, text.to_string())?;
+                    }
+                }
+                "textDocument/didChange" => {
+                    let uri = params.get("textDocument").and_then(|d| d.get("uri")).and_then(Json::as_str);
+                    // With full sync, the last change holds the whole new text
+                    let changes = params.get("contentChanges").and_then(Json::as_array);
+                    let text = changes.and_then(|c| c.last()).and_then(|c| c.get("text")).and_then(Json::as_str);
+                    if let (Some(uri), Some(text)) = (uri, text) {
+                        update(server, uri, text.to_string())?;
+                    }
+                }
+                "textDocument/didClose" => {
+                    if let Some(uri) = params.get("textDocument").and_then(|d| d.get("uri")).and_then(Json::as_str) {
+                        server.documents.remove(uri);
+                        publish_diagnostics(server, uri, vec![])?;
+                    }
+                }
+                _ => (),
+            }
+            return Ok(());
+        }
+    };
+    let result = match method {
+        "initialize" => Ok(capabilities()),
+        "shutdown" => {
+            server.shutdown = true;
+            Ok(Json::Null)
+        }
+        "textDocument/definition" => at_position(server, &params, definition),
+        "textDocument/references" => at_position(server, &params, references),
+        "textDocument/hover" => at_position(server, &params, hover),
+        "textDocument/completion" => at_position(server, &params, completion),
+        "textDocument/documentSymbol" => match document(server, &params) {
+            Some((uri, document)) => Ok(document_symbols(uri, document)),
+            None => Err((INVALID_PARAMS, "unknown document".to_string())),
+        },
+        m => Err((METHOD_NOT_FOUND, format!("unsupported method `{}`", m))),
+    };
+    let message = match result {
+        Ok(result) => response(id, result),
+        Err((code, msg)) => error_response(id, code, &msg),
+    };
+    write_message(&mut server.out, &message)
+}
+
+fn capabilities() -> Json {
+    let capabilities = Json::object(vec![
+        // Full document sync
+        ("textDocumentSync", Json::Int(1)),
+        ("definitionProvider", Json::Bool(true)),
+        ("referencesProvider", Json::Bool(true)),
+        ("hoverProvider", Json::Bool(true)),
+        ("documentSymbolProvider", Json::Bool(true)),
+        ("completionProvider", Json::object(vec![])),
+    ]);
+    let version = Json::string(env!("CARGO_PKG_VERSION"));
+    let info = Json::object(vec![("name", Json::string("rslox")), ("version", version)]);
+    Json::object(vec![("capabilities", capabilities), ("serverInfo", info)])
+}
+
+fn document<'a, W: Write>(server: &'a Server<W>, params: &'a Json) -> Option<(&'a str, &'a Document)> {
+    let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+    server.documents.get(uri).map(|document| (uri, document))
+}
+
+type Handler = fn(&str, &Document, usize, &Json) -> Json;
+
+/**
+ * Run a request that's about a position in a document, such as go-to-definition.
+ */
+fn at_position<W: Write>(server: &Server<W>, params: &Json, handler: Handler) -> Result<Json, (i64, String)> {
+    let (uri, document) = document(server, params).ok_or((INVALID_PARAMS, "unknown document".to_string()))?;
+    let position = params.get("position").ok_or((INVALID_PARAMS, "missing position".to_string()))?;
+    let offset = to_offset(&document.text, position);
+    Ok(handler(uri, document, offset, params))
+}
+
+/*
+ * Analysis
+ */
+
+fn update<W: Write>(server: &mut Server<W>, uri: &str, text: String) -> io::Result<()> {
+    let (index, diagnostics) = analyze(&text);
+    let diagnostics = diagnostics.into_iter().map(|d| d.to_json(&text)).collect();
+    let document = match index {
+        Some(index) => Document { text, index: Some(index) },
+        None => match server.documents.remove(uri) {
+            Some(previous) if previous.index.is_some() => previous,
+            _ => Document { text, index: None },
+        },
+    };
+    server.documents.insert(uri.to_string(), document);
+    publish_diagnostics(server, uri, diagnostics)
+}
+
+fn publish_diagnostics<W: Write>(server: &mut Server<W>, uri: &str, diagnostics: Vec<Json>) -> io::Result<()> {
+    let params = Json::object(vec![("uri", Json::string(uri)), ("diagnostics", Json::Array(diagnostics))]);
+    write_message(&mut server.out, &notification("textDocument/publishDiagnostics", params))
+}
+
+struct Diagnostic {
+    line: u32,
+    message: String,
+    // 1 for errors, 2 for warnings
+    severity: u8,
+    code: Option<&'static str>,
+    // Exactly what the diagnostic is about, when that's known
+    span: Option<Span>,
+}
+
+impl Diagnostic {
+    /**
+     * Build a diagnostic from an error message, which may start with a `[line N]` prefix.
+     */
+    fn error(line: u32, message: &str) -> Diagnostic {
+        let (line, message) = match message.strip_prefix("[line ").and_then(|rest| rest.split_once("] ")) {
+            Some((n, rest)) => (n.parse().unwrap_or(line), rest),
+            None => (line, message),
+        };
+        Diagnostic { line, message: message.to_string(), severity: 1, code: None, span: None }
+    }
+
+    // Errors that only know their line cover all of it apart from the indentation
+    fn to_json(&self, text: &str) -> Json {
+        let range = match self.span.filter(|span| span.end <= text.len()) {
+            Some(span) => {
+                let ((start_line, start_character), (end_line, end_character)) =
+                    (to_position(text, span.start), to_position(text, span.end));
+                range_json(start_line, start_character, end_line, end_character)
+            }
+            None => {
+                let line = self.line.saturating_sub(1) as usize;
+                let content = text_line(text, line);
+                let indent = &content[..content.len() - content.trim_start().len()];
+                range_json(line, utf16_len(indent), line, utf16_len(content))
+            }
+        };
+        let mut fields = vec![
+            ("range", range),
+            ("severity", Json::Int(self.severity as i64)),
+            ("source", Json::string("rslox")),
+            ("message", Json::string(&self.message)),
+        ];
+        if let Some(code) = self.code {
+            fields.push(("code", Json::string(code)));
+        }
+        Json::object(fields)
+    }
+}
+
+/**
+ * Check a document, returning its index if it parsed along with everything wrong with it.
+ */
+fn analyze(text: &str) -> (Option<Index>, Vec<Diagnostic>) {
+    // Comments are needed for linting, so all of the tokens are scanned up front
+    let tokens = match Scanner::new(text.to_string()).collect::<Result<Vec<Token>, ScanError>>() {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            let diagnostic = Diagnostic { span: Some(e.span), ..Diagnostic::error(e.span.line, &e.message) };
+            return (None, vec![diagnostic]);
+        }
+    };
+    let comments: Vec<Comment> = tokens.iter().flat_map(|t| t.comments.iter().cloned()).collect();
+    let statements = match program(&mut Parser::new(tokens.into_iter().map(Ok))) {
+        Ok(statements) => statements,
+        Err(msg) => return (None, vec![Diagnostic::error(1, &msg)]),
+    };
+    let mut diagnostics = vec![];
+    match resolve(&statements) {
+        Ok(()) => {
+            for l in lint(&statements, &comments) {
+                let code = Some(l.rule);
+                diagnostics.push(Diagnostic { line: l.line, message: l.message, severity: 2, code, span: None });
+            }
+        }
+        Err(errors) => diagnostics.extend(errors.iter().map(|e| Diagnostic::error(1, e))),
+    }
+    (Some(index(&statements)), diagnostics)
+}
+
+/*
+ * Symbol index
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SymbolKind {
+    Variable,
+    Function,
+    Parameter,
+}
+
+struct Symbol {
+    name: String,
+    kind: SymbolKind,
+    // Span of the name where it's declared, and of the whole declaration
+    name_span: Span,
+    span: Span,
+    // Inferred type of a variable, or the return type of a function
+    ty: String,
+    params: Vec<String>,
+    // Offsets between which the name is in scope. Globals are in scope everywhere.
+    scope: (usize, usize),
+    // The function this was declared in, if any
+    container: Option<usize>,
+}
+
+impl Symbol {
+    fn detail(&self) -> String {
+        match self.kind {
+            SymbolKind::Variable => format!("var {}: {}", self.name, self.ty),
+            SymbolKind::Parameter => format!("param {}", self.name),
+            SymbolKind::Function => format!("func {}({}): {}", self.name, self.params.join(", "), self.ty),
+        }
+    }
+}
+
+struct Index {
+    symbols: Vec<Symbol>,
+    // Every place a symbol is named, including where it's declared
+    references: Vec<(Span, usize)>,
+}
+
+impl Index {
+    fn symbol_at(&self, offset: usize) -> Option<usize> {
+        self.references.iter().find(|(span, _)| span.start <= offset && offset <= span.end).map(|(_, s)| *s)
+    }
+}
+
+/**
+ * Builds an index by walking the tree while tracking scopes the same way the resolver does.
+ */
+struct Indexer {
+    index: Index,
+    // Innermost scope last, each with the offset it ends at. The bottom one is the global scope.
+    scopes: Vec<(HashMap<String, usize>, usize)>,
+    // Functions being walked, innermost last, with the types of the values they return. Anonymous functions have no
+    // symbol of their own.
+    functions: Vec<(Option<usize>, Vec<String>)>,
+    // Names that weren't declared yet where they're used, which can only be globals declared further down
+    unresolved: Vec<Token>,
+}
+
+fn index(statements: &[Stmt]) -> Index {
+    let mut indexer = Indexer {
+        index: Index { symbols: vec![], references: vec![] },
+        scopes: vec![(HashMap::new(), usize::MAX)],
+        functions: vec![],
+        unresolved: vec![],
+    };
+    for statement in statements {
+        indexer.visit_stmt(statement);
+    }
+    for name in std::mem::take(&mut indexer.unresolved) {
+        if let Some(symbol) = indexer.scopes[0].0.get(&name.lexeme) {
+            indexer.index.references.push((name.span, *symbol));
+        }
+    }
+    indexer.index
+}
+
+impl Visitor for Indexer {
+    fn visit_stmt(&mut self, s: &Stmt) {
+        match s {
+            Stmt::Var(name, initializer, _, span) => {
+                if let Some(e) = initializer {
+                    self.visit_expr(e);
+                }
+                let ty = initializer.as_ref().map(|e| infer(self, e)).unwrap_or_else(|| "nil".to_string());
+                declare(self, name, SymbolKind::Variable, *span, ty);
+            }
+            Stmt::Block(_, span) | Stmt::For(.., span) => {
+                self.scopes.push((HashMap::new(), span.end));
+                walk_stmt(self, s);
+                self.scopes.pop();
+            }
+            Stmt::Function(decl) => {
+                let function = declare(self, &decl.name, SymbolKind::Function, decl.span, "nil".to_string());
+                self.index.symbols[function].params = decl.params.iter().map(|p| p.lexeme.clone()).collect();
+                self.functions.push((Some(function), vec![]));
+                self.scopes.push((HashMap::new(), decl.span.end));
+                for param in &decl.params {
+                    declare(self, param, SymbolKind::Parameter, param.span, "any".to_string());
+                }
+                walk_stmt(self, s);
+                self.scopes.pop();
+                let (_, returns) = self.functions.pop().unwrap();
+                self.index.symbols[function].ty = match returns.first() {
+                    None => "nil".to_string(),
+                    Some(first) if returns.iter().all(|ty| ty == first) => first.clone(),
+                    Some(_) => "any".to_string(),
+                };
+            }
+            Stmt::Return(value, _) => {
+                walk_stmt(self, s);
+                let ty = value.as_ref().map(|e| infer(self, e)).unwrap_or_else(|| "nil".to_string());
+                if let Some((_, returns)) = self.functions.last_mut() {
+                    returns.push(ty);
+                }
+            }
+            Stmt::Try(body, catch, finally, _) => {
+                self.visit_stmt(body);
+                if let Some((name, handler)) = catch {
+                    // Anything can be thrown, so nothing is known about the caught value
+                    self.scopes.push((HashMap::new(), handler.span().end));
+                    declare(self, name, SymbolKind::Variable, name.span, "any".to_string());
+                    self.visit_stmt(handler);
+                    self.scopes.pop();
+                }
+                if let Some(finally) = finally {
+                    self.visit_stmt(finally);
+                }
+            }
+            _ => walk_stmt(self, s),
+        }
+    }
+
+    fn visit_expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Variable(name) | Expr::Assign(name, _) => match lookup(self, &name.lexeme) {
+                Some(symbol) => self.index.references.push((name.span, symbol)),
+                None => self.unresolved.push(name.clone()),
+            },
+            Expr::Lambda(decl, _) => {
+                self.functions.push((None, vec![]));
+                self.scopes.push((HashMap::new(), decl.span.end));
+                for param in &decl.params {
+                    declare(self, param, SymbolKind::Parameter, param.span, "any".to_string());
+                }
+                walk_expr(self, e);
+                self.scopes.pop();
+                self.functions.pop();
+                return;
+            }
+            _ => (),
+        }
+        walk_expr(self, e);
+    }
+}
+
+fn declare(i: &mut Indexer, name: &Token, kind: SymbolKind, span: Span, ty: String) -> usize {
+    let symbol = i.index.symbols.len();
+    let global = i.scopes.len() == 1;
+    i.index.symbols.push(Symbol {
+        name: name.lexeme.clone(),
+        kind,
+        name_span: name.span,
+        span,
+        ty,
+        params: vec![],
+        scope: if global { (0, usize::MAX) } else { (name.span.start, i.scopes.last().unwrap().1) },
+        container: i.functions.iter().rev().find_map(|(f, _)| *f),
+    });
+    i.index.references.push((name.span, symbol));
+    i.scopes.last_mut().unwrap().0.insert(name.lexeme.clone(), symbol);
+    symbol
+}
+
+fn lookup(i: &Indexer, name: &str) -> Option<usize> {
+    i.scopes.iter().rev().find_map(|(scope, _)| scope.get(name).copied())
+}
+
+/**
+ * Work out the type of an expression as far as can be done without running it. Anything that could be one of several
+ * types is "any".
+ */
+fn infer(i: &Indexer, e: &Expr) -> String {
+    let ty = match e {
+        Expr::StringLiteral(..) | Expr::Interpolation(_) => "string",
+        Expr::IntLiteral(..) | Expr::FloatLiteral(..) => "number",
+        Expr::BoolLiteral(..) => "bool",
+        Expr::NilLiteral(_) => "nil",
+        Expr::Grouping(inner, _) | Expr::Assign(_, inner) => return infer(i, inner),
+        Expr::Sequence(expressions) => return infer(i, &expressions[expressions.len() - 1]),
+        Expr::Unary(_, op) if op.token_type == TokenType::Bang => "bool",
+        Expr::Unary(..) => "number",
+        Expr::Binary(left, op, right) => match op.token_type {
+            TokenType::Plus => {
+                let (left, right) = (infer(i, left), infer(i, right));
+                if left == "string" || right == "string" {
+                    "string"
+                } else if left == "number" && right == "number" {
+                    "number"
+                } else {
+                    "any"
+                }
+            }
+            TokenType::Minus | TokenType::Star | TokenType::Slash | TokenType::Percent => "number",
+            _ => "bool",
+        },
+        Expr::Logical(left, _, right) | Expr::Ternary(_, left, right) => {
+            let (left, right) = (infer(i, left), infer(i, right));
+            return if left == right { left } else { "any".to_string() };
+        }
+        Expr::Variable(name) => match lookup(i, &name.lexeme).map(|s| &i.index.symbols[s]) {
+            Some(symbol) if symbol.kind == SymbolKind::Function => "function",
+            Some(symbol) => return symbol.ty.clone(),
+            None => "any",
+        },
+        Expr::Get(..) => "any",
+        Expr::Lambda(..) => "function",
+        Expr::List(..) | Expr::Slice(..) => "list",
+        Expr::Map(..) => "map",
+        Expr::Index(..) => "any",
+        Expr::SetIndex(_, _, _, value) => return infer(i, value),
+        Expr::Call(callee, ..) => match &**callee {
+            Expr::Variable(name) => match lookup(i, &name.lexeme).map(|s| &i.index.symbols[s]) {
+                Some(symbol) if symbol.kind == SymbolKind::Function => return symbol.ty.clone(),
+                _ => "any",
+            },
+            _ => "any",
+        },
+    };
+    ty.to_string()
+}
+
+/*
+ * Requests
+ */
+
+fn definition(uri: &str, document: &Document, offset: usize, _: &Json) -> Json {
+    match document.index.as_ref().and_then(|index| index.symbol_at(offset).map(|s| (index, s))) {
+        Some((index, s)) => location(uri, &document.text, index.symbols[s].name_span),
+        None => Json::Null,
+    }
+}
+
+fn references(uri: &str, document: &Document, offset: usize, params: &Json) -> Json {
+    let include_declaration = params
+        .get("context")
+        .and_then(|c| c.get("includeDeclaration"))
+        .and_then(Json::as_bool)
+        .unwrap_or(true);
+    let mut locations = vec![];
+    if let Some(index) = &document.index {
+        if let Some(s) = index.symbol_at(offset) {
+            let declaration = index.symbols[s].name_span;
+            for (span, _) in index.references.iter().filter(|(_, r)| *r == s) {
+                if include_declaration || *span != declaration {
+                    locations.push(location(uri, &document.text, *span));
+                }
+            }
+        }
+    }
+    Json::Array(locations)
+}
+
+fn hover(_: &str, document: &Document, offset: usize, _: &Json) -> Json {
+    let index = match &document.index {
+        Some(index) => index,
+        None => return Json::Null,
+    };
+    let (span, s) = match index.references.iter().find(|(span, _)| span.start <= offset && offset <= span.end) {
+        Some(reference) => reference,
+        None => return Json::Null,
+    };
+    let contents = Json::object(vec![
+        ("kind", Json::string("markdown")),
+        ("value", Json::String(format!("```rslox\n{}\n```", index.symbols[*s].detail()))),
+    ]);
+    Json::object(vec![("contents", contents), ("range", range(&document.text, *span))])
+}
+
+fn completion(_: &str, document: &Document, offset: usize, _: &Json) -> Json {
+    let mut items: Vec<Json> = vec![];
+    let mut seen: Vec<&str> = vec![];
+    if let Some(index) = &document.index {
+        // Innermost declarations come last, and hide anything further out with the same name
+        for symbol in index.symbols.iter().rev().filter(|s| s.scope.0 <= offset && offset <= s.scope.1) {
+            if seen.contains(&symbol.name.as_str()) {
+                continue;
+            }
+            seen.push(&symbol.name);
+            let kind = if symbol.kind == SymbolKind::Function { 3 } else { 6 };
+            items.push(Json::object(vec![
+                ("label", Json::string(&symbol.name)),
+                ("kind", Json::Int(kind)),
+                ("detail", Json::String(symbol.detail())),
+            ]));
+        }
+    }
+    for keyword in KEYWORDS.iter() {
+        items.push(Json::object(vec![("label", Json::string(keyword)), ("kind", Json::Int(14))]));
+    }
+    Json::Array(items)
+}
+
+fn document_symbols(_: &str, document: &Document) -> Json {
+    match &document.index {
+        Some(index) => Json::Array(symbols_in(index, &document.text, None)),
+        None => Json::Array(vec![]),
+    }
+}
+
+fn symbols_in(index: &Index, text: &str, container: Option<usize>) -> Vec<Json> {
+    let mut symbols = vec![];
+    for (s, symbol) in index.symbols.iter().enumerate() {
+        if symbol.container != container || symbol.kind == SymbolKind::Parameter {
+            continue;
+        }
+        let (kind, children) = match symbol.kind {
+            SymbolKind::Function => (12, symbols_in(index, text, Some(s))),
+            _ => (13, vec![]),
+        };
+        symbols.push(Json::object(vec![
+            ("name", Json::string(&symbol.name)),
+            ("detail", Json::String(symbol.detail())),
+            ("kind", Json::Int(kind)),
+            ("range", range(text, symbol.span)),
+            ("selectionRange", range(text, symbol.name_span)),
+            ("children", Json::Array(children)),
+        ]));
+    }
+    symbols
+}
+
+/*
+ * Positions. The protocol counts lines from 0 and characters in UTF-16 code units; spans are byte offsets.
+ */
+
+fn text_line(text: &str, line: usize) -> &str {
+    text.split('\n').nth(line).unwrap_or("").trim_end_matches('\r')
+}
+
+fn utf16_len(s: &str) -> usize {
+    s.encode_utf16().count()
+}
+
+fn to_offset(text: &str, position: &Json) -> usize {
+    let line = position.get("line").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+    let character = position.get("character").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+    let line_start: usize = text.split('\n').take(line).map(|l| l.len() + 1).sum();
+    let mut units = 0;
+    for (i, c) in text[line_start.min(text.len())..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn to_position(text: &str, offset: usize) -> (usize, usize) {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (line, utf16_len(&before[line_start..]))
+}
+
+fn range_json(start_line: usize, start_character: usize, end_line: usize, end_character: usize) -> Json {
+    let position = |line, character| {
+        Json::object(vec![("line", Json::Int(line as i64)), ("character", Json::Int(character as i64))])
+    };
+    Json::object(vec![
+        ("start", position(start_line, start_character)),
+        ("end", position(end_line, end_character)),
+    ])
+}
+
+fn range(text: &str, span: Span) -> Json {
+    let (start_line, start_character) = to_position(text, span.start);
+    let (end_line, end_character) = to_position(text, span.end);
+    range_json(start_line, start_character, end_line, end_character)
+}
+
+fn location(uri: &str, text: &str, span: Span) -> Json {
+    Json::object(vec![("uri", Json::string(uri)), ("range", range(text, span))])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run a session of messages through the server, returning its exit status and every message it sent back
+    fn session(messages: &[&str]) -> (i32, Vec<Json>) {
+        let mut input = String::new();
+        for message in messages {
+            input += &format!("Content-Length: {}\r\n\r\n{}", message.len(), message);
+        }
+        let mut out = vec![];
+        let status = serve(input.as_bytes(), &mut out).unwrap();
+        let mut out = out.as_slice();
+        let mut replies = vec![];
+        while let Some(body) = read_message(&mut out).unwrap() {
+            replies.push(Json::parse(&body).unwrap());
+        }
+        (status, replies)
+    }
+
+    fn open(text: &str) -> String {
+        let document = Json::object(vec![("uri", Json::string("file:///a.rslox")), ("text", Json::string(text))]);
+        let params = Json::object(vec![("textDocument", document)]);
+        notification("textDocument/didOpen", params).to_string()
+    }
+
+    fn change(text: &str) -> String {
+        let params = Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::string("file:///a.rslox"))])),
+            ("contentChanges", Json::Array(vec![Json::object(vec![("text", Json::string(text))])])),
+        ]);
+        notification("textDocument/didChange", params).to_string()
+    }
+
+    fn definition(id: i64, line: i64, character: i64) -> String {
+        format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":{},\"method\":\"textDocument/definition\",\"params\":{{\"textDocument\":\
+             {{\"uri\":\"file:///a.rslox\"}},\"position\":{{\"line\":{},\"character\":{}}}}}}}",
+            id, line, character
+        )
+    }
+
+    fn result(replies: &[Json], id: i64) -> &Json {
+        let reply = replies.iter().find(|r| r.get("id") == Some(&Json::Int(id))).unwrap();
+        reply.get("result").unwrap()
+    }
+
+    const SHUTDOWN: &str = "{\"jsonrpc\":\"2.0\",\"id\":99,\"method\":\"shutdown\"}";
+    const EXIT: &str = "{\"jsonrpc\":\"2.0\",\"method\":\"exit\"}";
+
+    #[test]
+    fn navigation_survives_edits_that_dont_parse() {
+        let (status, replies) = session(&[
+            &open("var abc = 1;\nprint abc;\n"),
+            &change("ééééééééééé (\n"),
+            &definition(1, 1, 7),
+            &definition(2, 0, 5),
+            SHUTDOWN,
+            EXIT,
+        ]);
+        assert_eq!(status, 0);
+        // Both positions are in the last version that parsed, where they're on `abc`
+        for id in [1, 2].iter() {
+            let start = result(&replies, *id).get("range").and_then(|r| r.get("start")).unwrap();
+            assert_eq!(start.get("line").and_then(Json::as_i64), Some(0));
+            assert_eq!(start.get("character").and_then(Json::as_i64), Some(4));
+        }
+    }
+
+    #[test]
+    fn unparsable_documents_have_no_index() {
+        let (status, replies) = session(&[&open("ééé (\n"), &definition(1, 0, 2), SHUTDOWN, EXIT]);
+        assert_eq!(status, 0);
+        assert_eq!(result(&replies, 1), &Json::Null);
+    }
+
+    #[test]
+    fn positions_snap_to_characters() {
+        assert_eq!(to_position("éé", 1), (0, 0));
+        assert_eq!(to_position("éé", 3), (0, 1));
+        assert_eq!(to_position("a\né", 100), (1, 1));
+    }
+}
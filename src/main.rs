@@ -1,17 +1,295 @@
 use std::env;
+use std::io::{stderr, IsTerminal};
 use std::process::exit;
 use rslox::*;
 
+const USAGE: &str = "Usage: rslox [options] [command] [script | -]
+       rslox doc [--format <format>] [--out <dir>] <script | directory>
+
+Commands:
+  run      Run a script (the default when a script is given)
+  repl     Start an interactive session (the default otherwise)
+  check    Parse and resolve a script without running it
+  tokens   Print the tokens of a script
+  ast      Print the syntax tree of a script
+  fmt      Reformat a script
+  debug    Run a script under an interactive debugger
+  lint     Report likely mistakes in a script (`lint --rules` lists the checks)
+  doc      Write reference pages for the functions and variables declared in a
+           script, or in every .rslox script under a directory
+
+Options:
+  -e <code>          Use <code> as the program instead of reading a script
+  --format <format>  Output format for `tokens` (table, json), `ast` (sexpr, json)
+                     or `doc` (html, markdown)
+  --from <format>    Read the program as rslox source (lox, the default) or as a
+                     syntax tree printed by `ast` (json, sexpr)
+  --backend <name>   Execution backend to use (available: tree-walk)
+  --float-division <policy>
+                     What dividing a float by zero does when running a script or
+                     the REPL: ieee (the default) gives inf or nan, error raises a
+                     runtime error. Dividing an integer by zero is always an error
+  --check            With `fmt`, exit with status 1 if the script isn't formatted
+                     instead of printing it
+  -w, --write        With `fmt`, reformat the script in place
+  -o, --out <dir>    With `doc`, the directory to write pages to (default: doc)
+  --no-color         Don't highlight diagnostics
+  -h, --help         Print this message
+
+A script path of `-` reads the program from stdin.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    Run,
+    Repl,
+    Check,
+    Tokens,
+    Ast,
+    Fmt,
+    Lint,
+    Debug,
+    Doc,
+}
+
+struct Options {
+    command: Option<Command>,
+    script: Option<String>,
+    inline: Option<String>,
+    format: Option<String>,
+    from: ProgramFormat,
+    fmt_mode: FormatMode,
+    list_rules: bool,
+    out: Option<String>,
+    float_division: Option<FloatDivision>,
+    color: bool,
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    // print_ast();
-    if args.len() > 2 {
-        println!("Usage: rlox [script]");
-        exit(64);
-    } else if args.len() == 2 { // First arg is the executable name
-        run_file(&args[1]);
+    rslox::with_large_stack(run)
+}
+
+fn run() {
+    let args: Vec<String> = env::args().skip(1).collect(); // First arg is the executable name
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, USAGE);
+            exit(EX_USAGE);
+        }
+    };
+
+    let command = options.command.unwrap_or(if options.script.is_some() || options.inline.is_some() {
+        Command::Run
     } else {
-        run_prompt();
+        Command::Repl
+    });
+    if command == Command::Repl {
+        if options.script.is_some() || options.inline.is_some() {
+            eprintln!("repl doesn't take a script\n\n{}", USAGE);
+            exit(EX_USAGE);
+        }
+        run_prompt(options.color, options.float_division.unwrap_or(FloatDivision::Ieee));
+        return;
+    }
+
+    if options.fmt_mode != FormatMode::Print && command != Command::Fmt {
+        eprintln!("--check and --write only apply to fmt\n\n{}", USAGE);
+        exit(EX_USAGE);
+    }
+    if options.fmt_mode == FormatMode::Write && matches!(options.script.as_deref(), None | Some("-")) {
+        eprintln!("--write needs a script file\n\n{}", USAGE);
+        exit(EX_USAGE);
+    }
+    if options.float_division.is_some() && command != Command::Run {
+        eprintln!("--float-division only applies to run and repl\n\n{}", USAGE);
+        exit(EX_USAGE);
+    }
+    if command == Command::Debug && options.script.as_deref() == Some("-") {
+        eprintln!("debug reads commands from stdin, so it can't read the script from there too\n\n{}", USAGE);
+        exit(EX_USAGE);
+    }
+    if command == Command::Lint && options.list_rules {
+        print_lint_rules();
+        return;
+    }
+    let format = options.format.as_deref();
+    match (command, format) {
+        (Command::Tokens, None | Some("table") | Some("json")) => (),
+        (Command::Ast, None | Some("sexpr") | Some("json")) => (),
+        (Command::Doc, None | Some("html") | Some("markdown")) => (),
+        (_, None) => (),
+        (_, Some(f)) => {
+            eprintln!("{:?} doesn't support the `{}` format\n\n{}", command, f, USAGE);
+            exit(EX_USAGE);
+        }
+    }
+    if options.out.is_some() && command != Command::Doc {
+        eprintln!("--out only applies to doc\n\n{}", USAGE);
+        exit(EX_USAGE);
+    }
+    if command == Command::Doc {
+        match options.script.as_deref() {
+            Some(path) if path != "-" && options.inline.is_none() && options.from == ProgramFormat::Source => {
+                let format = if format == Some("markdown") { DocFormat::Markdown } else { DocFormat::Html };
+                document(path, options.out.as_deref().unwrap_or("doc"), format, options.color);
+                return;
+            }
+            _ => {
+                eprintln!("doc needs a script or a directory of them\n\n{}", USAGE);
+                exit(EX_USAGE);
+            }
+        }
+    }
+    let path = options.script.clone();
+    let program = match (options.inline, options.script) {
+        (Some(code), None) => code,
+        (None, Some(path)) => match read_source(&path) {
+            Ok(program) => program,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                exit(EX_NOINPUT);
+            }
+        },
+        (Some(_), Some(_)) => {
+            eprintln!("can't use both -e and a script\n\n{}", USAGE);
+            exit(EX_USAGE);
+        }
+        (None, None) => {
+            eprintln!("{:?} needs a script, `-` or -e <code>\n\n{}", command, USAGE);
+            exit(EX_USAGE);
+        }
+    };
+    if options.from != ProgramFormat::Source && !matches!(command, Command::Run | Command::Check | Command::Ast) {
+        eprintln!("{:?} only reads rslox source\n\n{}", command, USAGE);
+        exit(EX_USAGE);
+    }
+    match command {
+        Command::Run => {
+            let float_division = options.float_division.unwrap_or(FloatDivision::Ieee);
+            run_source(program, options.from, options.color, float_division)
+        }
+        Command::Check => check_source(program, options.from, options.color),
+        Command::Tokens => print_tokens(program, options.color, format == Some("json")),
+        Command::Ast => {
+            let to = if format == Some("json") { ProgramFormat::Json } else { ProgramFormat::SExpr };
+            print_syntax_tree(program, options.from, to, options.color)
+        }
+        Command::Lint => lint_source(program, options.color),
+        Command::Debug => debug_source(program, options.color),
+        Command::Fmt => format_source(program, path.as_deref().filter(|p| *p != "-"), options.fmt_mode, options.color),
+        Command::Repl | Command::Doc => unreachable!(),
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        command: None,
+        script: None,
+        inline: None,
+        format: None,
+        from: ProgramFormat::Source,
+        fmt_mode: FormatMode::Print,
+        list_rules: false,
+        out: None,
+        float_division: None,
+        color: stderr().is_terminal(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            "--no-color" => options.color = false,
+            "--check" => options.fmt_mode = FormatMode::Check,
+            "-w" | "--write" => options.fmt_mode = FormatMode::Write,
+            "--rules" => options.list_rules = true,
+            "-o" | "--out" => match args.next() {
+                Some(dir) => options.out = Some(dir.clone()),
+                None => return Err(format!("{} needs an argument", arg)),
+            },
+            a if a.starts_with("--out=") => options.out = Some(a["--out=".len()..].to_string()),
+            "-e" => match args.next() {
+                Some(code) => options.inline = Some(code.clone()),
+                None => return Err("-e needs an argument".to_string()),
+            },
+            "--format" => match args.next() {
+                Some(format) => options.format = Some(format.clone()),
+                None => return Err("--format needs an argument".to_string()),
+            },
+            a if a.starts_with("--format=") => options.format = Some(a["--format=".len()..].to_string()),
+            "--from" => match args.next() {
+                Some(from) => options.from = parse_from(from)?,
+                None => return Err("--from needs an argument".to_string()),
+            },
+            a if a.starts_with("--from=") => options.from = parse_from(&a["--from=".len()..])?,
+            "--backend" => match args.next() {
+                Some(backend) => check_backend(backend)?,
+                None => return Err("--backend needs an argument".to_string()),
+            },
+            a if a.starts_with("--backend=") => check_backend(&a["--backend=".len()..])?,
+            "--float-division" => match args.next() {
+                Some(policy) => options.float_division = Some(parse_float_division(policy)?),
+                None => return Err("--float-division needs an argument".to_string()),
+            },
+            a if a.starts_with("--float-division=") => {
+                options.float_division = Some(parse_float_division(&a["--float-division=".len()..])?)
+            }
+            a if a.starts_with('-') && a != "-" => return Err(format!("unknown option `{}`", a)),
+            a => {
+                if options.command.is_none() && options.script.is_none() {
+                    if let Some(command) = parse_command(a) {
+                        options.command = Some(command);
+                        continue;
+                    }
+                }
+                if options.script.is_some() {
+                    return Err(format!("unexpected argument `{}`", a));
+                }
+                options.script = Some(a.to_string());
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn parse_command(arg: &str) -> Option<Command> {
+    match arg {
+        "run" => Some(Command::Run),
+        "repl" => Some(Command::Repl),
+        "check" => Some(Command::Check),
+        "tokens" => Some(Command::Tokens),
+        "ast" => Some(Command::Ast),
+        "fmt" => Some(Command::Fmt),
+        "lint" => Some(Command::Lint),
+        "debug" => Some(Command::Debug),
+        "doc" => Some(Command::Doc),
+        _ => None,
     }
 }
 
+fn parse_from(format: &str) -> Result<ProgramFormat, String> {
+    match format {
+        "lox" => Ok(ProgramFormat::Source),
+        "json" => Ok(ProgramFormat::Json),
+        "sexpr" => Ok(ProgramFormat::SExpr),
+        f => Err(format!("unknown program format `{}`", f)),
+    }
+}
+
+fn parse_float_division(policy: &str) -> Result<FloatDivision, String> {
+    match policy {
+        "ieee" => Ok(FloatDivision::Ieee),
+        "error" => Ok(FloatDivision::Error),
+        p => Err(format!("unknown float division policy `{}`", p)),
+    }
+}
+
+// The tree-walking interpreter is the only backend for now
+fn check_backend(backend: &str) -> Result<(), String> {
+    match backend {
+        "tree-walk" => Ok(()),
+        b => Err(format!("unknown backend `{}`", b)),
+    }
+}
@@ -1,167 +1,688 @@
+
 use crate::scanner::*;
 use crate::syntax_tree::*;
 use std::collections::VecDeque;
+use std::rc::Rc;
 /*
 Grammar:
-expression     → equality ( "," equality )* 
-               | ternary ;
-ternary        → equality "?" expression ":" expression ;
-equality       → comparison ( ( "!=" | "==" ) comparison )* ;
-comparison     → term ( ( ">" | ">=" | "<" | "<=" ) term )* ;
-term           → factor ( ( "-" | "+" ) factor )* ;
-factor         → unary ( ( "/" | "*" ) unary )* ;
-unary          → ( "!" | "-" ) unary
+program        → declaration* EOF ;
+declaration    → funDecl
+               | varDecl
+               | statement ;
+funDecl        → "func" IDENTIFIER "(" parameters? ")" block ;
+parameters     → IDENTIFIER ( "," IDENTIFIER )* ;
+varDecl        → "var" IDENTIFIER ( "=" expression )? ";" ;
+statement      → exprStmt
+               | forStmt
+               | ifStmt
+               | printStmt
+               | returnStmt
+               | throwStmt
+               | tryStmt
+               | whileStmt
+               | block ;
+exprStmt       → expression ";" ;
+forStmt        → "for" "(" ( varDecl | exprStmt | ";" )
+                 expression? ";"
+                 expression? ")" statement ;
+ifStmt         → "if" "(" expression ")" statement
+                 ( "else" statement )? ;
+printStmt      → "print" expression ";" ;
+returnStmt     → "return" expression? ";" ;
+throwStmt      → "throw" expression ";" ;
+tryStmt        → "try" block ( "catch" "(" IDENTIFIER ")" block )?
+                 ( "finally" block )? ;
+whileStmt      → "while" "(" expression ")" statement ;
+block          → "{" declaration* "}" ;
+
+Expressions are parsed by precedence climbing, so the grammar doesn't spell out precedence. How tightly each
+operator binds, and which way it associates, is in PREFIX_RULES and INFIX_RULES instead. From loosest to tightest:
+`,`, `=` and `?:`, `or`, `and`, `==` `!=`, `<` `<=` `>` `>=`, `+` `-`, `*` `/` `%`, unary `!` `-`, then calls, `.`
+and indexing.
+
+expression     → prefix_op expression
+               | expression infix_op expression
+               | expression "?" expression ":" expression
+               | expression "(" arguments? ")"
+               | expression "." IDENTIFIER
+               | expression "[" expression "]"
+               | expression "[" expression? ":" expression? "]"
                | primary ;
-primary        → NUMBER | STRING | "true" | "false" | "nil"
-               | "(" expression ")" ;
+prefix_op      → "!" | "-" ;
+infix_op       → "," | "=" | "or" | "and" | "!=" | "==" | ">" | ">=" | "<" | "<="
+               | "-" | "+" | "/" | "*" | "%" ;
+arguments      → expression ( "," expression )* ;
+primary        → NUMBER | STRING | IDENTIFIER | "true" | "false" | "nil"
+               | "(" expression ")" | interpolation | lambda | list | map ;
+list           → "[" ( expression ( "," expression )* ","? )? "]" ;
+map            → "{" ( entry ( "," entry )* ","? )? "}" ;
+entry          → expression ":" expression ;
+
+A statement starting with "{" is a block, unless a ":" that doesn't belong to a "?" comes before the first ";" or
+"}" outside any brackets, in which case it's a map.
+lambda         → "func" "(" parameters? ")" block
+               | "(" parameters? ")" "=>" expression ;
+interpolation  → INTERPOLATION expression ( INTERPOLATION expression )* STRING ;
 */
 pub struct Parser {
-    tokens: VecDeque<Token>,
-    cur: i32,
+    // Tokens are only scanned when the parser gets to them
+    tokens: Box<dyn Iterator<Item = Result<Token, ScanError>>>,
+    // Tokens that have been scanned to look ahead at, but not consumed yet
+    lookahead: VecDeque<Token>,
+    // Scanning stops at the first error, which then ends the token stream as if it were the end of the input
+    scan_error: Option<ScanError>,
+    /// When set, a trailing expression without a closing ';' is accepted, so REPL users can type `1 + 2`.
+    pub repl: bool,
+    // How many function bodies we're currently nested inside of
+    function_depth: u32,
+    // Span of the most recently consumed token, used to find where a node ends
+    last_span: Span,
 }
 impl Parser {
-  pub fn new(tk: Vec<Token>) -> Self {
-    Parser { tokens: VecDeque::from(tk), cur: 0 }
+  pub fn new(tokens: impl Iterator<Item = Result<Token, ScanError>> + 'static) -> Self {
+    Parser {
+        tokens: Box::new(tokens),
+        lookahead: VecDeque::new(),
+        scan_error: None,
+        repl: false,
+        function_depth: 0,
+        last_span: Span::default(),
+    }
+  }
+
+  /**
+   * The error that stopped the tokens from being scanned, if there was one.
+   */
+  pub fn scan_error(&self) -> Option<&ScanError> {
+      self.scan_error.as_ref()
   }
 }
 
-pub fn expression(p: &mut Parser) -> Result<Expr, String> {
-    match binary_expr(p, equality, &[TokenType::Comma]) {
-        left @ Ok(_) =>
-        if token_matches(&p.tokens[p.cur as usize], &[TokenType::QuestionMark]) {
-            p.tokens.pop_front().unwrap();
-            match binary_expr(p, expression, &[TokenType::Colon]) {
-                Ok(Expr::Binary(if_true, _, if_false)) => Ok(Expr::Ternary(Box::new(left.unwrap()), if_true, if_false)),
-                Ok(_) => Err(format!("Unable to parse rhs of ternary.")),
-                e @ Err(_) => e
-            }
-        } else {
-            left
-        },
-        x@ Err(_) => x
-    }
-}
-
-fn binary_expr(
-    p: &mut Parser,
-    sub_expr: fn(&mut Parser) -> Result<Expr, String>,
-    token_match: &[TokenType],
-) -> Result<Expr, String> {
-    match sub_expr(p) {
-        Ok(evaluated) => {
-            let mut left = evaluated;
-            loop {
-                // println!("[binary_expr() w/{:?}] Current left: {:?}", token_match, left);
-                if p.tokens.len() == 0 {
-                    return Ok(left);
-                } else if token_matches(&p.tokens[p.cur as usize], token_match) {
-                    let op: Token = p.tokens.pop_front().unwrap();
-                    match sub_expr(p) {
-                        Ok(right) => left = Expr::Binary(Box::new(left), op, Box::new(right)),
-                        e2 @ Err(_) => {
-                            return e2
-                        }
-                    }
-                } else {
-                    // println!("Returning {:?}", left);
-                    break;
-                }
+/*
+ * Statements
+ */
+
+pub fn program(p: &mut Parser) -> Result<Vec<Stmt>, String> {
+    let mut statements = vec![];
+    let mut result = Ok(());
+    while !is_at_end(p) {
+        match declaration(p) {
+            Ok(statement) => statements.push(statement),
+            Err(e) => {
+                result = Err(e);
+                break;
             }
-            Ok(left)
         }
-        e @ Err(_) => e,
     }
+    // Running into a scan error looks like reaching the end early, so it takes priority over whatever that caused
+    if let Some(e) = &p.scan_error {
+        return Err(e.to_string());
+    }
+    result.map(|_| statements)
 }
 
-fn equality(p: &mut Parser) -> Result<Expr, String> {
-    binary_expr(
-        p,
-        comparison,
-        &[TokenType::BangEqual, TokenType::EqualEqual],
-    )
+fn declaration(p: &mut Parser) -> Result<Stmt, String> {
+    let doc = peek(p).and_then(doc_comment);
+    // `func (` starts an anonymous function rather than a declaration
+    let anonymous = peek_nth(p, 1).is_some_and(|t| t.token_type == TokenType::LeftParen);
+    if !anonymous && match_token(p, &[TokenType::Func]) {
+        function(p, doc)
+    } else if match_token(p, &[TokenType::Var]) {
+        var_declaration(p, doc)
+    } else {
+        statement(p)
+    }
 }
-fn comparison(p: &mut Parser) -> Result<Expr, String> {
-    binary_expr(
-        p,
-        term,
-        &[
-            TokenType::Greater,
-            TokenType::GreaterEqual,
-            TokenType::Less,
-            TokenType::LessEqual,
-        ],
-    )
+
+fn function(p: &mut Parser, doc: Option<String>) -> Result<Stmt, String> {
+    let start = p.last_span;
+    let name = consume(p, TokenType::Identifier, "expected function name")?;
+    consume(p, TokenType::LeftParen, "expected '(' after function name")?;
+    let params = parameters(p)?;
+    let body = function_body(p)?;
+    Ok(Stmt::Function(Rc::new(FunctionDecl { name, params, body, doc, span: start.to(p.last_span) })))
 }
-fn term(p: &mut Parser) -> Result<Expr, String> {
-    binary_expr(p, factor, &[TokenType::Plus, TokenType::Minus])
+
+/**
+ * Parse a parameter list up to and including the closing ')'. Assumes the opening '(' has already been consumed.
+ */
+fn parameters(p: &mut Parser) -> Result<Vec<Token>, String> {
+    let mut params = vec![];
+    if !check(p, &[TokenType::RightParen]) {
+        loop {
+            params.push(consume(p, TokenType::Identifier, "expected parameter name")?);
+            if !match_token(p, &[TokenType::Comma]) {
+                break;
+            }
+        }
+    }
+    consume(p, TokenType::RightParen, "expected ')' after parameters")?;
+    Ok(params)
 }
-fn factor(p: &mut Parser) -> Result<Expr, String> {
-    binary_expr(p, unary, &[TokenType::Slash, TokenType::Star])
+
+fn function_body(p: &mut Parser) -> Result<Vec<Stmt>, String> {
+    consume(p, TokenType::LeftBrace, "expected '{' before function body")?;
+    p.function_depth += 1;
+    let body = block(p);
+    p.function_depth -= 1;
+    body
 }
 
-fn unary(p: &mut Parser) -> Result<Expr, String> {
-    if p.tokens.len() == 0 {
-      parse_error(&Token::new(TokenType::Eof, String::from(""), Box::new(-1), 0), String::from("reached EOF"))
+fn var_declaration(p: &mut Parser, doc: Option<String>) -> Result<Stmt, String> {
+    let start = p.last_span;
+    let name = consume(p, TokenType::Identifier, "expected variable name")?;
+    let initializer = if match_token(p, &[TokenType::Equal]) {
+        Some(expression(p)?)
     } else {
-        let t = p.tokens.pop_front().unwrap();
-        if token_matches(&t, &[TokenType::Bang, TokenType::Minus]) {
-            // Recursive unary
-            match unary(p) {
-                Ok(right) => Ok(Expr::Unary(Box::new(right), t)),
-                e @ Err(_) => e,
+        None
+    };
+    consume(p, TokenType::Semicolon, "expected ';' after variable declaration")?;
+    Ok(Stmt::Var(name, initializer, doc, start.to(p.last_span)))
+}
+
+/**
+ * The documentation for a declaration starting with `t`: the `///` comments on the lines straight before it, joined
+ * into one string. A blank line or an ordinary comment in between means they aren't about this declaration.
+ */
+fn doc_comment(t: &Token) -> Option<String> {
+    let mut lines = vec![];
+    let mut line = t.span.line;
+    for comment in t.comments.iter().rev() {
+        match comment.doc() {
+            Some(text) if comment.span.line + 1 == line => {
+                lines.push(text);
+                line = comment.span.line;
             }
+            _ => break,
+        }
+    }
+    if lines.is_empty() {
+        return None;
+    }
+    lines.reverse();
+    Some(lines.join("\n"))
+}
+
+fn statement(p: &mut Parser) -> Result<Stmt, String> {
+    let start = peek_span(p);
+    if match_token(p, &[TokenType::Print]) {
+        let value = expression(p)?;
+        consume(p, TokenType::Semicolon, "expected ';' after value")?;
+        Ok(Stmt::Print(value, start.to(p.last_span)))
+    } else if !map_ahead(p) && match_token(p, &[TokenType::LeftBrace]) {
+        let statements = block(p)?;
+        Ok(Stmt::Block(statements, start.to(p.last_span)))
+    } else if match_token(p, &[TokenType::If]) {
+        if_statement(p)
+    } else if match_token(p, &[TokenType::While]) {
+        consume(p, TokenType::LeftParen, "expected '(' after 'while'")?;
+        let condition = expression(p)?;
+        consume(p, TokenType::RightParen, "expected ')' after condition")?;
+        let body = Box::new(statement(p)?);
+        Ok(Stmt::While(condition, body, start.to(p.last_span)))
+    } else if match_token(p, &[TokenType::For]) {
+        for_statement(p)
+    } else if check(p, &[TokenType::Return]) {
+        let keyword = advance(p);
+        if p.function_depth == 0 {
+            return parse_error(&keyword, String::from("can't return from top-level code"));
+        }
+        let value = if check(p, &[TokenType::Semicolon]) {
+            None
         } else {
-            // Primary
-            primary(p, t)
+            Some(expression(p)?)
+        };
+        consume(p, TokenType::Semicolon, "expected ';' after return value")?;
+        Ok(Stmt::Return(value, start.to(p.last_span)))
+    } else if match_token(p, &[TokenType::Throw]) {
+        let value = expression(p)?;
+        consume(p, TokenType::Semicolon, "expected ';' after thrown value")?;
+        Ok(Stmt::Throw(value, start.to(p.last_span)))
+    } else if check(p, &[TokenType::Try]) {
+        try_statement(p)
+    } else {
+        let expr = expression(p)?;
+        if !(p.repl && is_at_end(p)) {
+            consume(p, TokenType::Semicolon, "expected ';' after expression")?;
         }
+        Ok(Stmt::Expression(expr, start.to(p.last_span)))
     }
 }
 
-fn primary(p: &mut Parser, t: Token) -> Result<Expr, String> {
-    if p.tokens.len() == 0 {
-      // TODO: How to grab the line?
-      parse_error(&Token::new(TokenType::Eof, String::from(""), Box::new(-1), 0), String::from("reached EOF"))
+// Whether the '{' coming up starts a map, as in `{"a": 1}["a"];`, rather than a block. A map's first key is followed
+// by a ':', while a block's first statement gets to a ';' or '}' without one, other than those of `?:`.
+fn map_ahead(p: &mut Parser) -> bool {
+    if !check(p, &[TokenType::LeftBrace]) {
+        return false;
+    }
+    let (mut depth, mut ternaries) = (0, 0);
+    let mut n = 1;
+    while let Some(t) = peek_nth(p, n) {
+        match t.token_type {
+            TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBracket if depth > 0 => depth -= 1,
+            TokenType::RightBrace if depth > 0 => depth -= 1,
+            TokenType::RightBrace | TokenType::Semicolon if depth == 0 => return false,
+            TokenType::QuestionMark if depth == 0 => ternaries += 1,
+            TokenType::Colon if depth == 0 && ternaries > 0 => ternaries -= 1,
+            TokenType::Colon if depth == 0 => return true,
+            _ => (),
+        }
+        n += 1;
+    }
+    false
+}
+
+/**
+ * Parse the statements of a block. Assumes the opening '{' has already been consumed.
+ */
+fn block(p: &mut Parser) -> Result<Vec<Stmt>, String> {
+    let mut statements = vec![];
+    while !check(p, &[TokenType::RightBrace]) && !is_at_end(p) {
+        statements.push(declaration(p)?);
+    }
+    consume(p, TokenType::RightBrace, "expected '}' after block")?;
+    Ok(statements)
+}
+
+fn if_statement(p: &mut Parser) -> Result<Stmt, String> {
+    let start = p.last_span;
+    consume(p, TokenType::LeftParen, "expected '(' after 'if'")?;
+    let condition = expression(p)?;
+    consume(p, TokenType::RightParen, "expected ')' after if condition")?;
+    let then_branch = Box::new(statement(p)?);
+    let else_branch = if match_token(p, &[TokenType::Else]) {
+        Some(Box::new(statement(p)?))
     } else {
-        if token_matches(&t, &[TokenType::False]) {
-            Ok(Expr::BoolLiteral(false))
-        } else if token_matches(&t, &[TokenType::True]) {
-            Ok(Expr::BoolLiteral(true))
-        } else if token_matches(&t, &[TokenType::Nil]) {
-            Ok(Expr::NilLiteral())
-        } else if token_matches(&t, &[TokenType::Number]) {
-            match t.lexeme.parse::<i64>() {
-                Err(_) => {
-                    // Handle errors here
-                    let res = t.lexeme.parse::<f64>().unwrap();
-                    Ok(Expr::FloatLiteral(res))
-                }
-                Ok(v) => Ok(Expr::IntLiteral(v)),
+        None
+    };
+    Ok(Stmt::If(condition, then_branch, else_branch, start.to(p.last_span)))
+}
+
+fn try_statement(p: &mut Parser) -> Result<Stmt, String> {
+    let keyword = advance(p);
+    let body = Box::new(block_statement(p, "expected '{' after 'try'")?);
+    let catch = if match_token(p, &[TokenType::Catch]) {
+        consume(p, TokenType::LeftParen, "expected '(' after 'catch'")?;
+        let name = consume(p, TokenType::Identifier, "expected variable name for the caught value")?;
+        consume(p, TokenType::RightParen, "expected ')' after catch variable")?;
+        Some((name, Box::new(block_statement(p, "expected '{' before catch block")?)))
+    } else {
+        None
+    };
+    let finally = if match_token(p, &[TokenType::Finally]) {
+        Some(Box::new(block_statement(p, "expected '{' after 'finally'")?))
+    } else {
+        None
+    };
+    if catch.is_none() && finally.is_none() {
+        return parse_error(&keyword, String::from("expected 'catch' or 'finally' after try block"));
+    }
+    Ok(Stmt::Try(body, catch, finally, keyword.span.to(p.last_span)))
+}
+
+// A block that the grammar requires, rather than any statement
+fn block_statement(p: &mut Parser, msg: &str) -> Result<Stmt, String> {
+    let open = consume(p, TokenType::LeftBrace, msg)?;
+    let statements = block(p)?;
+    Ok(Stmt::Block(statements, open.span.to(p.last_span)))
+}
+
+/**
+ * `for` loops are kept as their own node rather than desugared into a `while`, so that tools see the loop as written.
+ */
+fn for_statement(p: &mut Parser) -> Result<Stmt, String> {
+    let start = p.last_span;
+    consume(p, TokenType::LeftParen, "expected '(' after 'for'")?;
+    let initializer = if match_token(p, &[TokenType::Semicolon]) {
+        None
+    } else if match_token(p, &[TokenType::Var]) {
+        Some(var_declaration(p, None)?)
+    } else {
+        let expr_start = peek_span(p);
+        let expr = expression(p)?;
+        consume(p, TokenType::Semicolon, "expected ';' after loop initializer")?;
+        Some(Stmt::Expression(expr, expr_start.to(p.last_span)))
+    };
+    let condition = if check(p, &[TokenType::Semicolon]) {
+        None
+    } else {
+        Some(expression(p)?)
+    };
+    consume(p, TokenType::Semicolon, "expected ';' after loop condition")?;
+    let increment = if check(p, &[TokenType::RightParen]) {
+        None
+    } else {
+        Some(expression(p)?)
+    };
+    consume(p, TokenType::RightParen, "expected ')' after for clauses")?;
+
+    let body = Box::new(statement(p)?);
+    Ok(Stmt::For(initializer.map(Box::new), condition, increment, body, start.to(p.last_span)))
+}
+
+/*
+ * Expressions
+ */
+
+pub fn expression(p: &mut Parser) -> Result<Expr, String> {
+    expression_bp(p, 0)
+}
+
+/**
+ * Which way a chain of operators with the same binding power groups: `a - b - c` is `(a - b) - c`, but `a = b = c`
+ * is `a = (b = c)`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Assoc {
+    Left,
+    Right,
+}
+
+/**
+ * How an operator that comes before its operand is parsed. The operand can only hold operators binding tighter than
+ * `bp`.
+ */
+struct PrefixRule {
+    token: TokenType,
+    bp: u8,
+    parse: fn(&mut Parser, Token, u8) -> Result<Expr, String>,
+}
+
+/**
+ * How an operator that comes after an expression is parsed: a binary operator, the `?:` mixfix operator, or a
+ * postfix call or property access. `bp` is how tightly the operator holds on to the expression before it; postfix
+ * operators have nothing after them, so ignore their associativity.
+ */
+struct InfixRule {
+    token: TokenType,
+    bp: u8,
+    assoc: Assoc,
+    parse: fn(&mut Parser, Expr, Token, u8) -> Result<Expr, String>,
+}
+impl InfixRule {
+    // The binding power operators in the expression after this one need to be part of it
+    fn right_bp(&self) -> u8 {
+        match self.assoc {
+            Assoc::Left => self.bp,
+            Assoc::Right => self.bp - 1,
+        }
+    }
+}
+
+/*
+ * Binding powers, from loosest to tightest
+ */
+const COMMA: u8 = 1;
+// As in C, `?:` is on the same level as `=`, so `a = b ? c : d` assigns the result of the `?:`, and its last branch
+// can be an assignment
+const ASSIGNMENT: u8 = 2;
+const OR: u8 = 3;
+const AND: u8 = 4;
+const EQUALITY: u8 = 5;
+const COMPARISON: u8 = 6;
+const TERM: u8 = 7;
+const FACTOR: u8 = 8;
+const UNARY: u8 = 9;
+const CALL: u8 = 10;
+
+const PREFIX_RULES: [PrefixRule; 2] = [
+    PrefixRule { token: TokenType::Bang, bp: UNARY, parse: unary },
+    PrefixRule { token: TokenType::Minus, bp: UNARY, parse: unary },
+];
+
+const INFIX_RULES: [InfixRule; 19] = [
+    InfixRule { token: TokenType::Comma, bp: COMMA, assoc: Assoc::Left, parse: sequence },
+    InfixRule { token: TokenType::Equal, bp: ASSIGNMENT, assoc: Assoc::Right, parse: assignment },
+    InfixRule { token: TokenType::QuestionMark, bp: ASSIGNMENT, assoc: Assoc::Right, parse: ternary },
+    InfixRule { token: TokenType::Or, bp: OR, assoc: Assoc::Left, parse: logical },
+    InfixRule { token: TokenType::And, bp: AND, assoc: Assoc::Left, parse: logical },
+    InfixRule { token: TokenType::BangEqual, bp: EQUALITY, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::EqualEqual, bp: EQUALITY, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::Greater, bp: COMPARISON, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::GreaterEqual, bp: COMPARISON, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::Less, bp: COMPARISON, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::LessEqual, bp: COMPARISON, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::Plus, bp: TERM, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::Minus, bp: TERM, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::Slash, bp: FACTOR, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::Star, bp: FACTOR, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::Percent, bp: FACTOR, assoc: Assoc::Left, parse: binary },
+    InfixRule { token: TokenType::LeftParen, bp: CALL, assoc: Assoc::Left, parse: call },
+    InfixRule { token: TokenType::Dot, bp: CALL, assoc: Assoc::Left, parse: property },
+    InfixRule { token: TokenType::LeftBracket, bp: CALL, assoc: Assoc::Left, parse: index },
+];
+
+/**
+ * Parse an expression made up of operators that bind tighter than `min_bp`, leaving any looser operator after it for
+ * the caller.
+ */
+fn expression_bp(p: &mut Parser, min_bp: u8) -> Result<Expr, String> {
+    if peek(p).is_none() {
+        return parse_error(&end_of_input(p), String::from("reached EOF"));
+    }
+    let t = advance(p);
+    let mut left = match PREFIX_RULES.iter().find(|rule| rule.token == t.token_type) {
+        Some(rule) => (rule.parse)(p, t, rule.bp)?,
+        None => primary(p, t)?,
+    };
+    loop {
+        let next = peek(p).map(|t| t.token_type);
+        let rule = match INFIX_RULES.iter().find(|rule| Some(rule.token) == next) {
+            Some(rule) if rule.bp > min_bp => rule,
+            _ => break,
+        };
+        let op = advance(p);
+        left = (rule.parse)(p, left, op, rule.right_bp())?;
+    }
+    Ok(left)
+}
+
+fn unary(p: &mut Parser, op: Token, bp: u8) -> Result<Expr, String> {
+    let right = expression_bp(p, bp)?;
+    Ok(Expr::Unary(Box::new(right), op))
+}
+
+fn binary(p: &mut Parser, left: Expr, op: Token, right_bp: u8) -> Result<Expr, String> {
+    let right = expression_bp(p, right_bp)?;
+    Ok(Expr::Binary(Box::new(left), op, Box::new(right)))
+}
+
+// `a, b, c` is a single sequence rather than one nested in another
+fn sequence(p: &mut Parser, left: Expr, _: Token, right_bp: u8) -> Result<Expr, String> {
+    let right = expression_bp(p, right_bp)?;
+    let mut expressions = match left {
+        Expr::Sequence(expressions) => expressions,
+        left => vec![left],
+    };
+    expressions.push(right);
+    Ok(Expr::Sequence(expressions))
+}
+
+fn logical(p: &mut Parser, left: Expr, op: Token, right_bp: u8) -> Result<Expr, String> {
+    let right = expression_bp(p, right_bp)?;
+    Ok(Expr::Logical(Box::new(left), op, Box::new(right)))
+}
+
+fn assignment(p: &mut Parser, target: Expr, equals: Token, right_bp: u8) -> Result<Expr, String> {
+    let value = expression_bp(p, right_bp)?;
+    match target {
+        Expr::Variable(name) => Ok(Expr::Assign(name, Box::new(value))),
+        Expr::Index(object, index, bracket) => Ok(Expr::SetIndex(object, index, bracket, Box::new(value))),
+        _ => parse_error(&equals, String::from("invalid assignment target")),
+    }
+}
+
+// Anything can go between the `?` and the `:`, since they bracket it
+fn ternary(p: &mut Parser, condition: Expr, _: Token, right_bp: u8) -> Result<Expr, String> {
+    let if_true = expression(p)?;
+    consume(p, TokenType::Colon, "expected ':' after the first branch of '?'")?;
+    let if_false = expression_bp(p, right_bp)?;
+    Ok(Expr::Ternary(Box::new(condition), Box::new(if_true), Box::new(if_false)))
+}
+
+fn call(p: &mut Parser, callee: Expr, _: Token, _: u8) -> Result<Expr, String> {
+    let mut arguments = vec![];
+    if !check(p, &[TokenType::RightParen]) {
+        loop {
+            // Commas separate the arguments, so they can't be comma expressions
+            arguments.push(expression_bp(p, COMMA)?);
+            if !match_token(p, &[TokenType::Comma]) {
+                break;
             }
-        } else if token_matches(&t, &[TokenType::Str]) {
-            Ok(Expr::StringLiteral(t.lexeme))
-        } else if token_matches(&t, &[TokenType::LeftParen]) {
-            match expression(p) {
-                Ok(sub) => {
-                    if p.tokens.len() == 0 {
-                      parse_error(&t, String::from("reached EOF"))
+        }
+    }
+    let paren = consume(p, TokenType::RightParen, "expected ')' after arguments")?;
+    Ok(Expr::Call(Box::new(callee), paren, arguments))
+}
+
+fn property(p: &mut Parser, object: Expr, _: Token, _: u8) -> Result<Expr, String> {
+    let name = consume(p, TokenType::Identifier, "expected property name after '.'")?;
+    Ok(Expr::Get(Box::new(object), name))
+}
+
+// Either `xs[i]` or a slice like `xs[i:j]`, where both ends are optional. As with `?:`, the brackets mean anything
+// can go inside them.
+fn index(p: &mut Parser, object: Expr, _: Token, _: u8) -> Result<Expr, String> {
+    let start = if check(p, &[TokenType::Colon]) { None } else { Some(Box::new(expression(p)?)) };
+    if !match_token(p, &[TokenType::Colon]) {
+        let bracket = consume(p, TokenType::RightBracket, "expected ']' after index")?;
+        // The start is only left out when a ':' follows
+        return Ok(Expr::Index(Box::new(object), start.unwrap(), bracket));
+    }
+    let end = if check(p, &[TokenType::RightBracket]) { None } else { Some(Box::new(expression(p)?)) };
+    let bracket = consume(p, TokenType::RightBracket, "expected ']' after slice")?;
+    Ok(Expr::Slice(Box::new(object), start, end, bracket))
+}
+
+fn primary(p: &mut Parser, t: Token) -> Result<Expr, String> {
+    if peek(p).is_none() {
+      parse_error(&end_of_input(p), String::from("reached EOF"))
+    } else if token_matches(&t, &[TokenType::False]) {
+        Ok(Expr::BoolLiteral(false, t.span))
+    } else if token_matches(&t, &[TokenType::True]) {
+        Ok(Expr::BoolLiteral(true, t.span))
+    } else if token_matches(&t, &[TokenType::Nil]) {
+        Ok(Expr::NilLiteral(t.span))
+    } else if token_matches(&t, &[TokenType::Number]) {
+        match t.literal {
+            Literal::Int(v) => Ok(Expr::IntLiteral(v, t.span)),
+            Literal::Float(v) => Ok(Expr::FloatLiteral(v, t.span)),
+            _ => parse_error(&t, String::from("number token is missing its value")),
+        }
+    } else if token_matches(&t, &[TokenType::Str]) {
+        string_part(&t)
+    } else if token_matches(&t, &[TokenType::Interpolation]) {
+        interpolation(p, t)
+    } else if token_matches(&t, &[TokenType::Identifier]) {
+        Ok(Expr::Variable(t))
+    } else if token_matches(&t, &[TokenType::LeftBracket]) {
+        let mut elements = vec![];
+        while !check(p, &[TokenType::RightBracket]) {
+            // Commas separate the elements, as they do call arguments
+            elements.push(expression_bp(p, COMMA)?);
+            if !match_token(p, &[TokenType::Comma]) {
+                break;
+            }
+        }
+        let end = consume(p, TokenType::RightBracket, "expected ']' after list elements")?;
+        Ok(Expr::List(elements, t.span.to(end.span)))
+    } else if token_matches(&t, &[TokenType::LeftBrace]) {
+        let mut entries = vec![];
+        while !check(p, &[TokenType::RightBrace]) {
+            let key = expression_bp(p, COMMA)?;
+            consume(p, TokenType::Colon, "expected ':' after map key")?;
+            entries.push((key, expression_bp(p, COMMA)?));
+            if !match_token(p, &[TokenType::Comma]) {
+                break;
+            }
+        }
+        let end = consume(p, TokenType::RightBrace, "expected '}' after map entries")?;
+        Ok(Expr::Map(entries, t.span.to(end.span)))
+    } else if token_matches(&t, &[TokenType::Func]) {
+        consume(p, TokenType::LeftParen, "expected '(' after 'func'")?;
+        let params = parameters(p)?;
+        let body = function_body(p)?;
+        Ok(Expr::Lambda(lambda(t, params, body, p.last_span), false))
+    } else if token_matches(&t, &[TokenType::LeftParen]) && arrow_ahead(p) {
+        let params = parameters(p)?;
+        consume(p, TokenType::Arrow, "expected '=>' after parameters")?;
+        // Like a call argument, the body stops at a comma so that lambdas can be passed to functions
+        let value = expression_bp(p, COMMA)?;
+        let span = value.span();
+        Ok(Expr::Lambda(lambda(t, params, vec![Stmt::Return(Some(value), span)], p.last_span), true))
+    } else if token_matches(&t, &[TokenType::LeftParen]) {
+        match expression(p) {
+            Ok(sub) => {
+                if peek(p).is_none() {
+                  parse_error(&t, String::from("reached EOF"))
+                } else {
+                    let end = advance(p);
+                    if token_matches(&end, &[TokenType::RightParen]) {
+                        Ok(Expr::Grouping(Box::new(sub), t.span.to(end.span)))
                     } else {
-                        let end = p.tokens.pop_front().unwrap();
-                        if token_matches(&end, &[TokenType::RightParen]) {
-                            Ok(Expr::Grouping(Box::new(sub)))
-                        } else {
-                          parse_error(&end, String::from("could not find matching right paren"))
-                        }
+                      parse_error(&end, String::from("could not find matching right paren"))
                     }
                 }
-                e @ Err(_) => e,
             }
+            e @ Err(_) => e,
+        }
+    } else {
+        parse_error(&t, String::from("unknown token"))
+    }
+}
+
+/**
+ * Whether the '(' just consumed starts the parameters of an arrow function: a list of names and a ')', followed by
+ * '=>'. Anything else is a parenthesized expression.
+ */
+fn arrow_ahead(p: &mut Parser) -> bool {
+    let mut n = 0;
+    while peek_nth(p, n).is_some_and(|t| t.token_type == TokenType::Identifier) {
+        n += 1;
+        if !peek_nth(p, n).is_some_and(|t| t.token_type == TokenType::Comma) {
+            break;
+        }
+        n += 1;
+    }
+    peek_nth(p, n).is_some_and(|t| t.token_type == TokenType::RightParen)
+        && peek_nth(p, n + 1).is_some_and(|t| t.token_type == TokenType::Arrow)
+}
+
+// Anonymous functions are named after the token they start with, which is where a traceback points to
+fn lambda(start: Token, params: Vec<Token>, body: Vec<Stmt>, end: Span) -> Rc<FunctionDecl> {
+    let span = start.span.to(end);
+    let name = Token { lexeme: String::from("<lambda>"), comments: vec![], ..start };
+    Rc::new(FunctionDecl { name, params, body, doc: None, span })
+}
+
+/**
+ * Parse the rest of an interpolated string, given the token for its first part. The scanner has already split the
+ * string up, so each expression is followed either by the next part or by the `Str` that ends the string.
+ */
+fn interpolation(p: &mut Parser, head: Token) -> Result<Expr, String> {
+    let mut parts = vec![string_part(&head)?];
+    loop {
+        parts.push(expression(p)?);
+        if check(p, &[TokenType::Interpolation]) {
+            let t = advance(p);
+            parts.push(string_part(&t)?);
         } else {
-            parse_error(&t, String::from("unknown token"))
+            let t = consume(p, TokenType::Str, "expected '}' after interpolated expression")?;
+            parts.push(string_part(&t)?);
+            return Ok(Expr::Interpolation(parts));
         }
     }
 }
 
-fn parse_error(t: &Token, msg: String) -> Result<Expr, String> {
+fn string_part(t: &Token) -> Result<Expr, String> {
+    match &t.literal {
+        Literal::Str(s) => Ok(Expr::StringLiteral(s.clone(), t.span)),
+        _ => parse_error(t, String::from("string token is missing its value")),
+    }
+}
+
+fn parse_error<T>(t: &Token, msg: String) -> Result<T, String> {
   if t.token_type == TokenType::Eof {
     Err(format!("[line {}] Error at end: {}", t.line, msg))
   } else {
@@ -169,6 +690,77 @@ fn parse_error(t: &Token, msg: String) -> Result<Expr, String> {
   }
 }
 
+/*
+ * Token helpers
+ */
+
+pub fn is_at_end(p: &mut Parser) -> bool {
+    peek(p).is_none_or(|t| t.token_type == TokenType::Eof)
+}
+
+// The token <n> places ahead, scanning as far as that if need be. `None` once the tokens have run out.
+fn peek_nth(p: &mut Parser, n: usize) -> Option<&Token> {
+    while p.lookahead.len() <= n && p.scan_error.is_none() {
+        match p.tokens.next() {
+            Some(Ok(t)) => p.lookahead.push_back(t),
+            Some(Err(e)) => p.scan_error = Some(e),
+            None => break,
+        }
+    }
+    p.lookahead.get(n)
+}
+
+fn peek(p: &mut Parser) -> Option<&Token> {
+    peek_nth(p, 0)
+}
+
+// Figure out if the next token matches any type in <types>, without consuming it
+fn check(p: &mut Parser, types: &[TokenType]) -> bool {
+    peek(p).is_some_and(|t| token_matches(t, types))
+}
+
+// Consume the next token if it matches any type in <types>
+fn match_token(p: &mut Parser, types: &[TokenType]) -> bool {
+    if check(p, types) {
+        advance(p);
+        true
+    } else {
+        false
+    }
+}
+
+fn advance(p: &mut Parser) -> Token {
+    peek(p);
+    let t = p.lookahead.pop_front().unwrap();
+    p.last_span = t.span;
+    t
+}
+
+// Stands in for the token after the last one, so errors there report the line the last token was on
+fn end_of_input(p: &Parser) -> Token {
+    Token::new(TokenType::Eof, String::from(""), Literal::None, p.last_span.line.max(1))
+}
+
+// Span of the next token, without consuming it
+fn peek_span(p: &mut Parser) -> Span {
+    match peek(p) {
+        Some(t) => t.span,
+        None => p.last_span,
+    }
+}
+
+// Consume the next token, which must be of type <ty>
+fn consume(p: &mut Parser, ty: TokenType, msg: &str) -> Result<Token, String> {
+    if check(p, &[ty]) {
+        Ok(advance(p))
+    } else {
+        match peek(p) {
+            Some(t) => parse_error(t, String::from(msg)),
+            None => parse_error(&end_of_input(p), String::from(msg)),
+        }
+    }
+}
+
 // Figure out if <t> matches any type in <types>
 fn token_matches(t: &Token, types: &[TokenType]) -> bool {
     for ty in types {
@@ -176,5 +768,69 @@ fn token_matches(t: &Token, types: &[TokenType]) -> bool {
             return true;
         }
     }
-    return false;
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+    use crate::serialize::program_to_sexpr;
+
+    fn parse(source: &str) -> Result<String, String> {
+        program(&mut Parser::new(Scanner::new(source.to_string()))).map(|statements| program_to_sexpr(&statements))
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(parse("1 + 2 * 3 - 4 / 2;").unwrap(), "(expr (- (+ 1 (* 2 3)) (/ 4 2)))");
+        assert_eq!(parse("-a.b(c)[0] - !d;").unwrap(), "(expr (- (- (index (call (. a b) c) 0)) (! d)))");
+        assert_eq!(parse("a or b and !c == d < e;").unwrap(), "(expr (or a (and b (== (! c) (< d e)))))");
+        assert_eq!(parse("a = b = c;").unwrap(), "(expr (= a (= b c)))");
+        assert_eq!(parse("(1 + 2) * 3;").unwrap(), "(expr (* (group (+ 1 2)) 3))");
+    }
+
+    #[test]
+    fn ternaries_and_sequences() {
+        assert_eq!(parse("a = b ? c : d ? e : f;").unwrap(), "(expr (= a (? b c (? d e f))))");
+        assert_eq!(parse("a ? b ? c : d : e;").unwrap(), "(expr (? a (? b c d) e))");
+        assert_eq!(parse("1, 2, 3;").unwrap(), "(expr (, 1 2 3))");
+        assert_eq!(parse("a = 1, b = 2;").unwrap(), "(expr (, (= a 1) (= b 2)))");
+        assert_eq!(parse("x ? 1, 2 : 3;").unwrap(), "(expr (? x (, 1 2) 3))");
+        // Commas between arguments aren't sequences, unless they're in parentheses
+        assert_eq!(parse("f((1, 2), 3);").unwrap(), "(expr (call f (group (, 1 2)) 3))");
+    }
+
+    #[test]
+    fn arrow_lambdas() {
+        assert_eq!(parse("(a, b) => a + b;").unwrap(), "(expr (=> (a b) (+ a b)))");
+        assert_eq!(parse("() => 1;").unwrap(), "(expr (=> () 1))");
+        assert_eq!(parse("(a) => (b) => a + b;").unwrap(), "(expr (=> (a) (=> (b) (+ a b))))");
+        assert_eq!(parse("f((x) => x * 2, 10);").unwrap(), "(expr (call f (=> (x) (* x 2)) 10))");
+        // Without the arrow, the same tokens are just parentheses
+        assert_eq!(parse("(a, b);").unwrap(), "(expr (group (, a b)))");
+        assert_eq!(parse("(a);").unwrap(), "(expr (group a))");
+        assert!(parse("(a, 1) => a;").is_err());
+    }
+
+    #[test]
+    fn maps_and_blocks_at_statement_start() {
+        assert_eq!(parse("{-1: \"x\"};").unwrap(), "(expr (map ((- 1) \"x\")))");
+        assert_eq!(parse("{\"${k}\": 1};").unwrap(), "(expr (map ((interpolate \"\" k \"\") 1)))");
+        assert_eq!(parse("{a ? b : c: 1};").unwrap(), "(expr (map ((? a b c) 1)))");
+        assert_eq!(parse("{ x = a ? b : c; }").unwrap(), "(block (expr (= x (? a b c))))");
+        assert_eq!(parse("{ f({\"k\": 1}); }").unwrap(), "(block (expr (call f (map (\"k\" 1)))))");
+        assert_eq!(parse("{}").unwrap(), "(block)");
+    }
+
+    #[test]
+    fn errors_at_the_end_report_the_last_line() {
+        assert_eq!(parse("print (1 + ").unwrap_err(), "[line 1] Error at end: reached EOF");
+        assert_eq!(parse("print 1;\nprint (1 + ").unwrap_err(), "[line 2] Error at end: reached EOF");
+        assert_eq!(
+            parse("print 1;\nprint (1 + 2").unwrap_err(),
+            "[line 2] Error at end: could not find matching right paren"
+        );
+        assert_eq!(parse("var x = 2").unwrap_err(), "[line 1] Error at end: expected ';' after variable declaration");
+    }
 }
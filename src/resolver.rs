@@ -0,0 +1,128 @@
+use crate::scanner::Token;
+use crate::syntax_tree::{walk_expr, walk_stmt, Expr, Stmt, Visitor};
+use std::collections::HashMap;
+
+/**
+ * Static checks that run after parsing and before execution. The resolver walks the tree tracking which names are
+ * declared in each local scope, and reports mistakes that can be caught without running the program.
+ */
+pub struct Resolver {
+    // One map per local scope; the value records whether the variable's initializer has finished resolving.
+    // Globals aren't tracked, since they may legally be defined after the code that refers to them.
+    scopes: Vec<HashMap<String, bool>>,
+    errors: Vec<String>,
+}
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver { scopes: vec![], errors: vec![] }
+    }
+}
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ * Resolve a whole program, returning every error found.
+ */
+pub fn resolve(statements: &[Stmt]) -> Result<(), Vec<String>> {
+    let mut r = Resolver::new();
+    for statement in statements {
+        r.visit_stmt(statement);
+    }
+    if r.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(r.errors)
+    }
+}
+
+impl Visitor for Resolver {
+    fn visit_stmt(&mut self, s: &Stmt) {
+        match s {
+            Stmt::Var(name, initializer, ..) => {
+                declare(self, name);
+                if let Some(e) = initializer {
+                    self.visit_expr(e);
+                }
+                define(self, name);
+            }
+            Stmt::Block(..) | Stmt::For(..) => {
+                self.scopes.push(HashMap::new());
+                walk_stmt(self, s);
+                self.scopes.pop();
+            }
+            Stmt::Function(decl) => {
+                // Define the name eagerly so the function can refer to itself recursively
+                declare(self, &decl.name);
+                define(self, &decl.name);
+                self.scopes.push(HashMap::new());
+                for param in &decl.params {
+                    declare(self, param);
+                    define(self, param);
+                }
+                walk_stmt(self, s);
+                self.scopes.pop();
+            }
+            Stmt::Try(body, catch, finally, _) => {
+                self.visit_stmt(body);
+                if let Some((name, handler)) = catch {
+                    self.scopes.push(HashMap::new());
+                    declare(self, name);
+                    define(self, name);
+                    self.visit_stmt(handler);
+                    self.scopes.pop();
+                }
+                if let Some(finally) = finally {
+                    self.visit_stmt(finally);
+                }
+            }
+            _ => walk_stmt(self, s),
+        }
+    }
+
+    fn visit_expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Variable(name) => {
+                if let Some(scope) = self.scopes.last() {
+                    if scope.get(&name.lexeme) == Some(&false) {
+                        error(self, name, "can't read local variable in its own initializer");
+                    }
+                }
+            }
+            Expr::Lambda(decl, _) => {
+                self.scopes.push(HashMap::new());
+                for param in &decl.params {
+                    declare(self, param);
+                    define(self, param);
+                }
+                walk_expr(self, e);
+                self.scopes.pop();
+                return;
+            }
+            _ => (),
+        }
+        walk_expr(self, e);
+    }
+}
+
+fn declare(r: &mut Resolver, name: &Token) {
+    if let Some(scope) = r.scopes.last_mut() {
+        if scope.contains_key(&name.lexeme) {
+            error(r, name, "already a variable with this name in this scope");
+        } else {
+            scope.insert(name.lexeme.clone(), false);
+        }
+    }
+}
+
+fn define(r: &mut Resolver, name: &Token) {
+    if let Some(scope) = r.scopes.last_mut() {
+        scope.insert(name.lexeme.clone(), true);
+    }
+}
+
+fn error(r: &mut Resolver, t: &Token, msg: &str) {
+    r.errors.push(format!("[line {}] Error at '{}': {}", t.line, t.lexeme, msg));
+}
@@ -1,14 +1,32 @@
-use std::any::Any;
 use std::collections::HashMap;
-use crate::err;
+use std::fmt;
+use crate::json::Json;
 
+/**
+ * Turns source code into tokens, one at a time as they're asked for. The last token is always an `Eof`, unless
+ * scanning fails, in which case the error is the last thing produced.
+ */
 pub struct Scanner {
-    source: Vec<u8>,
-    pub tokens: Vec<Token>,
+    source: String,
+    // Byte offsets into `source`, always on a character boundary
     pub start: usize,
     pub cur: usize,
     pub line: u32,
+    // Offset of the first character of the current line, used to work out columns
+    line_start: usize,
+    // Where the token currently being scanned started; `line` may have moved on by the time it's added
+    start_line: u32,
+    start_column: u32,
+    // Comments seen since the last token, waiting to be attached to the next one
+    pending_comments: Vec<Comment>,
+    // One entry for each `${` we're inside of: where it starts, how many `{`s inside it haven't been closed yet, and
+    // how the string it's in is quoted
+    interpolations: Vec<(usize, u32, Quoting)>,
     pub reserved_identifiers: HashMap<String, TokenType>,
+    // The token the last call to `scan_token` produced, if it produced one
+    token: Option<Token>,
+    // Set once the `Eof` token or an error has been returned
+    finished: bool,
 }
 impl Scanner {
     /**
@@ -16,63 +34,103 @@ impl Scanner {
      */
     pub fn new(source: String) -> Scanner {
         Scanner {
-            source: source.bytes().collect(),
-            tokens: vec![],
+            source,
             start: 0,
             cur: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
+            pending_comments: vec![],
+            interpolations: vec![],
             reserved_identifiers: reserved_identifiers(),
+            token: None,
+            finished: false,
         }
     }
 
 
-    pub fn scan_tokens(&mut self) -> Result<(), String> {
+    /**
+     * Scan up to the end of the next token, skipping whitespace and collecting comments on the way.
+     */
+    fn scan_next(&mut self) -> Result<Token, ScanError> {
         while self.cur < self.source.len() {
             self.start = self.cur;
-            let res: Result<(), String> = self.scan_token();
-            if !res.is_ok() {
-                return res;
+            self.start_line = self.line;
+            self.start_column = self.column(self.start);
+            self.scan_token()?;
+            if let Some(token) = self.token.take() {
+                return Ok(token);
             }
         }
-        Ok(self.tokens.push(Token::new(
-            TokenType::Eof,
-            "".to_string(),
-            Box::new(0),
-            self.line,
-        )))
+        if let Some((start, ..)) = self.interpolations.first() {
+            // The `${` may be on an earlier line, so its position has to be worked out from scratch
+            let before = &self.source[..*start];
+            let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+            let line = before.matches('\n').count() as u32 + 1;
+            let column = before[line_start..].chars().count() as u32 + 1;
+            let span = Span { start: *start, end: self.cur, line, column };
+            return Err(ScanError { message: "unterminated string interpolation".to_string(), span });
+        }
+        self.start = self.cur;
+        self.start_line = self.line;
+        self.start_column = self.column(self.start);
+        self.add_token(TokenType::Eof)?;
+        Ok(self.token.take().unwrap())
     }
 
-    fn scan_token(&mut self) -> Result<(), String> {
-        //TODO: This only holds if we use ASCII strings ONLY
-        let c = self.advance() as char;
+    fn scan_token(&mut self) -> Result<(), ScanError> {
+        let c = self.advance();
         match c {
-            '(' => Ok(self.add_token(TokenType::LeftParen)),
-            ')' => Ok(self.add_token(TokenType::RightParen)),
-            '{' => Ok(self.add_token(TokenType::LeftBrace)),
-            '}' => Ok(self.add_token(TokenType::RightBrace)),
-            ',' => Ok(self.add_token(TokenType::Comma)),
-            '.' => Ok(self.add_token(TokenType::Dot)),
-            '-' => Ok(self.add_token(TokenType::Minus)),
-            '+' => Ok(self.add_token(TokenType::Plus)),
-            ';' => Ok(self.add_token(TokenType::Semicolon)),
-            '*' => Ok(self.add_token(TokenType::Star)),
-            '?' => Ok(self.add_token(TokenType::QuestionMark)),
-            ':' => Ok(self.add_token(TokenType::Colon)),
+            '(' => self.add_token(TokenType::LeftParen),
+            ')' => self.add_token(TokenType::RightParen),
+            '{' => {
+                if let Some((_, depth, _)) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.add_token(TokenType::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                // The end of an interpolated expression, so carry on with the rest of the string
+                Some((_, 0, quoting)) => {
+                    let quoting = *quoting;
+                    self.interpolations.pop();
+                    self.string(quoting)
+                }
+                Some((_, depth, _)) => {
+                    *depth -= 1;
+                    self.add_token(TokenType::RightBrace)
+                }
+                None => self.add_token(TokenType::RightBrace),
+            },
+            '[' => self.add_token(TokenType::LeftBracket),
+            ']' => self.add_token(TokenType::RightBracket),
+            ',' => self.add_token(TokenType::Comma),
+            '.' => self.add_token(TokenType::Dot),
+            '-' => self.add_token(TokenType::Minus),
+            '+' => self.add_token(TokenType::Plus),
+            ';' => self.add_token(TokenType::Semicolon),
+            '*' => self.add_token(TokenType::Star),
+            '%' => self.add_token(TokenType::Percent),
+            '?' => self.add_token(TokenType::QuestionMark),
+            ':' => self.add_token(TokenType::Colon),
             '!' => {
                 let matched_char = if self.match_char('=') {
                     TokenType::BangEqual
                 } else {
                     TokenType::Bang
                 };
-                Ok(self.add_token(matched_char))
+                self.add_token(matched_char)
             }
             '=' => {
                 let matched_char = if self.match_char('=') {
                     TokenType::EqualEqual
+                } else if self.match_char('>') {
+                    TokenType::Arrow
                 } else {
                     TokenType::Equal
                 };
-                Ok(self.add_token(matched_char))
+                self.add_token(matched_char)
             }
             '<' => {
                 let matched_char = if self.match_char('=') {
@@ -80,7 +138,7 @@ impl Scanner {
                 } else {
                     TokenType::Less
                 };
-                Ok(self.add_token(matched_char))
+                self.add_token(matched_char)
             }
             '>' => {
                 let matched_char = if self.match_char('=') {
@@ -88,7 +146,7 @@ impl Scanner {
                 } else {
                     TokenType::Greater
                 };
-                Ok(self.add_token(matched_char))
+                self.add_token(matched_char)
             }
             '/' => {
                 if self.match_char('/') {
@@ -96,40 +154,45 @@ impl Scanner {
                     while self.peek() != '\n' && self.cur < self.source.len() {
                         self.advance();
                     }
-                    Ok(())
+                    self.add_comment()
                 } else if self.match_char('*') {
-                    // If a multiline comment, advance until you see the closing */
-                    let mut star_found = false;
-                    while self.cur < self.source.len() {
-                        if self.peek() == '\n' {
-                            self.line += 1;
-                        } else if self.peek() == '/' && star_found {
-                            self.advance();
-                            break;
-                        } else if self.peek() == '*' {
-                            star_found = true;
-                        } else {
-                            star_found = false;
+                    // Block comments nest, so keep going until every `/*` has been closed
+                    let mut depth = 1;
+                    while depth > 0 {
+                        if self.cur >= self.source.len() {
+                            let span = Span { end: self.start + 2, ..self.current_span() };
+                            return Err(ScanError { message: "unterminated block comment".to_string(), span });
+                        }
+                        match self.advance() {
+                            '\n' => {
+                                self.line += 1;
+                                self.line_start = self.cur;
+                            }
+                            '/' if self.match_char('*') => depth += 1,
+                            '*' if self.match_char('/') => depth -= 1,
+                            _ => (),
                         }
-                        // Don't advance until the end of the loop iteration. This is because match_char also advances, so we don't want to skip
-                        // over the character immediately following the '/*'
-                        self.advance();
                     }
-                    Ok(())
+                    self.add_comment()
                 } else {
-                    Ok(self.add_token(TokenType::Slash))
+                    self.add_token(TokenType::Slash)
                 }
             }
             // Ignore whitespace
             ' ' | '\r' | '\t' => Ok(()),
-            '\n' => Ok(self.line += 1),
-            '"' => self.string(),
-            x if is_digit(x) => self.number(x),
+            '\n' => {
+                self.line += 1;
+                self.line_start = self.cur;
+                Ok(())
+            }
+            '"' => self.open_string(false),
+            'r' if self.peek() == '"' => {
+                self.advance();
+                self.open_string(true)
+            }
+            x if is_digit(x) => self.number(),
             x if is_alpha(x) => self.identifier(),
-            x => {
-                println!("Current tokens: {:?}", self.tokens);
-                err(self.line, format!("Unexpected token `{}` ({}).", x, x as usize).as_str())
-            },
+            x => Err(self.error(self.start, format!("Unexpected character `{}`.", x.escape_debug()))),
         }
     }
 
@@ -138,10 +201,10 @@ impl Scanner {
      * the comparison.
      */
     fn match_char(&mut self, expected: char) -> bool {
-        if self.cur == self.source.len() || self.source[self.cur] as char != expected {
+        if self.cur == self.source.len() || self.peek() != expected {
             false
         } else {
-            self.cur += 1;
+            self.advance();
             true
         }
     }
@@ -150,105 +213,318 @@ impl Scanner {
      * Return the current character without advancing `cur`.
      */
     fn peek(&self) -> char {
-        if self.cur == self.source.len() {
-            '\0'
-        } else {
-            self.source[self.cur] as char
-        }
+        self.source[self.cur..].chars().next().unwrap_or('\0')
     }
 
     /**
      * Return the next character without advancing `cur`. Use for single-character lookahead.
      */
     fn peek_next(&self) -> char {
-        if self.cur + 1 > self.source.len() { 
-            '\0'
-        } else {
-            self.source[self.cur + 1] as char
-        }
+        self.source[self.cur..].chars().nth(1).unwrap_or('\0')
     }
 
     /**
-     * Returns the u8 at the current position in the scanner and increments the current position _afterwards_.
+     * Returns the character at the current position in the scanner and moves past it _afterwards_.
      */
-    fn advance(&mut self) -> u8 {
-        let res = self.source[self.cur];
-        self.cur += 1;
-        return res;
+    fn advance(&mut self) -> char {
+        let c = self.peek();
+        if self.cur < self.source.len() {
+            self.cur += c.len_utf8();
+        }
+        c
+    }
+
+    // 1-based column of `offset` on the current line, counted in characters
+    fn column(&self, offset: usize) -> u32 {
+        self.source[self.line_start..offset].chars().count() as u32 + 1
     }
 
-    fn add_token(&mut self, token_t: TokenType) {
-        self._add_token(token_t, Box::new(0));
+    fn add_token(&mut self, token_t: TokenType) -> Result<(), ScanError> {
+        self._add_token(token_t, Literal::None)
     }
 
-    fn _add_token(&mut self, token_t: TokenType, literal: Box<dyn Any>) {
-        let text: &[u8] = &self.source[self.start..self.cur];
-        self.tokens.push(Token::new(
-            token_t,
-            String::from_utf8(text.to_vec()).ok().unwrap(),
+    fn _add_token(&mut self, token_t: TokenType, literal: Literal) -> Result<(), ScanError> {
+        self.token = Some(Token {
+            token_type: token_t,
+            lexeme: self.source[self.start..self.cur].to_string(),
             literal,
-            self.line,
-        ));
+            line: self.start_line,
+            span: self.current_span(),
+            comments: std::mem::take(&mut self.pending_comments),
+        });
+        Ok(())
     }
 
-    fn number(&mut self, start: char) -> Result<(), String> {
-        let mut num: Vec<u8> = vec![start as u8];
-        // Grab all digits
-        while is_digit(self.peek()) {
-            num.push(self.advance());
+    /**
+     * Comments aren't tokens, but they're kept as trivia on the token that follows them so that tools like the
+     * formatter can put them back.
+     */
+    fn add_comment(&mut self) -> Result<(), ScanError> {
+        self.pending_comments.push(Comment {
+            text: self.source[self.start..self.cur].to_string(),
+            span: self.current_span(),
+        });
+        Ok(())
+    }
+
+    fn current_span(&self) -> Span {
+        Span { start: self.start, end: self.cur, line: self.start_line, column: self.start_column }
+    }
+
+    // An error covering the source from `start` (on the current line) up to `cur`
+    fn error(&self, start: usize, message: String) -> ScanError {
+        let span = Span { start, end: self.cur, line: self.line, column: self.column(start) };
+        ScanError { message, span }
+    }
+
+    /**
+     * Scan a number: an integer in decimal, hex (`0xFF`), binary (`0b1010`) or octal (`0o17`), or a decimal float with
+     * a fractional part and/or an exponent (`1.5`, `6.02E23`, `1e-9`). Digits can be grouped with `_` (`1_000_000`).
+     */
+    fn number(&mut self) -> Result<(), ScanError> {
+        let radix = match (&self.source[self.start..self.cur], self.peek()) {
+            ("0", 'x' | 'X') => Some((16, "hex")),
+            ("0", 'b' | 'B') => Some((2, "binary")),
+            ("0", 'o' | 'O') => Some((8, "octal")),
+            _ => None,
+        };
+        if let Some((radix, kind)) = radix {
+            self.advance();
+            let digits = self.digits(self.cur, radix, kind)?;
+            if digits.is_empty() {
+                let prefix = &self.source[self.start..self.cur];
+                return Err(self.error(self.start, format!("expected {} digits after `{}`", kind, prefix)));
+            }
+            if self.peek() == '.' && is_digit(self.peek_next()) {
+                return Err(self.error(self.start, format!("{} literals can't have a fractional part", kind)));
+            }
+            return match i64::from_str_radix(&digits, radix) {
+                Ok(n) => self._add_token(TokenType::Number, Literal::Int(n)),
+                Err(_) => Err(self.error(self.start, "integer literal is too large".to_string())),
+            };
         }
 
-        // Look for a decimal and consume it
+        self.digits(self.start, 10, "number")?;
+        let mut is_float = false;
+        // Look for a decimal and consume it, along with the fractional part
         if self.peek() == '.' && is_digit(self.peek_next()) {
-            num.push(self.advance());
+            self.advance();
+            self.digits(self.cur, 10, "number")?;
+            is_float = true;
         }
-
-        // Consume the fractional part
-        while is_digit(self.peek()) {
-            num.push(self.advance());
+        if matches!(self.peek(), 'e' | 'E') {
+            self.advance();
+            if matches!(self.peek(), '+' | '-') {
+                self.advance();
+            }
+            if self.digits(self.cur, 10, "exponent")?.is_empty() {
+                return Err(self.error(self.start, "expected digits in the exponent".to_string()));
+            }
+            is_float = true;
+        }
+        if is_alpha(self.peek()) {
+            let suffix_start = self.cur;
+            while is_alphanumeric(self.peek()) {
+                self.advance();
+            }
+            let suffix = self.source[suffix_start..self.cur].to_string();
+            return Err(self.error(suffix_start, format!("invalid suffix `{}` on number", suffix)));
         }
 
-        // println!("{:?}", num);
-        let num_str: String = String::from_utf8(num).unwrap();
-        match num_str.parse::<f64>() {
-            Ok(n) => {
-                self._add_token(TokenType::Number, Box::new(n));
-                Ok(())
+        let num_str: String = self.source[self.start..self.cur].chars().filter(|c| *c != '_').collect();
+        if is_float {
+            match num_str.parse::<f64>() {
+                Ok(n) => self._add_token(TokenType::Number, Literal::Float(n)),
+                Err(exc) => Err(self.error(self.start, format!("could not parse number `{}`: {}", num_str, exc))),
             }
-            Err(exc) => {
-                err(self.line, format!("could not parse number `{}`: {}", num_str, exc).as_str())
+        } else {
+            match num_str.parse::<i64>() {
+                Ok(n) => self._add_token(TokenType::Number, Literal::Int(n)),
+                Err(_) => Err(self.error(self.start, "integer literal is too large".to_string())),
             }
         }
     }
 
     /**
-     * Parse a string out-may be multiple characters. Returns an error if the string is unterminated. Multi-line strings are allowed.
+     * Consume a run of digits starting at `start` (which may already have been consumed), returning them without any
+     * `_` separators. For radixes other than 10 letters are consumed too, so that a stray one is reported as an invalid
+     * digit rather than left to start an identifier.
      */
-    fn string(&mut self) -> Result<(), String> {
-        while self.peek() != '"' && self.cur < self.source.len() {
-            if self.peek() == '\n' {
+    fn digits(&mut self, start: usize, radix: u32, kind: &str) -> Result<String, ScanError> {
+        let consumes = |c: char| c == '_' || if radix == 10 { c.is_ascii_digit() } else { c.is_ascii_alphanumeric() };
+        while consumes(self.peek()) {
+            self.advance();
+        }
+        let text = &self.source[start..self.cur];
+        let bytes = text.as_bytes();
+        for (i, c) in text.char_indices() {
+            let message = if c == '_' {
+                if i > 0 && i + 1 < bytes.len() && bytes[i + 1] != b'_' {
+                    continue;
+                }
+                "`_` can only go between digits".to_string()
+            } else if !c.is_digit(radix) {
+                format!("invalid digit `{}` in {} literal", c, kind)
+            } else {
+                continue;
+            };
+            let span = Span { start: start + i, end: start + i + 1, line: self.line, column: self.column(start + i) };
+            return Err(ScanError { message, span });
+        }
+        Ok(text.chars().filter(|c| *c != '_').collect())
+    }
+
+    /**
+     * Work out how a string is quoted, once its opening '"' (and the `r` of a raw string) has been consumed, and scan
+     * it. A string opened with `"""` is a triple-quoted string: it's meant to be spread over several lines, so a line
+     * break straight after the opening quotes is dropped, as is the line the closing quotes are on if there's nothing
+     * else on it, and the indentation common to all of its lines is stripped.
+     */
+    fn open_string(&mut self, raw: bool) -> Result<(), ScanError> {
+        let mut quoting = Quoting { raw, triple: None };
+        if self.source[self.cur..].starts_with("\"\"") {
+            self.advance();
+            self.advance();
+            let indent = self.common_indent(raw);
+            quoting.triple = Some(indent);
+            if self.source[self.cur..].starts_with("\r\n") {
+                self.advance();
+            }
+            if self.match_char('\n') {
                 self.line += 1;
+                self.line_start = self.cur;
+                self.skip_indent(indent);
             }
-            self.advance();
         }
-        
-        if self.cur == self.source.len() - 1 {
-            return err(self.line, "unterminated string")
+        self.string(quoting)
+    }
+
+    /**
+     * Parse a string out-may be multiple characters. Returns an error if the string is unterminated or has an invalid
+     * escape sequence in it. Multi-line strings are allowed. Raw strings have no escape sequences or interpolation, so
+     * everything up to the closing quote is taken as it is.
+     *
+     * A string with `${expr}` in it is scanned in parts: each part that runs up to a `${` is an `Interpolation` token,
+     * followed by the tokens of the expression. The `}` closing the expression starts the next part, and the last
+     * part, which runs up to the closing '"', is a `Str`.
+     */
+    fn string(&mut self, quoting: Quoting) -> Result<(), ScanError> {
+        let closing = if quoting.triple.is_some() { "\"\"\"" } else { "\"" };
+        let mut value = String::new();
+        while !self.source[self.cur..].starts_with(closing) && self.cur < self.source.len() {
+            if !quoting.raw && self.peek() == '$' && self.peek_next() == '{' {
+                let open = self.cur;
+                self.advance();
+                self.advance();
+                self.interpolations.push((open, 0, quoting));
+                return self._add_token(TokenType::Interpolation, Literal::Str(value));
+            }
+            match self.advance() {
+                '\n' => {
+                    self.line += 1;
+                    self.line_start = self.cur;
+                    value.push('\n');
+                    if let Some(indent) = quoting.triple {
+                        self.skip_indent(indent);
+                    }
+                }
+                '\\' if !quoting.raw => value.push(self.escape()?),
+                c => value.push(c),
+            }
         }
 
-        // Grab the closing '"'
-        self.advance();
+        if self.cur >= self.source.len() {
+            return Err(ScanError { message: "unterminated string".to_string(), span: self.current_span() });
+        }
 
-        match std::str::from_utf8(&self.source[(self.start - 1)..(self.cur - 1)]) {
-            Ok(v) => {
-                let s = String::from(v);
-                self._add_token(TokenType::Str, Box::new(s));
-                Ok(())
+        // The closing quotes of a triple-quoted string on a line of their own aren't part of the last line
+        let closing_line = &self.source[self.line_start..self.cur];
+        if quoting.triple.is_some() && closing_line.chars().all(|c| c == ' ' || c == '\t') {
+            if let Some(end) = value.rfind('\n') {
+                value.truncate(end);
             }
-            Err(e) => {
-                println!("{}", e);
-                err(self.line, "could not parse source")
+        }
+
+        // Grab the closing quote(s)
+        for _ in 0..closing.len() {
+            self.advance();
+        }
+        self._add_token(TokenType::Str, Literal::Str(value))
+    }
+
+    // Skip up to `indent` spaces and tabs at the start of a line of a triple-quoted string
+    fn skip_indent(&mut self, indent: usize) {
+        for _ in 0..indent {
+            if !matches!(self.peek(), ' ' | '\t') {
+                break;
             }
+            self.advance();
+        }
+    }
+
+    /**
+     * The number of spaces and tabs that start every line of the triple-quoted string about to be scanned, not
+     * counting the line it opens on or lines that are blank. The closing quotes count as a line when they're on one of
+     * their own, so they can be used to keep some indentation.
+     */
+    fn common_indent(&self, raw: bool) -> usize {
+        let rest = &self.source[self.cur..];
+        let mut chars = rest.char_indices();
+        let mut end = rest.len();
+        while let Some((i, c)) = chars.next() {
+            if rest[i..].starts_with("\"\"\"") {
+                end = i;
+                break;
+            } else if c == '\\' && !raw {
+                chars.next();
+            }
+        }
+        let lines: Vec<&str> = rest[..end].split('\n').skip(1).collect();
+        let mut indent = None;
+        for (i, line) in lines.iter().enumerate() {
+            let line = line.trim_end_matches('\r');
+            let leading = line.chars().take_while(|c| *c == ' ' || *c == '\t').count();
+            if leading < line.chars().count() || i == lines.len() - 1 {
+                indent = Some(indent.map_or(leading, |n: usize| n.min(leading)));
+            }
+        }
+        indent.unwrap_or(0)
+    }
+
+    /**
+     * Work out the character an escape sequence stands for. Assumes the '\' has already been consumed.
+     */
+    fn escape(&mut self) -> Result<char, ScanError> {
+        let start = self.cur - 1;
+        if self.cur >= self.source.len() {
+            return Err(ScanError { message: "unterminated string".to_string(), span: self.current_span() });
+        }
+        match self.advance() {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '"' => Ok('"'),
+            '\\' => Ok('\\'),
+            '$' => Ok('$'),
+            'u' => {
+                if !self.match_char('{') {
+                    return Err(self.error(start, "expected '{' after `\\u`".to_string()));
+                }
+                let digits_start = self.cur;
+                while self.peek().is_ascii_hexdigit() {
+                    self.advance();
+                }
+                let digits = self.source[digits_start..self.cur].to_string();
+                if !self.match_char('}') {
+                    return Err(self.error(start, "expected hex digits and a closing '}' in `\\u{...}`".to_string()));
+                }
+                let code = u32::from_str_radix(&digits, 16).ok().filter(|_| digits.len() <= 6);
+                match code.and_then(char::from_u32) {
+                    Some(c) => Ok(c),
+                    None => Err(self.error(start, format!("`\\u{{{}}}` isn't a Unicode character", digits))),
+                }
+            }
+            c => Err(self.error(start, format!("invalid escape sequence `\\{}`", c.escape_debug()))),
         }
     }
 
@@ -256,34 +532,46 @@ impl Scanner {
      * Parse an identifier, which may be multiple characters. After identifier scanning has completed, the resulting token will be checked
      * to see if it matches any reserved words; this can only be done after scanning because of the requirement for maximal munch.
      */
-    fn identifier(&mut self) -> Result<(), String> {
+    fn identifier(&mut self) -> Result<(), ScanError> {
         while is_alphanumeric(self.peek()) && self.cur < self.source.len() {
             self.advance();
         }
-        
-        match std::str::from_utf8(&self.source[self.start..(self.cur - 1)]) {
-            Ok(v) => {
-                let s = String::from(v);
-                match self.reserved_identifiers.get(&s) {
-                    Some(reserved) => self._add_token(*reserved, Box::new(s)),
-                    None => self._add_token(TokenType::Str, Box::new(s)),
-                }
-                Ok(())
-            }
-            Err(e) => {
-                println!("{}", e);
-                err(self.line, "could not parse source")
-            }
+
+        match self.reserved_identifiers.get(&self.source[self.start..self.cur]) {
+            Some(reserved) => self.add_token(*reserved),
+            None => self.add_token(TokenType::Identifier),
         }
     }
 }
 
+// How a string literal is quoted, needed to carry on scanning it after an interpolated expression
+#[derive(Clone, Copy)]
+struct Quoting {
+    raw: bool,
+    // For a triple-quoted string, how much indentation to strip from the start of each line
+    triple: Option<usize>,
+}
+
+impl Iterator for Scanner {
+    type Item = Result<Token, ScanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let next = self.scan_next();
+        self.finished = !matches!(&next, Ok(token) if token.token_type != TokenType::Eof);
+        Some(next)
+    }
+}
+
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
+// Identifiers can use letters from any script, but only ASCII digits
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c == '_')
+    c.is_alphabetic() || (c == '_')
 }
 
 fn is_alphanumeric(c: char) -> bool {
@@ -297,9 +585,11 @@ fn reserved_identifiers() -> HashMap<String, TokenType> {
     // Populate reserved identifiers
     let mut reserved_identifiers = HashMap::<String, TokenType>::new();
     reserved_identifiers.insert(String::from("and"), TokenType::And);
+    reserved_identifiers.insert(String::from("catch"), TokenType::Catch);
     reserved_identifiers.insert(String::from("class"), TokenType::Class);
     reserved_identifiers.insert(String::from("else"), TokenType::Else);
     reserved_identifiers.insert(String::from("false"), TokenType::False);
+    reserved_identifiers.insert(String::from("finally"), TokenType::Finally);
     reserved_identifiers.insert(String::from("for"), TokenType::For);
     reserved_identifiers.insert(String::from("func"), TokenType::Func);
     reserved_identifiers.insert(String::from("if"), TokenType::If);
@@ -309,28 +599,188 @@ fn reserved_identifiers() -> HashMap<String, TokenType> {
     reserved_identifiers.insert(String::from("return"), TokenType::Return);
     reserved_identifiers.insert(String::from("super"), TokenType::Super);
     reserved_identifiers.insert(String::from("this"), TokenType::This);
+    reserved_identifiers.insert(String::from("throw"), TokenType::Throw);
     reserved_identifiers.insert(String::from("true"), TokenType::True);
+    reserved_identifiers.insert(String::from("try"), TokenType::Try);
     reserved_identifiers.insert(String::from("var"), TokenType::Var);
     reserved_identifiers.insert(String::from("while"), TokenType::While);
-    return reserved_identifiers;
+    reserved_identifiers
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
-    literal: Box<dyn Any>,
+    pub literal: Literal,
     pub line: u32,
+    pub span: Span,
+    /// Comments between the previous token and this one
+    pub comments: Vec<Comment>,
 }
 impl Token {
-    pub fn new(token_type: TokenType, lexeme: String, literal: Box<dyn Any>, line: u32) -> Token {
+    /**
+     * Create a token that doesn't come from scanning source, so only knows which line it belongs to.
+     */
+    pub fn new(token_type: TokenType, lexeme: String, literal: Literal, line: u32) -> Token {
         Token {
             token_type,
             lexeme,
             literal,
             line,
+            span: Span { start: 0, end: 0, line, column: 0 },
+            comments: vec![],
         }
     }
+
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("type", Json::String(format!("{:?}", self.token_type))),
+            ("lexeme", Json::string(&self.lexeme)),
+            ("literal", self.literal.to_json()),
+            ("span", self.span.to_json()),
+        ])
+    }
+
+    /**
+     * A human-readable line describing this token, lined up in columns with other tokens.
+     */
+    pub fn to_table_row(&self) -> String {
+        format!(
+            "{:<10} {:<12} {:<14} {:<16} {}",
+            format!("{}:{}", self.span.line, self.span.column),
+            format!("{}..{}", self.span.start, self.span.end),
+            format!("{:?}", self.token_type),
+            self.lexeme.escape_debug().to_string(),
+            self.literal
+        )
+    }
+}
+
+/// Column headers matching `Token::to_table_row`.
+pub const TOKEN_TABLE_HEADER: &str = "LINE:COL   OFFSETS      TYPE           LEXEME           LITERAL";
+
+/**
+ * A `//` or `/* */` comment, including its delimiters.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub text: String,
+    pub span: Span,
+}
+impl Comment {
+    /**
+     * The text of a `///` doc comment, without the slashes or the space after them. `////` starts an ordinary comment,
+     * so that a line of slashes doesn't turn into documentation.
+     */
+    pub fn doc(&self) -> Option<&str> {
+        let text = self.text.strip_prefix("///").filter(|text| !text.starts_with('/'))?;
+        Some(text.strip_prefix(' ').unwrap_or(text))
+    }
+}
+
+/**
+ * The value carried by a literal token, already converted from its lexeme.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    None,
+    Str(String),
+    Int(i64),
+    Float(f64),
+}
+impl Literal {
+    pub fn to_json(&self) -> Json {
+        match self {
+            Literal::None => Json::Null,
+            Literal::Str(s) => Json::string(s),
+            Literal::Int(n) => Json::Int(*n),
+            Literal::Float(n) => Json::Number(*n),
+        }
+    }
+}
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Literal::None => Ok(()),
+            Literal::Str(s) => write!(f, "{:?}", s),
+            Literal::Int(n) => write!(f, "{}", n),
+            Literal::Float(n) => write!(f, "{:?}", n),
+        }
+    }
+}
+
+/**
+ * Where a token came from in the source. `start` and `end` are byte offsets (end is exclusive); `line` and `column`
+ * are 1-based and point at the first character.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub column: u32,
+}
+impl Span {
+    /**
+     * A span running from the start of this one to the end of `other`.
+     */
+    pub fn to(self, other: Span) -> Span {
+        Span { end: other.end, ..self }
+    }
+
+    pub fn to_json(self) -> Json {
+        Json::object(vec![
+            ("start", Json::Int(self.start as i64)),
+            ("end", Json::Int(self.end as i64)),
+            ("line", Json::Int(self.line as i64)),
+            ("column", Json::Int(self.column as i64)),
+        ])
+    }
+}
+
+/**
+ * Why a program couldn't be scanned, and the source it's about.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanError {
+    pub message: String,
+    pub span: Span,
+}
+impl ScanError {
+    /**
+     * The error as shown to users, with the offending source underlined.
+     */
+    pub fn report(&self, source: &str) -> String {
+        let mut lines = vec![self.to_string()];
+        lines.extend(underline(source, self.span));
+        lines.join("\n")
+    }
+}
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}] Error: {}", self.span.line, self.message)
+    }
+}
+impl From<ScanError> for String {
+    fn from(e: ScanError) -> String {
+        e.to_string()
+    }
+}
+
+/**
+ * The line of `source` that `span` starts on, followed by carets under the span (up to the end of that line). Empty
+ * if the span is empty or doesn't fit in `source`.
+ */
+pub fn underline(source: &str, span: Span) -> Vec<String> {
+    if span.start >= span.end || span.end > source.len() {
+        return vec![];
+    }
+    let line_start = source[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = source[span.start..].find('\n').map(|i| span.start + i).unwrap_or(source.len());
+    // Keep any tabs in the indentation so the carets line up
+    let indent: String = source[line_start..span.start].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    let width = source[span.start..span.end.min(line_end)].chars().count().max(1);
+    vec![format!("    {}", &source[line_start..line_end]), format!("    {}{}", indent, "^".repeat(width))]
 }
 
 // Debug is good enough here, it prints the enum name
@@ -341,6 +791,8 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -348,6 +800,7 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
     QuestionMark,
     Colon,
 
@@ -356,6 +809,7 @@ pub enum TokenType {
     BangEqual,
     Equal,
     EqualEqual,
+    Arrow,
     Greater,
     GreaterEqual,
     Less,
@@ -364,13 +818,17 @@ pub enum TokenType {
     // Literals
     Identifier,
     Str,
+    // The part of a string before a `${`
+    Interpolation,
     Number,
 
     //  Keywords
     And,
+    Catch,
     Class,
     Else,
     False,
+    Finally,
     Func,
     For,
     If,
@@ -380,9 +838,157 @@ pub enum TokenType {
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
     Eof,
 }
+impl TokenType {
+    /**
+     * Look up a token type by its name, as printed by `{:?}`.
+     */
+    pub fn from_name(name: &str) -> Option<TokenType> {
+        ALL_TOKEN_TYPES.iter().find(|t| format!("{:?}", t) == name).copied()
+    }
+}
+
+const ALL_TOKEN_TYPES: [TokenType; 50] = [
+    TokenType::LeftParen, TokenType::RightParen, TokenType::LeftBrace, TokenType::RightBrace,
+    TokenType::LeftBracket, TokenType::RightBracket, TokenType::Comma, TokenType::Dot, TokenType::Minus,
+    TokenType::Plus, TokenType::Semicolon, TokenType::Slash, TokenType::Star, TokenType::Percent,
+    TokenType::QuestionMark, TokenType::Colon, TokenType::Bang, TokenType::BangEqual, TokenType::Equal,
+    TokenType::EqualEqual, TokenType::Arrow, TokenType::Greater, TokenType::GreaterEqual, TokenType::Less,
+    TokenType::LessEqual, TokenType::Identifier, TokenType::Str, TokenType::Interpolation, TokenType::Number,
+    TokenType::And, TokenType::Catch, TokenType::Class, TokenType::Else, TokenType::False, TokenType::Finally,
+    TokenType::Func, TokenType::For, TokenType::If, TokenType::Nil, TokenType::Or, TokenType::Print,
+    TokenType::Return, TokenType::Super, TokenType::This, TokenType::Throw, TokenType::True, TokenType::Try,
+    TokenType::Var, TokenType::While, TokenType::Eof,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{program, Parser};
+    use crate::syntax_tree::Stmt;
+
+    fn scan(source: &str) -> Result<Vec<Token>, ScanError> {
+        Scanner::new(source.to_string()).collect()
+    }
+
+    // The literal of the first token
+    fn literal(source: &str) -> Literal {
+        scan(source).unwrap()[0].literal.clone()
+    }
+
+    // The message of the error scanning stops with, and the source it's about
+    fn error(source: &str) -> (String, &str) {
+        let e = scan(source).unwrap_err();
+        (e.message, &source[e.span.start..e.span.end])
+    }
+
+    fn string(s: &str) -> Literal {
+        Literal::Str(s.to_string())
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(literal(r#""a\nb\t\"\\\r""#), string("a\nb\t\"\\\r"));
+        assert_eq!(literal(r#""\u{48}\u{1F600}""#), string("H😀"));
+        assert_eq!(error(r#""ok \q""#), ("invalid escape sequence `\\q`".to_string(), r"\q"));
+        assert_eq!(error(r#""\u48""#), ("expected '{' after `\\u`".to_string(), r"\u"));
+        assert_eq!(error(r#""\u{}""#), ("`\\u{}` isn't a Unicode character".to_string(), r"\u{}"));
+        assert_eq!(error(r#""\u{110000}""#), ("`\\u{110000}` isn't a Unicode character".to_string(), r"\u{110000}"));
+        assert_eq!(error(r#""\u{d800}""#), ("`\\u{d800}` isn't a Unicode character".to_string(), r"\u{d800}"));
+        assert_eq!(error("\"abc").0, "unterminated string");
+    }
+
+    #[test]
+    fn unicode() {
+        let tokens = scan("var héllo = \"wörld ✓\";").unwrap();
+        assert_eq!(tokens[1].token_type, TokenType::Identifier);
+        assert_eq!(tokens[1].lexeme, "héllo");
+        assert_eq!(tokens[3].literal, string("wörld ✓"));
+        // Spans are byte offsets, and columns count characters
+        assert_eq!((tokens[3].span.start, tokens[3].span.end, tokens[3].span.column), (13, 25, 13));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(literal("0xFF"), Literal::Int(255));
+        assert_eq!(literal("0b1010"), Literal::Int(10));
+        assert_eq!(literal("0o17"), Literal::Int(15));
+        assert_eq!(literal("1_000_000"), Literal::Int(1_000_000));
+        assert_eq!(literal("1e-9"), Literal::Float(1e-9));
+        assert_eq!(literal("6.02E23"), Literal::Float(6.02e23));
+        assert_eq!(literal("1e3"), Literal::Float(1000.0));
+        assert_eq!(literal("1.5"), Literal::Float(1.5));
+        // A '.' only makes a float when there's a digit after it, so methods can be called on integers
+        let tokens = scan("1.len").unwrap();
+        assert_eq!((tokens[0].literal.clone(), tokens[1].token_type), (Literal::Int(1), TokenType::Dot));
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        let separator = "`_` can only go between digits".to_string();
+        assert_eq!(error("1__0"), (separator.clone(), "_"));
+        assert_eq!(error("1_ "), (separator.clone(), "_"));
+        assert_eq!(error("1.5_"), (separator.clone(), "_"));
+        assert_eq!(error("0x_1"), (separator, "_"));
+        assert_eq!(error("0x"), ("expected hex digits after `0x`".to_string(), "0x"));
+        assert_eq!(error("0b2"), ("invalid digit `2` in binary literal".to_string(), "2"));
+        assert_eq!(error("1e"), ("expected digits in the exponent".to_string(), "1e"));
+        assert_eq!(error("9223372036854775808").0, "integer literal is too large");
+    }
+
+    #[test]
+    fn raw_strings() {
+        assert_eq!(literal(r#"r"C:\path\n""#), string(r"C:\path\n"));
+        assert_eq!(literal(r#"r"${x}""#), string("${x}"));
+        assert_eq!(error("r\"abc").0, "unterminated string");
+    }
+
+    #[test]
+    fn triple_quoted_strings() {
+        // The indentation the lines share is removed, along with the line breaks just inside the quotes
+        assert_eq!(literal("\"\"\"\n    a\n      b\n    \"\"\""), string("a\n  b"));
+        assert_eq!(literal("\"\"\"\n  a\n b\n  \"\"\""), string(" a\nb"));
+        assert_eq!(literal("\"\"\"x\"\"\""), string("x"));
+        assert_eq!(literal("\"\"\"say \"hi\" \\n\"\"\""), string("say \"hi\" \n"));
+        let tokens = scan("\"\"\"\na\n\"\"\" x").unwrap();
+        assert_eq!((tokens[1].lexeme.as_str(), tokens[1].line), ("x", 3));
+        assert_eq!(error("\"\"\"\nabc\"\"").0, "unterminated string");
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        let tokens = scan("/* a /* b\n */ c\n */ 1 /**/ 2").unwrap();
+        assert_eq!(tokens[0].literal, Literal::Int(1));
+        assert_eq!(tokens[0].line, 3);
+        assert_eq!(tokens[0].comments[0].text, "/* a /* b\n */ c\n */");
+        assert_eq!(tokens[1].comments[0].text, "/**/");
+        // The error points at the comment that's still open, which is the outer one
+        assert_eq!(error("1\n/* a\n/* b */\n"), ("unterminated block comment".to_string(), "/*"));
+        assert_eq!(scan("1\n/* a\n/* b */\n").unwrap_err().span.line, 2);
+    }
+
+    #[test]
+    fn doc_comments() {
+        let tokens = scan("// plain\n/// Adds.\n///\n////\nfunc").unwrap();
+        let docs: Vec<Option<&str>> = tokens[0].comments.iter().map(Comment::doc).collect();
+        assert_eq!(docs, vec![None, Some("Adds."), Some(""), None]);
+
+        let source = "/// Adds `a` and `b`.\n///\n/// More.\nfunc add(a, b) { return a + b; }\n\
+                      /// The answer.\nvar x = 42;";
+        let statements = program(&mut Parser::new(Scanner::new(source.to_string()))).unwrap();
+        match (&statements[0], &statements[1]) {
+            (Stmt::Function(decl), Stmt::Var(_, _, doc, _)) => {
+                assert_eq!(decl.doc.as_deref(), Some("Adds `a` and `b`.\n\nMore."));
+                assert_eq!(doc.as_deref(), Some("The answer."));
+            }
+            _ => panic!("expected a function and a variable"),
+        }
+    }
+}
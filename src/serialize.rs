@@ -0,0 +1,933 @@
+use crate::json::Json;
+use crate::scanner::{Literal, Scanner, Span, Token, TokenType};
+use crate::syntax_tree::{Expr, FunctionDecl, Stmt};
+use std::rc::Rc;
+
+/*
+ * Serialization of syntax trees, for tools that want to consume parse results and for snapshotting parser output.
+ *
+ * There are two forms:
+ * - JSON, which keeps everything, including the span of every node and token.
+ * - A canonical S-expression form, which drops spans so that it only changes when the shape of the tree changes:
+ *     (var x (+ 1 (group (* 2 3))))
+ *     (if (< x 10) (print "small") (print "big"))
+ *     (=> (a b) (+ a b))
+ *     (= (index xs 0) (slice ys 1 ()))
+ *     (map ("a" 1) ("b" (list 2 3)))
+ *
+ * Both forms can be read back into a syntax tree.
+ */
+
+/*
+ * JSON
+ */
+
+pub fn program_to_json(statements: &[Stmt]) -> Json {
+    Json::Array(statements.iter().map(stmt_to_json).collect())
+}
+
+pub fn stmt_to_json(s: &Stmt) -> Json {
+    let span = s.span().to_json();
+    match s {
+        Stmt::Expression(e, _) => node("Expression", span, vec![("expression", expr_to_json(e))]),
+        Stmt::Print(e, _) => node("Print", span, vec![("expression", expr_to_json(e))]),
+        Stmt::Var(name, initializer, doc, _) => node(
+            "Var",
+            span,
+            vec![
+                ("name", name.to_json()),
+                ("initializer", optional(initializer.as_ref().map(expr_to_json))),
+                ("doc", optional(doc.as_deref().map(Json::string))),
+            ],
+        ),
+        Stmt::Block(statements, _) => node("Block", span, vec![("statements", program_to_json(statements))]),
+        Stmt::If(condition, then_branch, else_branch, _) => node(
+            "If",
+            span,
+            vec![
+                ("condition", expr_to_json(condition)),
+                ("then", stmt_to_json(then_branch)),
+                ("else", optional(else_branch.as_ref().map(|s| stmt_to_json(s)))),
+            ],
+        ),
+        Stmt::While(condition, body, _) => node(
+            "While",
+            span,
+            vec![("condition", expr_to_json(condition)), ("body", stmt_to_json(body))],
+        ),
+        Stmt::For(initializer, condition, increment, body, _) => node(
+            "For",
+            span,
+            vec![
+                ("initializer", optional(initializer.as_ref().map(|s| stmt_to_json(s)))),
+                ("condition", optional(condition.as_ref().map(expr_to_json))),
+                ("increment", optional(increment.as_ref().map(expr_to_json))),
+                ("body", stmt_to_json(body)),
+            ],
+        ),
+        Stmt::Function(decl) => node(
+            "Function",
+            span,
+            vec![
+                ("name", decl.name.to_json()),
+                ("params", Json::Array(decl.params.iter().map(|p| p.to_json()).collect())),
+                ("body", program_to_json(&decl.body)),
+                ("doc", optional(decl.doc.as_deref().map(Json::string))),
+            ],
+        ),
+        Stmt::Return(value, _) => node("Return", span, vec![("value", optional(value.as_ref().map(expr_to_json)))]),
+        Stmt::Throw(e, _) => node("Throw", span, vec![("value", expr_to_json(e))]),
+        Stmt::Try(body, catch, finally, _) => node(
+            "Try",
+            span,
+            vec![
+                ("body", stmt_to_json(body)),
+                (
+                    "catch",
+                    optional(catch.as_ref().map(|(name, handler)| {
+                        Json::object(vec![("name", name.to_json()), ("body", stmt_to_json(handler))])
+                    })),
+                ),
+                ("finally", optional(finally.as_ref().map(|s| stmt_to_json(s)))),
+            ],
+        ),
+    }
+}
+
+pub fn expr_to_json(e: &Expr) -> Json {
+    let span = e.span().to_json();
+    match e {
+        Expr::StringLiteral(v, _) => node("String", span, vec![("value", Json::string(v))]),
+        Expr::IntLiteral(v, _) => node("Int", span, vec![("value", Json::Int(*v))]),
+        Expr::FloatLiteral(v, _) => node("Float", span, vec![("value", Json::Number(*v))]),
+        Expr::BoolLiteral(v, _) => node("Bool", span, vec![("value", Json::Bool(*v))]),
+        Expr::NilLiteral(_) => node("Nil", span, vec![]),
+        Expr::Unary(operand, op) => node(
+            "Unary",
+            span,
+            vec![("op", op.to_json()), ("operand", expr_to_json(operand))],
+        ),
+        Expr::Binary(left, op, right) | Expr::Logical(left, op, right) => node(
+            if matches!(e, Expr::Binary(..)) { "Binary" } else { "Logical" },
+            span,
+            vec![("op", op.to_json()), ("left", expr_to_json(left)), ("right", expr_to_json(right))],
+        ),
+        Expr::Ternary(condition, if_true, if_false) => node(
+            "Ternary",
+            span,
+            vec![
+                ("condition", expr_to_json(condition)),
+                ("then", expr_to_json(if_true)),
+                ("else", expr_to_json(if_false)),
+            ],
+        ),
+        Expr::Sequence(expressions) => {
+            node("Sequence", span, vec![("expressions", Json::Array(expressions.iter().map(expr_to_json).collect()))])
+        }
+        Expr::Grouping(inner, _) => node("Grouping", span, vec![("expression", expr_to_json(inner))]),
+        Expr::Variable(name) => node("Variable", span, vec![("name", name.to_json())]),
+        Expr::Assign(name, value) => node(
+            "Assign",
+            span,
+            vec![("name", name.to_json()), ("value", expr_to_json(value))],
+        ),
+        Expr::Call(callee, paren, arguments) => node(
+            "Call",
+            span,
+            vec![
+                ("callee", expr_to_json(callee)),
+                ("paren", paren.to_json()),
+                ("arguments", Json::Array(arguments.iter().map(expr_to_json).collect())),
+            ],
+        ),
+        Expr::Get(object, name) => node(
+            "Get",
+            span,
+            vec![("object", expr_to_json(object)), ("name", name.to_json())],
+        ),
+        Expr::Interpolation(parts) => {
+            node("Interpolation", span, vec![("parts", Json::Array(parts.iter().map(expr_to_json).collect()))])
+        }
+        Expr::List(elements, _) => {
+            node("List", span, vec![("elements", Json::Array(elements.iter().map(expr_to_json).collect()))])
+        }
+        Expr::Map(entries, _) => {
+            let entries = entries
+                .iter()
+                .map(|(key, value)| Json::object(vec![("key", expr_to_json(key)), ("value", expr_to_json(value))]))
+                .collect();
+            node("Map", span, vec![("entries", Json::Array(entries))])
+        }
+        Expr::Index(object, index, bracket) => node(
+            "Index",
+            span,
+            vec![("object", expr_to_json(object)), ("index", expr_to_json(index)), ("bracket", bracket.to_json())],
+        ),
+        Expr::Slice(object, start, end, bracket) => node(
+            "Slice",
+            span,
+            vec![
+                ("object", expr_to_json(object)),
+                ("start", optional(start.as_ref().map(|e| expr_to_json(e)))),
+                ("end", optional(end.as_ref().map(|e| expr_to_json(e)))),
+                ("bracket", bracket.to_json()),
+            ],
+        ),
+        Expr::SetIndex(object, index, bracket, value) => node(
+            "SetIndex",
+            span,
+            vec![
+                ("object", expr_to_json(object)),
+                ("index", expr_to_json(index)),
+                ("bracket", bracket.to_json()),
+                ("value", expr_to_json(value)),
+            ],
+        ),
+        Expr::Lambda(decl, arrow) => node(
+            "Lambda",
+            span,
+            vec![
+                ("name", decl.name.to_json()),
+                ("params", Json::Array(decl.params.iter().map(|p| p.to_json()).collect())),
+                ("body", program_to_json(&decl.body)),
+                ("arrow", Json::Bool(*arrow)),
+            ],
+        ),
+    }
+}
+
+fn node(kind: &str, span: Json, mut fields: Vec<(&str, Json)>) -> Json {
+    fields.insert(0, ("span", span));
+    fields.insert(0, ("kind", Json::string(kind)));
+    Json::object(fields)
+}
+
+fn optional(value: Option<Json>) -> Json {
+    value.unwrap_or(Json::Null)
+}
+
+pub fn program_from_json(json: &Json) -> Result<Vec<Stmt>, String> {
+    match json.as_array() {
+        Some(statements) => statements.iter().map(stmt_from_json).collect(),
+        None => Err("expected an array of statements".to_string()),
+    }
+}
+
+pub fn stmt_from_json(json: &Json) -> Result<Stmt, String> {
+    let span = span_from_json(field(json, "span")?)?;
+    match kind(json)? {
+        "Expression" => Ok(Stmt::Expression(expr_from_json(field(json, "expression")?)?, span)),
+        "Print" => Ok(Stmt::Print(expr_from_json(field(json, "expression")?)?, span)),
+        "Var" => Ok(Stmt::Var(
+            token_from_json(field(json, "name")?)?,
+            optional_from_json(field(json, "initializer")?, expr_from_json)?,
+            optional_from_json(field(json, "doc")?, doc_from_json)?,
+            span,
+        )),
+        "Block" => Ok(Stmt::Block(program_from_json(field(json, "statements")?)?, span)),
+        "If" => Ok(Stmt::If(
+            expr_from_json(field(json, "condition")?)?,
+            Box::new(stmt_from_json(field(json, "then")?)?),
+            optional_from_json(field(json, "else")?, stmt_from_json)?.map(Box::new),
+            span,
+        )),
+        "While" => Ok(Stmt::While(
+            expr_from_json(field(json, "condition")?)?,
+            Box::new(stmt_from_json(field(json, "body")?)?),
+            span,
+        )),
+        "For" => Ok(Stmt::For(
+            optional_from_json(field(json, "initializer")?, stmt_from_json)?.map(Box::new),
+            optional_from_json(field(json, "condition")?, expr_from_json)?,
+            optional_from_json(field(json, "increment")?, expr_from_json)?,
+            Box::new(stmt_from_json(field(json, "body")?)?),
+            span,
+        )),
+        "Function" => Ok(Stmt::Function(Rc::new(FunctionDecl {
+            name: token_from_json(field(json, "name")?)?,
+            params: params_from_json(json)?,
+            body: program_from_json(field(json, "body")?)?,
+            doc: optional_from_json(field(json, "doc")?, doc_from_json)?,
+            span,
+        }))),
+        "Return" => Ok(Stmt::Return(optional_from_json(field(json, "value")?, expr_from_json)?, span)),
+        "Throw" => Ok(Stmt::Throw(expr_from_json(field(json, "value")?)?, span)),
+        "Try" => Ok(Stmt::Try(
+            Box::new(stmt_from_json(field(json, "body")?)?),
+            optional_from_json(field(json, "catch")?, catch_from_json)?,
+            optional_from_json(field(json, "finally")?, stmt_from_json)?.map(Box::new),
+            span,
+        )),
+        k => Err(format!("unknown statement kind `{}`", k)),
+    }
+}
+
+fn params_from_json(json: &Json) -> Result<Vec<Token>, String> {
+    match field(json, "params")?.as_array() {
+        Some(params) => params.iter().map(token_from_json).collect(),
+        None => Err("expected an array of parameters".to_string()),
+    }
+}
+
+fn catch_from_json(json: &Json) -> Result<(Token, Box<Stmt>), String> {
+    Ok((token_from_json(field(json, "name")?)?, Box::new(stmt_from_json(field(json, "body")?)?)))
+}
+
+pub fn expr_from_json(json: &Json) -> Result<Expr, String> {
+    let span = span_from_json(field(json, "span")?)?;
+    let boxed = |key: &str| -> Result<Box<Expr>, String> { Ok(Box::new(expr_from_json(field(json, key)?)?)) };
+    match kind(json)? {
+        "String" => match field(json, "value")?.as_str() {
+            Some(v) => Ok(Expr::StringLiteral(v.to_string(), span)),
+            None => Err("expected a string value".to_string()),
+        },
+        "Int" => match field(json, "value")?.as_i64() {
+            Some(v) => Ok(Expr::IntLiteral(v, span)),
+            None => Err("expected an integer value".to_string()),
+        },
+        "Float" => Ok(Expr::FloatLiteral(number(field(json, "value")?)?, span)),
+        "Bool" => match field(json, "value")?.as_bool() {
+            Some(v) => Ok(Expr::BoolLiteral(v, span)),
+            None => Err("expected a bool value".to_string()),
+        },
+        "Nil" => Ok(Expr::NilLiteral(span)),
+        "Unary" => Ok(Expr::Unary(boxed("operand")?, token_from_json(field(json, "op")?)?)),
+        "Binary" => Ok(Expr::Binary(boxed("left")?, token_from_json(field(json, "op")?)?, boxed("right")?)),
+        "Logical" => Ok(Expr::Logical(boxed("left")?, token_from_json(field(json, "op")?)?, boxed("right")?)),
+        "Ternary" => Ok(Expr::Ternary(boxed("condition")?, boxed("then")?, boxed("else")?)),
+        "Grouping" => Ok(Expr::Grouping(boxed("expression")?, span)),
+        "Variable" => Ok(Expr::Variable(token_from_json(field(json, "name")?)?)),
+        "Assign" => Ok(Expr::Assign(token_from_json(field(json, "name")?)?, boxed("value")?)),
+        "Call" => {
+            let arguments = match field(json, "arguments")?.as_array() {
+                Some(arguments) => arguments.iter().map(expr_from_json).collect::<Result<Vec<Expr>, String>>()?,
+                None => return Err("expected an array of arguments".to_string()),
+            };
+            Ok(Expr::Call(boxed("callee")?, token_from_json(field(json, "paren")?)?, arguments))
+        }
+        "Get" => Ok(Expr::Get(boxed("object")?, token_from_json(field(json, "name")?)?)),
+        "Interpolation" => match field(json, "parts")?.as_array() {
+            Some(parts) => Ok(Expr::Interpolation(parts.iter().map(expr_from_json).collect::<Result<_, _>>()?)),
+            None => Err("expected an array of parts".to_string()),
+        },
+        "Sequence" => match field(json, "expressions")?.as_array() {
+            Some(expressions) if expressions.len() >= 2 => {
+                Ok(Expr::Sequence(expressions.iter().map(expr_from_json).collect::<Result<_, _>>()?))
+            }
+            _ => Err("expected an array of at least two expressions".to_string()),
+        },
+        "List" => match field(json, "elements")?.as_array() {
+            Some(elements) => Ok(Expr::List(elements.iter().map(expr_from_json).collect::<Result<_, _>>()?, span)),
+            None => Err("expected an array of elements".to_string()),
+        },
+        "Map" => match field(json, "entries")?.as_array() {
+            Some(entries) => Ok(Expr::Map(entries.iter().map(entry_from_json).collect::<Result<_, _>>()?, span)),
+            None => Err("expected an array of entries".to_string()),
+        },
+        "Index" => Ok(Expr::Index(boxed("object")?, boxed("index")?, token_from_json(field(json, "bracket")?)?)),
+        "Slice" => Ok(Expr::Slice(
+            boxed("object")?,
+            optional_from_json(field(json, "start")?, expr_from_json)?.map(Box::new),
+            optional_from_json(field(json, "end")?, expr_from_json)?.map(Box::new),
+            token_from_json(field(json, "bracket")?)?,
+        )),
+        "SetIndex" => Ok(Expr::SetIndex(
+            boxed("object")?,
+            boxed("index")?,
+            token_from_json(field(json, "bracket")?)?,
+            boxed("value")?,
+        )),
+        "Lambda" => {
+            let arrow = field(json, "arrow")?.as_bool().ok_or("expected `arrow` to be a bool")?;
+            let body = program_from_json(field(json, "body")?)?;
+            if arrow && !matches!(body.as_slice(), [Stmt::Return(Some(_), _)]) {
+                return Err("expected the body of an arrow function to be a single return".to_string());
+            }
+            let name = token_from_json(field(json, "name")?)?;
+            let decl = FunctionDecl { name, params: params_from_json(json)?, body, doc: None, span };
+            Ok(Expr::Lambda(Rc::new(decl), arrow))
+        }
+        k => Err(format!("unknown expression kind `{}`", k)),
+    }
+}
+
+fn entry_from_json(json: &Json) -> Result<(Expr, Expr), String> {
+    Ok((expr_from_json(field(json, "key")?)?, expr_from_json(field(json, "value")?)?))
+}
+
+fn token_from_json(json: &Json) -> Result<Token, String> {
+    let type_name = field(json, "type")?.as_str().unwrap_or_default();
+    let token_type = match TokenType::from_name(type_name) {
+        Some(t) => t,
+        None => return Err(format!("unknown token type `{}`", type_name)),
+    };
+    let lexeme = match field(json, "lexeme")?.as_str() {
+        Some(l) => l.to_string(),
+        None => return Err("expected a token lexeme".to_string()),
+    };
+    let literal = match json.get("literal") {
+        Some(Json::String(s)) => Literal::Str(s.clone()),
+        Some(Json::Int(n)) => Literal::Int(*n),
+        Some(Json::Number(n)) => Literal::Float(*n),
+        _ => Literal::None,
+    };
+    let span = span_from_json(field(json, "span")?)?;
+    Ok(Token { token_type, lexeme, literal, line: span.line, span, comments: vec![] })
+}
+
+fn span_from_json(json: &Json) -> Result<Span, String> {
+    Ok(Span {
+        start: number(field(json, "start")?)? as usize,
+        end: number(field(json, "end")?)? as usize,
+        line: number(field(json, "line")?)? as u32,
+        column: number(field(json, "column")?)? as u32,
+    })
+}
+
+fn optional_from_json<T>(json: &Json, from_json: fn(&Json) -> Result<T, String>) -> Result<Option<T>, String> {
+    match json {
+        Json::Null => Ok(None),
+        j => Ok(Some(from_json(j)?)),
+    }
+}
+
+fn doc_from_json(json: &Json) -> Result<String, String> {
+    match json.as_str() {
+        Some(doc) => Ok(doc.to_string()),
+        None => Err("expected `doc` to be a string".to_string()),
+    }
+}
+
+fn kind(json: &Json) -> Result<&str, String> {
+    match field(json, "kind")?.as_str() {
+        Some(k) => Ok(k),
+        None => Err("expected `kind` to be a string".to_string()),
+    }
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> Result<&'a Json, String> {
+    match json.get(key) {
+        Some(v) => Ok(v),
+        None => Err(format!("missing field `{}`", key)),
+    }
+}
+
+fn number(json: &Json) -> Result<f64, String> {
+    match json.as_f64() {
+        Some(n) => Ok(n),
+        None => Err("expected a number".to_string()),
+    }
+}
+
+/*
+ * S-expressions
+ */
+
+/**
+ * Render a program in canonical S-expression form, one top-level statement per line.
+ */
+pub fn program_to_sexpr(statements: &[Stmt]) -> String {
+    let lines: Vec<String> = statements.iter().map(stmt_to_sexpr).collect();
+    lines.join("\n")
+}
+
+pub fn stmt_to_sexpr(s: &Stmt) -> String {
+    match s {
+        Stmt::Expression(e, _) => format!("(expr {})", expr_to_sexpr(e)),
+        Stmt::Print(e, _) => format!("(print {})", expr_to_sexpr(e)),
+        Stmt::Var(name, None, ..) => format!("(var {})", name.lexeme),
+        Stmt::Var(name, Some(e), ..) => format!("(var {} {})", name.lexeme, expr_to_sexpr(e)),
+        Stmt::Block(statements, _) => list("block", statements.iter().map(stmt_to_sexpr)),
+        Stmt::If(condition, then_branch, None, _) => {
+            format!("(if {} {})", expr_to_sexpr(condition), stmt_to_sexpr(then_branch))
+        }
+        Stmt::If(condition, then_branch, Some(else_branch), _) => format!(
+            "(if {} {} {})",
+            expr_to_sexpr(condition),
+            stmt_to_sexpr(then_branch),
+            stmt_to_sexpr(else_branch)
+        ),
+        Stmt::While(condition, body, _) => format!("(while {} {})", expr_to_sexpr(condition), stmt_to_sexpr(body)),
+        // Missing clauses are written as `()`
+        Stmt::For(initializer, condition, increment, body, _) => format!(
+            "(for {} {} {} {})",
+            initializer.as_ref().map_or("()".to_string(), |s| stmt_to_sexpr(s)),
+            condition.as_ref().map_or("()".to_string(), expr_to_sexpr),
+            increment.as_ref().map_or("()".to_string(), expr_to_sexpr),
+            stmt_to_sexpr(body)
+        ),
+        Stmt::Function(decl) => {
+            let params: Vec<&str> = decl.params.iter().map(|p| p.lexeme.as_str()).collect();
+            let head = format!("func {} ({})", decl.name.lexeme, params.join(" "));
+            list(&head, decl.body.iter().map(stmt_to_sexpr))
+        }
+        Stmt::Return(None, _) => "(return)".to_string(),
+        Stmt::Return(Some(e), _) => format!("(return {})", expr_to_sexpr(e)),
+        Stmt::Throw(e, _) => format!("(throw {})", expr_to_sexpr(e)),
+        // Missing clauses are written as `()`, like in `for`
+        Stmt::Try(body, catch, finally, _) => format!(
+            "(try {} {} {})",
+            stmt_to_sexpr(body),
+            catch.as_ref().map_or("()".to_string(), |(name, handler)| {
+                format!("(catch {} {})", name.lexeme, stmt_to_sexpr(handler))
+            }),
+            finally.as_ref().map_or("()".to_string(), |s| stmt_to_sexpr(s))
+        ),
+    }
+}
+
+pub fn expr_to_sexpr(e: &Expr) -> String {
+    match e {
+        Expr::StringLiteral(v, _) => quote(v),
+        Expr::IntLiteral(v, _) => format!("{}", v),
+        // Debug formatting always includes a '.' or exponent, which keeps floats distinct from ints. Infinities are
+        // written as a number too big for a float, which reads back as one, rather than as `inf`, which would read back
+        // as a variable. No literal is NaN.
+        Expr::FloatLiteral(v, _) if v.is_infinite() => String::from(if *v > 0.0 { "1e999" } else { "-1e999" }),
+        Expr::FloatLiteral(v, _) => format!("{:?}", v),
+        Expr::BoolLiteral(v, _) => format!("{}", v),
+        Expr::NilLiteral(_) => "nil".to_string(),
+        Expr::Unary(operand, op) => format!("({} {})", op.lexeme, expr_to_sexpr(operand)),
+        Expr::Binary(left, op, right) | Expr::Logical(left, op, right) => {
+            format!("({} {} {})", op.lexeme, expr_to_sexpr(left), expr_to_sexpr(right))
+        }
+        Expr::Ternary(condition, if_true, if_false) => format!(
+            "(? {} {} {})",
+            expr_to_sexpr(condition),
+            expr_to_sexpr(if_true),
+            expr_to_sexpr(if_false)
+        ),
+        Expr::Sequence(expressions) => list(",", expressions.iter().map(expr_to_sexpr)),
+        Expr::Grouping(inner, _) => format!("(group {})", expr_to_sexpr(inner)),
+        Expr::Variable(name) => name.lexeme.clone(),
+        Expr::Assign(name, value) => format!("(= {} {})", name.lexeme, expr_to_sexpr(value)),
+        Expr::Call(callee, _, arguments) => {
+            let head = format!("call {}", expr_to_sexpr(callee));
+            list(&head, arguments.iter().map(expr_to_sexpr))
+        }
+        Expr::Get(object, name) => format!("(. {} {})", expr_to_sexpr(object), name.lexeme),
+        Expr::Interpolation(parts) => list("interpolate", parts.iter().map(expr_to_sexpr)),
+        Expr::List(elements, _) => list("list", elements.iter().map(expr_to_sexpr)),
+        // Each entry is a list of the key and the value
+        Expr::Map(entries, _) => list(
+            "map",
+            entries.iter().map(|(key, value)| format!("({} {})", expr_to_sexpr(key), expr_to_sexpr(value))),
+        ),
+        Expr::Index(object, index, _) => format!("(index {} {})", expr_to_sexpr(object), expr_to_sexpr(index)),
+        // Missing ends are written as `()`
+        Expr::Slice(object, start, end, _) => format!(
+            "(slice {} {} {})",
+            expr_to_sexpr(object),
+            start.as_ref().map_or("()".to_string(), |e| expr_to_sexpr(e)),
+            end.as_ref().map_or("()".to_string(), |e| expr_to_sexpr(e))
+        ),
+        Expr::SetIndex(object, index, _, value) => format!(
+            "(= (index {} {}) {})",
+            expr_to_sexpr(object),
+            expr_to_sexpr(index),
+            expr_to_sexpr(value)
+        ),
+        // The arrow form is written with just the returned expression, and the other with the body's statements
+        Expr::Lambda(decl, arrow) => {
+            let params: Vec<&str> = decl.params.iter().map(|p| p.lexeme.as_str()).collect();
+            match (arrow, decl.body.as_slice()) {
+                (true, [Stmt::Return(Some(value), _)]) => {
+                    format!("(=> ({}) {})", params.join(" "), expr_to_sexpr(value))
+                }
+                _ => list(&format!("lambda ({})", params.join(" ")), decl.body.iter().map(stmt_to_sexpr)),
+            }
+        }
+    }
+}
+
+fn list(head: &str, items: impl Iterator<Item = String>) -> String {
+    let mut s = format!("({}", head);
+    for item in items {
+        s.push(' ');
+        s.push_str(&item);
+    }
+    s.push(')');
+    s
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Debug)]
+enum SExpr {
+    Atom(String),
+    Str(String),
+    List(Vec<SExpr>),
+}
+
+/**
+ * Read a program back from its canonical S-expression form. The resulting tree has no span information.
+ */
+pub fn program_from_sexpr(source: &str) -> Result<Vec<Stmt>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut cur = 0;
+    let mut statements = vec![];
+    loop {
+        while cur < chars.len() && chars[cur].is_whitespace() {
+            cur += 1;
+        }
+        if cur == chars.len() {
+            return Ok(statements);
+        }
+        let sexpr = read_sexpr(&chars, &mut cur)?;
+        statements.push(stmt_from_sexpr(&sexpr)?);
+    }
+}
+
+fn read_sexpr(chars: &[char], cur: &mut usize) -> Result<SExpr, String> {
+    while *cur < chars.len() && chars[*cur].is_whitespace() {
+        *cur += 1;
+    }
+    match chars.get(*cur) {
+        None => Err("unexpected end of input".to_string()),
+        Some('(') => {
+            *cur += 1;
+            let mut items = vec![];
+            loop {
+                while *cur < chars.len() && chars[*cur].is_whitespace() {
+                    *cur += 1;
+                }
+                match chars.get(*cur) {
+                    None => return Err("unclosed '('".to_string()),
+                    Some(')') => {
+                        *cur += 1;
+                        return Ok(SExpr::List(items));
+                    }
+                    Some(_) => items.push(read_sexpr(chars, cur)?),
+                }
+            }
+        }
+        Some(')') => Err(format!("unexpected ')' at offset {}", cur)),
+        Some('"') => {
+            *cur += 1;
+            let mut s = String::new();
+            loop {
+                match chars.get(*cur) {
+                    None => return Err("unterminated string".to_string()),
+                    Some('"') => {
+                        *cur += 1;
+                        return Ok(SExpr::Str(s));
+                    }
+                    Some('\\') => {
+                        *cur += 1;
+                        match chars.get(*cur) {
+                            Some('"') => s.push('"'),
+                            Some('\\') => s.push('\\'),
+                            Some('n') => s.push('\n'),
+                            Some('r') => s.push('\r'),
+                            Some('t') => s.push('\t'),
+                            Some('u') => {
+                                // `\u{...}`, with the digits running from just after the `{` to the first `}`
+                                if chars.get(*cur + 1) != Some(&'{') {
+                                    return Err(format!("expected '{{' after \\u at offset {}", cur));
+                                }
+                                let close = match chars[*cur..].iter().position(|c| *c == '}') {
+                                    Some(close) => *cur + close,
+                                    None => return Err("unterminated unicode escape".to_string()),
+                                };
+                                let hex: String = chars[(*cur + 2)..close].iter().collect();
+                                let valid = (1..=6).contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit());
+                                let code = if valid { u32::from_str_radix(&hex, 16).ok() } else { None };
+                                match code.and_then(char::from_u32) {
+                                    Some(c) => s.push(c),
+                                    None => return Err(format!("invalid unicode escape `{}`", hex)),
+                                }
+                                *cur = close;
+                            }
+                            _ => return Err(format!("invalid escape at offset {}", cur)),
+                        }
+                        *cur += 1;
+                    }
+                    Some(c) => {
+                        s.push(*c);
+                        *cur += 1;
+                    }
+                }
+            }
+        }
+        Some(_) => {
+            let start = *cur;
+            while *cur < chars.len() && !chars[*cur].is_whitespace() && chars[*cur] != '(' && chars[*cur] != ')' {
+                *cur += 1;
+            }
+            Ok(SExpr::Atom(chars[start..*cur].iter().collect()))
+        }
+    }
+}
+
+fn stmt_from_sexpr(sexpr: &SExpr) -> Result<Stmt, String> {
+    let span = Span::default();
+    let items = match sexpr {
+        SExpr::List(items) => items,
+        other => return Err(format!("expected a statement, found {:?}", other)),
+    };
+    match (head(items)?, &items[1..]) {
+        ("expr", [e]) => Ok(Stmt::Expression(expr_from_sexpr(e)?, span)),
+        ("print", [e]) => Ok(Stmt::Print(expr_from_sexpr(e)?, span)),
+        ("var", [name]) => Ok(Stmt::Var(name_from_sexpr(name)?, None, None, span)),
+        ("var", [name, e]) => Ok(Stmt::Var(name_from_sexpr(name)?, Some(expr_from_sexpr(e)?), None, span)),
+        ("block", statements) => Ok(Stmt::Block(stmts_from_sexpr(statements)?, span)),
+        ("if", [condition, then_branch]) => Ok(Stmt::If(
+            expr_from_sexpr(condition)?,
+            Box::new(stmt_from_sexpr(then_branch)?),
+            None,
+            span,
+        )),
+        ("if", [condition, then_branch, else_branch]) => Ok(Stmt::If(
+            expr_from_sexpr(condition)?,
+            Box::new(stmt_from_sexpr(then_branch)?),
+            Some(Box::new(stmt_from_sexpr(else_branch)?)),
+            span,
+        )),
+        ("while", [condition, body]) => Ok(Stmt::While(
+            expr_from_sexpr(condition)?,
+            Box::new(stmt_from_sexpr(body)?),
+            span,
+        )),
+        ("for", [initializer, condition, increment, body]) => Ok(Stmt::For(
+            optional_from_sexpr(initializer, stmt_from_sexpr)?.map(Box::new),
+            optional_from_sexpr(condition, expr_from_sexpr)?,
+            optional_from_sexpr(increment, expr_from_sexpr)?,
+            Box::new(stmt_from_sexpr(body)?),
+            span,
+        )),
+        ("func", [name, SExpr::List(params), body @ ..]) => Ok(Stmt::Function(Rc::new(FunctionDecl {
+            name: name_from_sexpr(name)?,
+            params: params.iter().map(name_from_sexpr).collect::<Result<Vec<Token>, String>>()?,
+            body: stmts_from_sexpr(body)?,
+            doc: None,
+            span,
+        }))),
+        ("return", []) => Ok(Stmt::Return(None, span)),
+        ("return", [e]) => Ok(Stmt::Return(Some(expr_from_sexpr(e)?), span)),
+        ("throw", [e]) => Ok(Stmt::Throw(expr_from_sexpr(e)?, span)),
+        ("try", [body, catch, finally]) => Ok(Stmt::Try(
+            Box::new(stmt_from_sexpr(body)?),
+            optional_from_sexpr(catch, catch_from_sexpr)?,
+            optional_from_sexpr(finally, stmt_from_sexpr)?.map(Box::new),
+            span,
+        )),
+        (h, _) => Err(format!("malformed `{}` statement", h)),
+    }
+}
+
+fn optional_from_sexpr<T>(sexpr: &SExpr, from_sexpr: fn(&SExpr) -> Result<T, String>) -> Result<Option<T>, String> {
+    match sexpr {
+        SExpr::List(items) if items.is_empty() => Ok(None),
+        s => Ok(Some(from_sexpr(s)?)),
+    }
+}
+
+fn stmts_from_sexpr(items: &[SExpr]) -> Result<Vec<Stmt>, String> {
+    items.iter().map(stmt_from_sexpr).collect()
+}
+
+fn catch_from_sexpr(sexpr: &SExpr) -> Result<(Token, Box<Stmt>), String> {
+    match sexpr {
+        SExpr::List(items) => match (head(items)?, &items[1..]) {
+            ("catch", [name, handler]) => Ok((name_from_sexpr(name)?, Box::new(stmt_from_sexpr(handler)?))),
+            _ => Err(format!("expected a catch clause, found {:?}", sexpr)),
+        },
+        other => Err(format!("expected a catch clause, found {:?}", other)),
+    }
+}
+
+fn expr_from_sexpr(sexpr: &SExpr) -> Result<Expr, String> {
+    let span = Span::default();
+    let boxed = |e: &SExpr| -> Result<Box<Expr>, String> { Ok(Box::new(expr_from_sexpr(e)?)) };
+    match sexpr {
+        SExpr::Str(s) => Ok(Expr::StringLiteral(s.clone(), span)),
+        SExpr::Atom(a) => match a.as_str() {
+            "true" => Ok(Expr::BoolLiteral(true, span)),
+            "false" => Ok(Expr::BoolLiteral(false, span)),
+            "nil" => Ok(Expr::NilLiteral(span)),
+            a if a.strip_prefix('-').unwrap_or(a).starts_with(|c: char| c.is_ascii_digit()) => match a.parse::<i64>() {
+                Ok(v) => Ok(Expr::IntLiteral(v, span)),
+                Err(_) => match a.parse::<f64>() {
+                    Ok(v) => Ok(Expr::FloatLiteral(v, span)),
+                    Err(_) => Err(format!("invalid number `{}`", a)),
+                },
+            },
+            _ => Ok(Expr::Variable(name_from_sexpr(sexpr)?)),
+        },
+        SExpr::List(items) => match (head(items)?, &items[1..]) {
+            ("group", [e]) => Ok(Expr::Grouping(boxed(e)?, span)),
+            ("?", [condition, if_true, if_false]) => {
+                Ok(Expr::Ternary(boxed(condition)?, boxed(if_true)?, boxed(if_false)?))
+            }
+            (",", expressions) if expressions.len() >= 2 => {
+                Ok(Expr::Sequence(expressions.iter().map(expr_from_sexpr).collect::<Result<_, _>>()?))
+            }
+            ("=", [target @ SExpr::List(_), value]) => match expr_from_sexpr(target)? {
+                Expr::Index(object, index, bracket) => Ok(Expr::SetIndex(object, index, bracket, boxed(value)?)),
+                _ => Err(format!("invalid assignment target {:?}", target)),
+            },
+            ("=", [name, value]) => Ok(Expr::Assign(name_from_sexpr(name)?, boxed(value)?)),
+            ("list", elements) => Ok(Expr::List(elements.iter().map(expr_from_sexpr).collect::<Result<_, _>>()?, span)),
+            ("map", entries) => Ok(Expr::Map(entries.iter().map(entry_from_sexpr).collect::<Result<_, _>>()?, span)),
+            ("index", [object, index]) => Ok(Expr::Index(boxed(object)?, boxed(index)?, bracket_token())),
+            ("slice", [object, start, end]) => Ok(Expr::Slice(
+                boxed(object)?,
+                optional_from_sexpr(start, expr_from_sexpr)?.map(Box::new),
+                optional_from_sexpr(end, expr_from_sexpr)?.map(Box::new),
+                bracket_token(),
+            )),
+            (".", [object, name]) => Ok(Expr::Get(boxed(object)?, name_from_sexpr(name)?)),
+            ("interpolate", parts) if parts.len() % 2 == 1 => {
+                Ok(Expr::Interpolation(parts.iter().map(expr_from_sexpr).collect::<Result<_, _>>()?))
+            }
+            ("=>", [SExpr::List(params), value]) => {
+                let body = vec![Stmt::Return(Some(expr_from_sexpr(value)?), span)];
+                Ok(Expr::Lambda(lambda_from_sexpr(params, body)?, true))
+            }
+            ("lambda", [SExpr::List(params), body @ ..]) => {
+                Ok(Expr::Lambda(lambda_from_sexpr(params, stmts_from_sexpr(body)?)?, false))
+            }
+            ("call", [callee, arguments @ ..]) => Ok(Expr::Call(
+                boxed(callee)?,
+                Token::new(TokenType::RightParen, ")".to_string(), Literal::None, 0),
+                arguments.iter().map(expr_from_sexpr).collect::<Result<Vec<Expr>, String>>()?,
+            )),
+            (op, [operand]) => Ok(Expr::Unary(boxed(operand)?, op_token(op)?)),
+            (op, [left, right]) => {
+                let op = op_token(op)?;
+                if op.token_type == TokenType::And || op.token_type == TokenType::Or {
+                    Ok(Expr::Logical(boxed(left)?, op, boxed(right)?))
+                } else {
+                    Ok(Expr::Binary(boxed(left)?, op, boxed(right)?))
+                }
+            }
+            (h, _) => Err(format!("malformed `{}` expression", h)),
+        },
+    }
+}
+
+fn entry_from_sexpr(sexpr: &SExpr) -> Result<(Expr, Expr), String> {
+    match sexpr {
+        SExpr::List(items) if items.len() == 2 => Ok((expr_from_sexpr(&items[0])?, expr_from_sexpr(&items[1])?)),
+        other => Err(format!("expected a key and a value, found {:?}", other)),
+    }
+}
+
+fn bracket_token() -> Token {
+    Token::new(TokenType::RightBracket, "]".to_string(), Literal::None, 0)
+}
+
+fn lambda_from_sexpr(params: &[SExpr], body: Vec<Stmt>) -> Result<Rc<FunctionDecl>, String> {
+    Ok(Rc::new(FunctionDecl {
+        name: Token::new(TokenType::Identifier, "<lambda>".to_string(), Literal::None, 0),
+        params: params.iter().map(name_from_sexpr).collect::<Result<Vec<Token>, String>>()?,
+        body,
+        doc: None,
+        span: Span::default(),
+    }))
+}
+
+fn head(items: &[SExpr]) -> Result<&str, String> {
+    match items.first() {
+        Some(SExpr::Atom(h)) => Ok(h),
+        _ => Err("expected a list starting with an operator or keyword".to_string()),
+    }
+}
+
+fn name_from_sexpr(sexpr: &SExpr) -> Result<Token, String> {
+    match sexpr {
+        SExpr::Atom(name) => Ok(Token::new(TokenType::Identifier, name.clone(), Literal::None, 0)),
+        other => Err(format!("expected a name, found {:?}", other)),
+    }
+}
+
+/**
+ * Work out the token type of an operator by scanning it.
+ */
+fn op_token(op: &str) -> Result<Token, String> {
+    match Scanner::new(op.to_string()).next() {
+        Some(Ok(t)) if t.lexeme == op => Ok(Token::new(t.token_type, op.to_string(), Literal::None, 0)),
+        _ => Err(format!("unknown operator `{}`", op)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{program, Parser};
+
+    const SOURCE: &str = "var big = 9007199254740993;
+var xs = [1, 2.0, \"three\", {\"k\": -9223372036854775807}];
+var square = (x) => x * x;
+func f(a, b) {
+    print xs[1:] + [a, b];
+    xs[0] = big;
+    return a > b ? a : b;
+}
+print f(big, 2.5);
+";
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        program(&mut Parser::new(Scanner::new(source.to_string()))).unwrap()
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = program_to_json(&parse(SOURCE));
+        let read = program_from_json(&Json::parse(&json.to_string()).unwrap()).unwrap();
+        assert_eq!(program_to_json(&read), json);
+        assert!(json.to_string().contains("9007199254740993"));
+    }
+
+    #[test]
+    fn sexpr_round_trip() {
+        let sexpr = program_to_sexpr(&parse(SOURCE));
+        let read = program_from_sexpr(&sexpr).unwrap();
+        assert_eq!(program_to_sexpr(&read), sexpr);
+        assert!(sexpr.contains("9007199254740993"));
+    }
+
+    #[test]
+    fn infinite_floats_round_trip() {
+        let infinities = vec![
+            Stmt::Print(Expr::FloatLiteral(f64::INFINITY, Span::default()), Span::default()),
+            Stmt::Print(Expr::FloatLiteral(f64::NEG_INFINITY, Span::default()), Span::default()),
+        ];
+        let sexpr = program_to_sexpr(&infinities);
+        assert_eq!(program_to_sexpr(&program_from_sexpr(&sexpr).unwrap()), sexpr);
+        assert_eq!(program_to_sexpr(&parse("print 1e999;")), program_to_sexpr(&infinities[..1]));
+    }
+
+    #[test]
+    fn malformed_unicode_escapes_are_errors() {
+        for escape in ["\\u}", "\\u12}", "\\u{}", "\\u{zz}", "\\u{d800}", "\\u{110000}", "\\u{41"].iter() {
+            assert!(program_from_sexpr(&format!("(print \"{}\")", escape)).is_err(), "{}", escape);
+        }
+        let read = program_from_sexpr("(print \"\\u{1F600}\\u{41}\")").unwrap();
+        assert_eq!(program_to_sexpr(&read), "(print \"😀A\")");
+    }
+
+    #[test]
+    fn token_literals_round_trip() {
+        for token in Scanner::new(String::from("9007199254740993 2.0 1e3 1.5e-3 0x10 \"s\"")) {
+            let token = token.unwrap();
+            let read = token_from_json(&Json::parse(&token.to_json().to_string()).unwrap()).unwrap();
+            assert_eq!(read.literal, token.literal);
+        }
+    }
+}
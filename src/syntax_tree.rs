@@ -1,54 +1,496 @@
-use crate::{scanner::{Token, TokenType}, interpreter::Nil};
+use crate::{scanner::{Span, Token}, interpreter::{format_float, Nil, Callable, ErrorObject, List, Map}};
 use core::any::Any;
+use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expr {
     // Literal(Box<dyn Any>),
-    StringLiteral(String),
-    IntLiteral(i64),
-    FloatLiteral(f64),
-    BoolLiteral(bool),
-    NilLiteral(),
+    StringLiteral(String, Span),
+    IntLiteral(i64, Span),
+    FloatLiteral(f64, Span),
+    BoolLiteral(bool, Span),
+    NilLiteral(Span),
     Unary(Box<Expr>, Token),
     Binary(Box<Expr>, Token, Box<Expr>),
+    Logical(Box<Expr>, Token, Box<Expr>),
     Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
-    Grouping(Box<Expr>),
+    // Expressions separated by commas, evaluated in order for the value of the last one. There are always at least two.
+    Sequence(Vec<Expr>),
+    // The span covers the parentheses
+    Grouping(Box<Expr>, Span),
+    Variable(Token),
+    Assign(Token, Box<Expr>),
+    // The token is the closing paren, kept around for error reporting
+    Call(Box<Expr>, Token, Vec<Expr>),
+    // Property access: the object and the property name
+    Get(Box<Expr>, Token),
+    // A list literal. The span covers the brackets.
+    List(Vec<Expr>, Span),
+    // A map literal: each key with its value. The span covers the braces.
+    Map(Vec<(Expr, Expr)>, Span),
+    // Indexing, e.g. `xs[i]`: the object, the index and the closing bracket
+    Index(Box<Expr>, Box<Expr>, Token),
+    // Slicing, e.g. `xs[1:3]`: the object, the start and end (either of which can be left out) and the closing bracket
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>, Token),
+    // Assignment to an index, e.g. `xs[i] = v`: the object, the index, the closing bracket and the value
+    SetIndex(Box<Expr>, Box<Expr>, Token, Box<Expr>),
+    // A string with `${...}` in it. The parts alternate between string literals and interpolated expressions, starting
+    // and ending with a literal (which may be empty). Each literal's span includes the `"`, `}` and `${` around it.
+    Interpolation(Vec<Expr>),
+    // An anonymous function, named `<lambda>`. The flag is set for the `(a, b) => a + b` form, whose body is a single
+    // `return` with the same span as its value.
+    Lambda(Rc<FunctionDecl>, bool),
+}
+impl Expr {
+    /**
+     * The source this expression was parsed from. Most nodes work this out from their tokens and children.
+     */
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::StringLiteral(_, span)
+            | Expr::IntLiteral(_, span)
+            | Expr::FloatLiteral(_, span)
+            | Expr::BoolLiteral(_, span)
+            | Expr::NilLiteral(span)
+            | Expr::Grouping(_, span)
+            | Expr::List(_, span)
+            | Expr::Map(_, span) => *span,
+            Expr::Unary(e, t) => t.span.to(e.span()),
+            Expr::Binary(left, _, right) | Expr::Logical(left, _, right) => left.span().to(right.span()),
+            Expr::Ternary(condition, _, if_false) => condition.span().to(if_false.span()),
+            Expr::Variable(t) => t.span,
+            Expr::Assign(t, value) => t.span.to(value.span()),
+            Expr::Call(callee, paren, _) => callee.span().to(paren.span),
+            Expr::Get(object, name) => object.span().to(name.span),
+            Expr::Index(object, _, bracket) | Expr::Slice(object, _, _, bracket) => object.span().to(bracket.span),
+            Expr::SetIndex(object, _, _, value) => object.span().to(value.span()),
+            Expr::Interpolation(parts) | Expr::Sequence(parts) => parts[0].span().to(parts[parts.len() - 1].span()),
+            Expr::Lambda(decl, _) => decl.span,
+        }
+    }
+}
+
+/**
+ * Statements. The trailing span of each variant covers the whole statement, including its keyword and any closing
+ * ';' or '}'.
+ */
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Expression(Expr, Span),
+    Print(Expr, Span),
+    // Name, initializer and doc comment
+    Var(Token, Option<Expr>, Option<String>, Span),
+    Block(Vec<Stmt>, Span),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>, Span),
+    While(Expr, Box<Stmt>, Span),
+    // Initializer, condition, increment and body. Any of the clauses may be left out.
+    For(Option<Box<Stmt>>, Option<Expr>, Option<Expr>, Box<Stmt>, Span),
+    Function(Rc<FunctionDecl>),
+    Return(Option<Expr>, Span),
+    Throw(Expr, Span),
+    // Body, then the optional `catch` clause's variable and block, then the optional `finally` block. Each one is a
+    // `Stmt::Block`, and at least one of the clauses is always present.
+    Try(Box<Stmt>, Option<(Token, Box<Stmt>)>, Option<Box<Stmt>>, Span),
+}
+impl Stmt {
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Expression(_, span)
+            | Stmt::Print(_, span)
+            | Stmt::Var(_, _, _, span)
+            | Stmt::Block(_, span)
+            | Stmt::If(_, _, _, span)
+            | Stmt::While(_, _, span)
+            | Stmt::For(_, _, _, _, span)
+            | Stmt::Return(_, span)
+            | Stmt::Throw(_, span)
+            | Stmt::Try(_, _, _, span) => *span,
+            Stmt::Function(decl) => decl.span,
+        }
+    }
+}
+
+/**
+ * A function declaration. Shared between the syntax tree and every closure created from it, since a function body can
+ * be executed many times.
+ */
+#[derive(Debug, Clone)]
+pub struct FunctionDecl {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Stmt>,
+    // The `///` comments before the declaration
+    pub doc: Option<String>,
+    pub span: Span,
 }
 
-pub fn visit(e: Expr) -> String {
+/**
+ * A read-only pass over the syntax tree. The default methods walk into every child node, so a pass only needs to
+ * override `visit_expr`/`visit_stmt`, handle the nodes it cares about, and call `walk_expr`/`walk_stmt` for the rest.
+ */
+pub trait Visitor {
+    fn visit_expr(&mut self, e: &Expr) {
+        walk_expr(self, e);
+    }
+
+    fn visit_stmt(&mut self, s: &Stmt) {
+        walk_stmt(self, s);
+    }
+}
+
+/**
+ * Visit each child of `e`.
+ */
+pub fn walk_expr<V: Visitor + ?Sized>(v: &mut V, e: &Expr) {
     match e {
-        Expr::StringLiteral(v) => format!("{}", v),
-        Expr::IntLiteral(v) => format!("{}", v),
-        Expr::FloatLiteral(v) => format!("{}", v),
-        Expr::BoolLiteral(v) => format!("{}", v),
-        Expr::NilLiteral() => format!("nil"),
-        Expr::Unary(e, t) => format!("( {} {} )", t.lexeme, visit(*e)),
-        Expr::Binary(e1, t, e2) => format!("( {} {} {} )", t.lexeme, visit(*e1), visit(*e2)),
-        Expr::Ternary(e1, e2, e3) => {
-            format!("( {} ? {} : {} )", visit(*e1), visit(*e2), visit(*e3))
+        Expr::StringLiteral(..)
+        | Expr::IntLiteral(..)
+        | Expr::FloatLiteral(..)
+        | Expr::BoolLiteral(..)
+        | Expr::NilLiteral(_)
+        | Expr::Variable(_) => (),
+        Expr::Unary(operand, _) | Expr::Grouping(operand, _) => v.visit_expr(operand),
+        Expr::Binary(left, _, right) | Expr::Logical(left, _, right) => {
+            v.visit_expr(left);
+            v.visit_expr(right);
+        }
+        Expr::Ternary(condition, if_true, if_false) => {
+            v.visit_expr(condition);
+            v.visit_expr(if_true);
+            v.visit_expr(if_false);
+        }
+        Expr::Assign(_, value) | Expr::Get(value, _) => v.visit_expr(value),
+        Expr::Call(callee, _, arguments) => {
+            v.visit_expr(callee);
+            for argument in arguments {
+                v.visit_expr(argument);
+            }
+        }
+        Expr::Interpolation(parts) | Expr::Sequence(parts) | Expr::List(parts, _) => {
+            for part in parts {
+                v.visit_expr(part);
+            }
+        }
+        Expr::Map(entries, _) => {
+            for (key, value) in entries {
+                v.visit_expr(key);
+                v.visit_expr(value);
+            }
+        }
+        Expr::Index(object, index, _) => {
+            v.visit_expr(object);
+            v.visit_expr(index);
         }
-        Expr::Grouping(e) => format!("( {} )", visit(*e)),
+        Expr::Slice(object, start, end, _) => {
+            v.visit_expr(object);
+            for bound in start.iter().chain(end.iter()) {
+                v.visit_expr(bound);
+            }
+        }
+        Expr::SetIndex(object, index, _, value) => {
+            v.visit_expr(object);
+            v.visit_expr(index);
+            v.visit_expr(value);
+        }
+        Expr::Lambda(decl, _) => {
+            for statement in &decl.body {
+                v.visit_stmt(statement);
+            }
+        }
+    }
+}
+
+/**
+ * Visit each child of `s`.
+ */
+pub fn walk_stmt<V: Visitor + ?Sized>(v: &mut V, s: &Stmt) {
+    match s {
+        Stmt::Expression(e, _) | Stmt::Print(e, _) => v.visit_expr(e),
+        Stmt::Var(_, initializer, ..) => {
+            if let Some(e) = initializer {
+                v.visit_expr(e);
+            }
+        }
+        Stmt::Block(statements, _) => {
+            for statement in statements {
+                v.visit_stmt(statement);
+            }
+        }
+        Stmt::If(condition, then_branch, else_branch, _) => {
+            v.visit_expr(condition);
+            v.visit_stmt(then_branch);
+            if let Some(else_branch) = else_branch {
+                v.visit_stmt(else_branch);
+            }
+        }
+        Stmt::While(condition, body, _) => {
+            v.visit_expr(condition);
+            v.visit_stmt(body);
+        }
+        Stmt::For(initializer, condition, increment, body, _) => {
+            if let Some(initializer) = initializer {
+                v.visit_stmt(initializer);
+            }
+            if let Some(condition) = condition {
+                v.visit_expr(condition);
+            }
+            if let Some(increment) = increment {
+                v.visit_expr(increment);
+            }
+            v.visit_stmt(body);
+        }
+        Stmt::Function(decl) => {
+            for statement in &decl.body {
+                v.visit_stmt(statement);
+            }
+        }
+        Stmt::Return(value, _) => {
+            if let Some(e) = value {
+                v.visit_expr(e);
+            }
+        }
+        Stmt::Throw(e, _) => v.visit_expr(e),
+        Stmt::Try(body, catch, finally, _) => {
+            v.visit_stmt(body);
+            if let Some((_, handler)) = catch {
+                v.visit_stmt(handler);
+            }
+            if let Some(finally) = finally {
+                v.visit_stmt(finally);
+            }
+        }
+    }
+}
+
+/**
+ * A pass that rebuilds the syntax tree, e.g. an optimizer. Like `Visitor`, the default methods rebuild every node
+ * unchanged, so a pass only overrides what it wants to rewrite and calls `fold_expr_children`/`fold_stmt_children` to
+ * carry on down the tree.
+ */
+pub trait Folder {
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        fold_expr_children(self, e)
+    }
+
+    fn fold_stmt(&mut self, s: Stmt) -> Stmt {
+        fold_stmt_children(self, s)
     }
 }
 
+/**
+ * Rebuild `e` with each of its children folded.
+ */
+pub fn fold_expr_children<F: Folder + ?Sized>(f: &mut F, e: Expr) -> Expr {
+    let mut fold = |e: Box<Expr>| Box::new(f.fold_expr(*e));
+    match e {
+        Expr::StringLiteral(..)
+        | Expr::IntLiteral(..)
+        | Expr::FloatLiteral(..)
+        | Expr::BoolLiteral(..)
+        | Expr::NilLiteral(_)
+        | Expr::Variable(_) => e,
+        Expr::Unary(operand, op) => Expr::Unary(fold(operand), op),
+        Expr::Grouping(inner, span) => Expr::Grouping(fold(inner), span),
+        Expr::Binary(left, op, right) => {
+            let left = fold(left);
+            Expr::Binary(left, op, fold(right))
+        }
+        Expr::Logical(left, op, right) => {
+            let left = fold(left);
+            Expr::Logical(left, op, fold(right))
+        }
+        Expr::Ternary(condition, if_true, if_false) => {
+            let condition = fold(condition);
+            let if_true = fold(if_true);
+            Expr::Ternary(condition, if_true, fold(if_false))
+        }
+        Expr::Assign(name, value) => Expr::Assign(name, fold(value)),
+        Expr::Get(object, name) => Expr::Get(fold(object), name),
+        Expr::Interpolation(parts) => Expr::Interpolation(parts.into_iter().map(|p| f.fold_expr(p)).collect()),
+        Expr::Sequence(expressions) => Expr::Sequence(expressions.into_iter().map(|e| f.fold_expr(e)).collect()),
+        Expr::List(elements, span) => Expr::List(elements.into_iter().map(|e| f.fold_expr(e)).collect(), span),
+        Expr::Map(entries, span) => {
+            Expr::Map(entries.into_iter().map(|(key, value)| (f.fold_expr(key), f.fold_expr(value))).collect(), span)
+        }
+        Expr::Index(object, index, bracket) => {
+            let object = fold(object);
+            Expr::Index(object, fold(index), bracket)
+        }
+        Expr::Slice(object, start, end, bracket) => {
+            let object = fold(object);
+            let start = start.map(&mut fold);
+            Expr::Slice(object, start, end.map(&mut fold), bracket)
+        }
+        Expr::SetIndex(object, index, bracket, value) => {
+            let object = fold(object);
+            let index = fold(index);
+            Expr::SetIndex(object, index, bracket, fold(value))
+        }
+        Expr::Call(callee, paren, arguments) => {
+            let callee = fold(callee);
+            Expr::Call(callee, paren, arguments.into_iter().map(|a| f.fold_expr(a)).collect())
+        }
+        Expr::Lambda(decl, arrow) => Expr::Lambda(fold_body(f, decl), arrow),
+    }
+}
+
+/**
+ * Rebuild `s` with each of its children folded.
+ */
+pub fn fold_stmt_children<F: Folder + ?Sized>(f: &mut F, s: Stmt) -> Stmt {
+    match s {
+        Stmt::Expression(e, span) => Stmt::Expression(f.fold_expr(e), span),
+        Stmt::Print(e, span) => Stmt::Print(f.fold_expr(e), span),
+        Stmt::Var(name, initializer, doc, span) => Stmt::Var(name, initializer.map(|e| f.fold_expr(e)), doc, span),
+        Stmt::Block(statements, span) => {
+            Stmt::Block(statements.into_iter().map(|s| f.fold_stmt(s)).collect(), span)
+        }
+        Stmt::If(condition, then_branch, else_branch, span) => {
+            let condition = f.fold_expr(condition);
+            let then_branch = Box::new(f.fold_stmt(*then_branch));
+            let else_branch = else_branch.map(|s| Box::new(f.fold_stmt(*s)));
+            Stmt::If(condition, then_branch, else_branch, span)
+        }
+        Stmt::While(condition, body, span) => {
+            let condition = f.fold_expr(condition);
+            Stmt::While(condition, Box::new(f.fold_stmt(*body)), span)
+        }
+        Stmt::For(initializer, condition, increment, body, span) => {
+            let initializer = initializer.map(|s| Box::new(f.fold_stmt(*s)));
+            let condition = condition.map(|e| f.fold_expr(e));
+            let increment = increment.map(|e| f.fold_expr(e));
+            Stmt::For(initializer, condition, increment, Box::new(f.fold_stmt(*body)), span)
+        }
+        Stmt::Function(decl) => Stmt::Function(fold_body(f, decl)),
+        Stmt::Return(value, span) => Stmt::Return(value.map(|e| f.fold_expr(e)), span),
+        Stmt::Throw(e, span) => Stmt::Throw(f.fold_expr(e), span),
+        Stmt::Try(body, catch, finally, span) => {
+            let body = Box::new(f.fold_stmt(*body));
+            let catch = catch.map(|(name, handler)| (name, Box::new(f.fold_stmt(*handler))));
+            let finally = finally.map(|s| Box::new(f.fold_stmt(*s)));
+            Stmt::Try(body, catch, finally, span)
+        }
+    }
+}
+
+fn fold_body<F: Folder + ?Sized>(f: &mut F, decl: Rc<FunctionDecl>) -> Rc<FunctionDecl> {
+    // Declarations are shared with closures, so only copy the declaration if something else still holds it
+    let decl = Rc::try_unwrap(decl).unwrap_or_else(|decl| (*decl).clone());
+    Rc::new(FunctionDecl { body: decl.body.into_iter().map(|s| f.fold_stmt(s)).collect(), ..decl })
+}
+
 pub fn visit_any(b: Box<dyn Any>) -> String {
-    if (*b).is::<String>() {
+    show(&b, false, &mut vec![])
+}
+
+/**
+ * How a value is shown inside a list or map: the same as `visit_any`, except that strings are quoted, so that
+ * `["a, b"]` and `["a", "b"]` look different.
+ */
+pub fn quoted(b: &Box<dyn Any>) -> String {
+    show(b, true, &mut vec![])
+}
+
+// `visiting` holds the lists and maps being shown further out, so that one containing itself is shown as `[...]` or
+// `{...}` rather than forever
+fn show(b: &Box<dyn Any>, quote: bool, visiting: &mut Vec<*const ()>) -> String {
+    if let (true, Some(s)) = (quote, (**b).downcast_ref::<String>()) {
+        format!("{:?}", s)
+    }
+    else if (*b).is::<String>() {
        String::from((*b).downcast_ref::<String>().unwrap().as_str())
     }
     else if (*b).is::<i64>() {
         format!("{}", *(*b).downcast_ref::<i64>().unwrap())
     }
     else if (*b).is::<f64>() {
-        format!("{}", *(*b).downcast_ref::<f64>().unwrap())
+        format_float(*(*b).downcast_ref::<f64>().unwrap())
     }
     else if (*b).is::<bool>() {
         format!("{}", *(*b).downcast_ref::<bool>().unwrap())
     }
     else if (*b).is::<Nil>() {
         String::from("nil")
+    }
+    else if (*b).is::<Rc<dyn Callable>>() {
+        format!("<fn {}>", (*b).downcast_ref::<Rc<dyn Callable>>().unwrap().name())
+    }
+    else if let Some(error) = (*b).downcast_ref::<ErrorObject>() {
+        format!("<error at line {}: {}>", error.line, error.message)
+    }
+    else if let Some(list) = (*b).downcast_ref::<List>() {
+        let pointer = Rc::as_ptr(list) as *const ();
+        if visiting.contains(&pointer) {
+            return String::from("[...]");
+        }
+        visiting.push(pointer);
+        let elements: Vec<String> = list.borrow().iter().map(|e| show(e, true, visiting)).collect();
+        visiting.pop();
+        format!("[{}]", elements.join(", "))
+    }
+    else if let Some(map) = (*b).downcast_ref::<Map>() {
+        let pointer = Rc::as_ptr(map) as *const ();
+        if visiting.contains(&pointer) {
+            return String::from("{...}");
+        }
+        visiting.push(pointer);
+        let entries: Vec<String> = map.borrow().iter()
+            .map(|(k, v)| format!("{}: {}", quoted(k), show(v, true, visiting)))
+            .collect();
+        visiting.pop();
+        format!("{{{}}}", entries.join(", "))
     } else {
-        String::from("")
+        String::from("<unknown>")
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{program, Parser};
+    use crate::scanner::{Scanner, TokenType};
+    use crate::serialize::program_to_sexpr;
 
+    // Works out arithmetic on integer literals, and drops the parentheses around whatever that leaves a literal
+    struct ConstantFolder;
+    impl Folder for ConstantFolder {
+        fn fold_expr(&mut self, e: Expr) -> Expr {
+            match fold_expr_children(self, e) {
+                Expr::Binary(left, op, right) => match (*left, *right) {
+                    (Expr::IntLiteral(l, span), Expr::IntLiteral(r, _)) if op.token_type == TokenType::Plus => {
+                        Expr::IntLiteral(l + r, span)
+                    }
+                    (Expr::IntLiteral(l, span), Expr::IntLiteral(r, _)) if op.token_type == TokenType::Minus => {
+                        Expr::IntLiteral(l - r, span)
+                    }
+                    (Expr::IntLiteral(l, span), Expr::IntLiteral(r, _)) if op.token_type == TokenType::Star => {
+                        Expr::IntLiteral(l * r, span)
+                    }
+                    (left, right) => Expr::Binary(Box::new(left), op, Box::new(right)),
+                },
+                Expr::Grouping(inner, span) => match *inner {
+                    literal @ Expr::IntLiteral(..) => literal,
+                    inner => Expr::Grouping(Box::new(inner), span),
+                },
+                e => e,
+            }
+        }
+    }
+
+    #[test]
+    fn folding_reaches_every_expression() {
+        let source = "var x = 1 + 2 * 3;
+func f(a) { while (a < 10 - 1) a = a + (2 - 1); return [a, 4 - 1][0] + {\"k\": 2 * 2}[\"k\"]; }
+print ((y) => y + (1 + 1))(x ? 5 * 5 : -(3 - 1));
+try { throw 6 * 7; } catch (e) { print \"${e + 0 * 1}\"; }";
+        let statements = program(&mut Parser::new(Scanner::new(source.to_string()))).unwrap();
+        let folded: Vec<Stmt> = statements.into_iter().map(|s| ConstantFolder.fold_stmt(s)).collect();
+        assert_eq!(
+            program_to_sexpr(&folded),
+            "(var x 7)
+(func f (a) (while (< a 9) (expr (= a (+ a 1)))) (return (+ (index (list a 3) 0) (index (map (\"k\" 4)) \"k\"))))
+(print (call (group (=> (y) (+ y 2))) (? x 25 (- 2))))
+(try (block (throw 42)) (catch e (block (print (interpolate \"\" (+ e 0) \"\")))) ())"
+        );
+    }
+}